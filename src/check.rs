@@ -0,0 +1,199 @@
+//! Checks whether a Cranelift function can be translated, without defining it.
+//!
+//! Every instruction is translated on its own into a scratch Walrus module
+//! (with its operands replaced by locals, so that an operand which cannot be
+//! translated does not hide problems with the instruction using it).
+//!
+//! The control flow is not structured, so it is not checked: only the types
+//! compared by branches are. Translating a function can still fail if the
+//! structured control flow contains a branch which cannot be lowered (which
+//! [`cranelift_module::Module::define_function`] reports as an
+//! [`ErrorKind::UnsupportedBranchMode`], or as a block which "cannot be
+//! reached").
+
+use cranelift_codegen::{
+    cursor::FuncCursor,
+    ir::{self, instructions::BranchInfo, Block, InstructionData},
+};
+use fnv::FnvHashMap;
+use walrus::{ir::Value, FunctionBuilder, InitExpr, Module as WalrusModule};
+
+use crate::{
+    conversions::{
+        block::CanBranchTo,
+        inst::build_wasm_inst,
+        narrow::is_i32,
+        sig::check_sig,
+        ty::{wasm_of_pointer, wasm_values_of_cranelift},
+    },
+    error::{ErrorKind, TranslationError},
+    locals::ValueLocals,
+    optable::OperandTable,
+    stack::StackFrame,
+    table::FunctionTable,
+    IndividualFunctionTranslator, WasmModule,
+};
+
+impl WasmModule {
+    /// Reports every construct in `func` which cannot (yet) be translated:
+    /// unsupported opcodes, value types and signatures.
+    ///
+    /// If the list is empty, [`cranelift_module::Module::define_function`]
+    /// will be able to translate the instructions of the function (provided
+    /// that the functions and data objects which it refers to have been
+    /// declared). Its control flow is not checked, so a branch which cannot be
+    /// lowered once the control flow has been structured is only reported by
+    /// `define_function`. The module is not modified.
+    pub fn check_function(&self, func: &ir::Function) -> Vec<TranslationError> {
+        let mut errors = Errors(Vec::new());
+        for error in check_sig(&func.signature) {
+            errors.push(error);
+        }
+
+        if func.layout.entry_block().is_none() {
+            errors.push(ErrorKind::Unsupported("the function has no blocks".to_string()).into());
+            return errors.0;
+        }
+
+        let blocks: Vec<Block> = func.layout.blocks().collect();
+        let insts: Vec<ir::Inst> = blocks
+            .iter()
+            .flat_map(|block| func.layout.block_insts(*block))
+            .collect();
+
+        // block parameters are checked at the branches which pass them
+        for inst in &insts {
+            if let BranchInfo::SingleDest(destination, _) = func.dfg.analyze_branch(*inst) {
+                for param in func.dfg.block_params(destination) {
+                    let ty = func.dfg.value_type(*param);
+                    if let Err(e) = wasm_values_of_cranelift(ty) {
+                        errors.push(e.at(*inst, func));
+                    }
+                }
+            }
+        }
+
+        let mut func = func.clone();
+        self.check_insts(&mut func, &insts, &mut errors);
+
+        errors.0
+    }
+
+    /// Translates each instruction (other than branches, which are translated
+    /// as part of the control flow) into a scratch module.
+    fn check_insts(&self, func: &mut ir::Function, insts: &[ir::Inst], errors: &mut Errors) {
+        let pointer_type = self.pointer_type();
+        let mut cursor = FuncCursor::new(func);
+
+        let mut scratch = WalrusModule::default();
+        let memory_id = scratch.memories.add_local(false, 0, None);
+        let stack_pointer = scratch.globals.add_local(
+            wasm_of_pointer(pointer_type),
+            true,
+            InitExpr::Value(Value::I32(0)),
+        );
+        let mut table = FunctionTable::new(&mut scratch);
+        let func_id =
+            FunctionBuilder::new(&mut scratch.types, &[], &[]).finish(vec![], &mut scratch.funcs);
+        let mut builder = FunctionBuilder::new(&mut scratch.types, &[], &[]);
+
+        // every value is available in a local (unsupported types are reported
+        // by the instructions which produce them)
+        let mut locals: FnvHashMap<_, _> = cursor
+            .func
+            .dfg
+            .values()
+            .map(|value| {
+                let ty = cursor.func.dfg.value_type(value);
+                let locals = ValueLocals::new(&mut scratch.locals, ty)
+                    .or_else(|_| ValueLocals::new(&mut scratch.locals, ir::types::I32))
+                    .unwrap();
+                (value, locals)
+            })
+            .collect();
+
+        let frame = StackFrame::layout(cursor.func, &mut scratch.locals, pointer_type);
+        let operand_table = OperandTable {
+            value_uses: Default::default(),
+            rematerialize: Default::default(),
+            block_params: Default::default(),
+        };
+        let (mut block_to_seq, mut multi_to_block) = (Default::default(), Default::default());
+        let mut pending_symbol_refs = Vec::new();
+        let can_branch_to = CanBranchTo {
+            destinations: Default::default(),
+        };
+
+        let mut translator = IndividualFunctionTranslator::new(
+            &mut scratch.locals,
+            &mut cursor,
+            &mut block_to_seq,
+            &mut multi_to_block,
+            &operand_table,
+            &mut locals,
+            &self.functions,
+            &mut table,
+            &mut scratch.types,
+            &self.decls,
+            &self.data_layout,
+            &mut pending_symbol_refs,
+            func_id,
+            memory_id,
+            pointer_type,
+            frame.as_ref(),
+            stack_pointer,
+            self.nontrapping_fptoint,
+            self.memory_config.shared,
+        );
+
+        for inst in insts {
+            match translator.cursor.func.dfg[*inst] {
+                InstructionData::Jump { .. } | InstructionData::Branch { .. } => (),
+                // only integers which fit in an `i64` can be compared
+                InstructionData::BranchIcmp { ref args, .. } => {
+                    let func = &translator.cursor.func;
+                    let ty = func.dfg.value_type(args.as_slice(&func.dfg.value_lists)[0]);
+                    if !is_i32(ty) && ty != ir::types::I64 {
+                        errors.push(
+                            TranslationError::new(ErrorKind::UnsupportedType(ty)).at(*inst, func),
+                        );
+                    }
+                }
+                InstructionData::BranchTable { arg, .. } => {
+                    let func = &translator.cursor.func;
+                    let ty = func.dfg.value_type(arg);
+                    if !is_i32(ty) && ty != ir::types::I64 {
+                        errors.push(
+                            TranslationError::new(ErrorKind::UnsupportedType(ty)).at(*inst, func),
+                        );
+                    }
+                }
+                InstructionData::MultiAry {
+                    opcode: ir::Opcode::Return,
+                    ..
+                } => (),
+                _ => {
+                    if let Err(e) = build_wasm_inst(
+                        *inst,
+                        &mut translator,
+                        &mut builder.func_body(),
+                        &can_branch_to,
+                    ) {
+                        errors.push(e);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// The errors found so far (each of which is only reported once).
+struct Errors(Vec<TranslationError>);
+
+impl Errors {
+    fn push(&mut self, error: TranslationError) {
+        if !self.0.contains(&error) {
+            self.0.push(error);
+        }
+    }
+}
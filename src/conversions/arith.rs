@@ -0,0 +1,164 @@
+//! Integer multiplication, division and remainder.
+//!
+//! WebAssembly traps in the same cases as Cranelift: when dividing by zero,
+//! and when the result of a signed division overflows (`srem` of the minimum
+//! value by -1 is zero in both). Narrow integers (see [super::narrow]) are
+//! extended before they are divided, and so have to be checked for overflow
+//! separately. The high halves of products are computed by widening the
+//! operands to `i64`s where possible, and from the products of 32-bit halves
+//! otherwise.
+
+use cranelift_codegen::ir::{self, types::I64};
+use walrus::{
+    ir::{BinaryOp, UnaryOp},
+    InstrSeqBuilder, ModuleLocals, ValType,
+};
+
+use crate::error::{ErrorKind, TranslationResult};
+
+use super::{
+    narrow::{extend, is_i32, is_narrow},
+    pair::{umulhi64, Half},
+};
+
+/// Whether `opcode` is a multiplication, division or remainder.
+pub(crate) fn is_muldiv(opcode: ir::Opcode) -> bool {
+    matches!(
+        opcode,
+        ir::Opcode::Imul
+            | ir::Opcode::Umulhi
+            | ir::Opcode::Smulhi
+            | ir::Opcode::Udiv
+            | ir::Opcode::Sdiv
+            | ir::Opcode::Urem
+            | ir::Opcode::Srem
+    )
+}
+
+/// Applies `opcode` to the two values (of type `ty`) on top of the stack.
+pub(crate) fn build_muldiv(
+    opcode: ir::Opcode,
+    ty: ir::Type,
+    locals: &mut ModuleLocals,
+    builder: &mut InstrSeqBuilder,
+) -> TranslationResult<()> {
+    if !is_i32(ty) && ty != I64 {
+        return Err(ErrorKind::UnsupportedType(ty).into());
+    }
+    let wide = ty == I64;
+    match opcode {
+        ir::Opcode::Imul => {
+            builder.binop(if wide {
+                BinaryOp::I64Mul
+            } else {
+                BinaryOp::I32Mul
+            });
+        }
+        ir::Opcode::Umulhi | ir::Opcode::Smulhi => {
+            build_mulhi(opcode == ir::Opcode::Smulhi, ty, locals, builder)
+        }
+        ir::Opcode::Udiv | ir::Opcode::Sdiv | ir::Opcode::Urem | ir::Opcode::Srem => {
+            let signed = matches!(opcode, ir::Opcode::Sdiv | ir::Opcode::Srem);
+            if is_narrow(ty) {
+                let (x, y) = (locals.add(ValType::I32), locals.add(ValType::I32));
+                builder.local_set(y);
+                extend(builder, ty, signed);
+                builder.local_set(x).local_get(y);
+                extend(builder, ty, signed);
+                builder.local_set(y);
+                if opcode == ir::Opcode::Sdiv {
+                    // the quotient of the minimum value and -1 does not
+                    // overflow an `i32`
+                    builder
+                        .local_get(x)
+                        .i32_const(i32::MIN >> (32 - ty.bits()))
+                        .binop(BinaryOp::I32Eq)
+                        .local_get(y)
+                        .i32_const(-1)
+                        .binop(BinaryOp::I32Eq)
+                        .binop(BinaryOp::I32And)
+                        .if_else(
+                            None,
+                            |overflow| {
+                                overflow.unreachable();
+                            },
+                            |_| (),
+                        );
+                }
+                builder.local_get(x).local_get(y);
+            }
+            builder.binop(match (opcode, wide) {
+                (ir::Opcode::Udiv, false) => BinaryOp::I32DivU,
+                (ir::Opcode::Udiv, true) => BinaryOp::I64DivU,
+                (ir::Opcode::Sdiv, false) => BinaryOp::I32DivS,
+                (ir::Opcode::Sdiv, true) => BinaryOp::I64DivS,
+                (ir::Opcode::Urem, false) => BinaryOp::I32RemU,
+                (ir::Opcode::Urem, true) => BinaryOp::I64RemU,
+                (_, false) => BinaryOp::I32RemS,
+                (_, true) => BinaryOp::I64RemS,
+            });
+        }
+        _ => return Err(ErrorKind::UnsupportedOpcode(opcode).into()),
+    }
+    Ok(())
+}
+
+/// Computes the high half of the product of the two values (of type `ty`) on
+/// top of the stack.
+fn build_mulhi(
+    signed: bool,
+    ty: ir::Type,
+    locals: &mut ModuleLocals,
+    builder: &mut InstrSeqBuilder,
+) {
+    if is_narrow(ty) {
+        // the whole product fits in an `i32`
+        let y = locals.add(ValType::I32);
+        builder.local_set(y);
+        extend(builder, ty, signed);
+        builder.local_get(y);
+        extend(builder, ty, signed);
+        builder
+            .binop(BinaryOp::I32Mul)
+            .i32_const(ty.bits() as i32)
+            .binop(if signed {
+                BinaryOp::I32ShrS
+            } else {
+                BinaryOp::I32ShrU
+            });
+    } else if ty != I64 {
+        // the whole product fits in an `i64`
+        let widen = if signed {
+            UnaryOp::I64ExtendSI32
+        } else {
+            UnaryOp::I64ExtendUI32
+        };
+        let y = locals.add(ValType::I32);
+        builder
+            .local_set(y)
+            .unop(widen)
+            .local_get(y)
+            .unop(widen)
+            .binop(BinaryOp::I64Mul)
+            .i64_const(32)
+            .binop(BinaryOp::I64ShrU)
+            .unop(UnaryOp::I32WrapI64);
+    } else {
+        let (x, y) = (locals.add(ValType::I64), locals.add(ValType::I64));
+        builder.local_set(y).local_set(x);
+        umulhi64(Half::Local(x), Half::Local(y), builder);
+        if signed {
+            // a negative operand is 2^64 more when it is treated as unsigned,
+            // which adds the other operand to the high half of the product
+            for (negative, other) in [(x, y), (y, x)] {
+                builder
+                    .local_get(negative)
+                    .i64_const(63)
+                    .binop(BinaryOp::I64ShrS)
+                    .local_get(other)
+                    .binop(BinaryOp::I64And)
+                    .binop(BinaryOp::I64Sub);
+            }
+        }
+    }
+}
@@ -0,0 +1,211 @@
+//! Atomic memory accesses, which use the instructions of the threads proposal
+//! (and so are only translated if the module's memory is shared).
+//!
+//! WebAssembly has no atomic `nand`, minimum or maximum, so these are
+//! translated into loops which retry a compare-and-exchange until no other
+//! thread has modified the value in the meantime.
+
+use cranelift_codegen::ir::{
+    self,
+    types::{I16, I32, I64, I8},
+    AtomicRmwOp,
+};
+use walrus::{
+    ir::{AtomicOp, AtomicWidth, BinaryOp, ExtendedLoad, LoadKind, MemArg, StoreKind},
+    InstrSeqBuilder, MemoryId, ModuleLocals, ValType,
+};
+
+use crate::error::{ErrorKind, TranslationResult};
+
+use super::{
+    narrow::{extend, is_i32},
+    ty::wasm_of_pointer,
+};
+
+/// Checks that atomic instructions can be used, i.e. that the memory is
+/// shared.
+pub(crate) fn check_shared(shared_memory: bool) -> TranslationResult<()> {
+    if shared_memory {
+        Ok(())
+    } else {
+        Err(ErrorKind::Unsupported(
+            "atomic instructions require a shared memory (and the threads proposal)".to_string(),
+        )
+        .into())
+    }
+}
+
+/// Returns the width of an atomic read-modify-write of a value of type `ty`.
+fn atomic_width(ty: ir::Type) -> TranslationResult<AtomicWidth> {
+    Ok(match ty {
+        I8 => AtomicWidth::I32_8,
+        I16 => AtomicWidth::I32_16,
+        I32 => AtomicWidth::I32,
+        I64 => AtomicWidth::I64,
+        _ => return Err(ErrorKind::UnsupportedType(ty).into()),
+    })
+}
+
+/// The `memarg` of an atomic access to a value of type `ty` (which must be
+/// naturally aligned).
+fn atomic_arg(ty: ir::Type) -> MemArg {
+    MemArg {
+        align: ty.bytes(),
+        offset: 0,
+    }
+}
+
+/// Returns the kind of atomic load which reads a value of type `ty`.
+fn atomic_load_kind(ty: ir::Type) -> TranslationResult<LoadKind> {
+    Ok(match ty {
+        I8 => LoadKind::I32_8 {
+            kind: ExtendedLoad::ZeroExtendAtomic,
+        },
+        I16 => LoadKind::I32_16 {
+            kind: ExtendedLoad::ZeroExtendAtomic,
+        },
+        I32 => LoadKind::I32 { atomic: true },
+        I64 => LoadKind::I64 { atomic: true },
+        _ => return Err(ErrorKind::UnsupportedType(ty).into()),
+    })
+}
+
+/// Atomically loads a value of type `ty` from the address on top of the stack.
+pub(crate) fn build_atomic_load(
+    ty: ir::Type,
+    memory: MemoryId,
+    builder: &mut InstrSeqBuilder,
+) -> TranslationResult<()> {
+    builder.load(memory, atomic_load_kind(ty)?, atomic_arg(ty));
+    Ok(())
+}
+
+/// Atomically stores the value (of type `ty`) on top of the stack at the
+/// address below it.
+pub(crate) fn build_atomic_store(
+    ty: ir::Type,
+    memory: MemoryId,
+    builder: &mut InstrSeqBuilder,
+) -> TranslationResult<()> {
+    let kind = match ty {
+        I8 => StoreKind::I32_8 { atomic: true },
+        I16 => StoreKind::I32_16 { atomic: true },
+        I32 => StoreKind::I32 { atomic: true },
+        I64 => StoreKind::I64 { atomic: true },
+        _ => return Err(ErrorKind::UnsupportedType(ty).into()),
+    };
+    builder.store(memory, kind, atomic_arg(ty));
+    Ok(())
+}
+
+/// Atomically replaces the value (of type `ty`) at the address below the top
+/// of the stack with the value on top of the stack, if it is equal to the
+/// value between them. The value which was in memory is left on the stack.
+pub(crate) fn build_atomic_cas(
+    ty: ir::Type,
+    memory: MemoryId,
+    builder: &mut InstrSeqBuilder,
+) -> TranslationResult<()> {
+    builder.cmpxchg(memory, atomic_width(ty)?, atomic_arg(ty));
+    Ok(())
+}
+
+/// Atomically applies `op` to the value (of type `ty`) at the address below
+/// the top of the stack and the value on top of the stack, leaving the value
+/// which was in memory on the stack.
+pub(crate) fn build_atomic_rmw(
+    op: AtomicRmwOp,
+    ty: ir::Type,
+    pointer_type: ir::Type,
+    memory: MemoryId,
+    locals: &mut ModuleLocals,
+    builder: &mut InstrSeqBuilder,
+) -> TranslationResult<()> {
+    let width = atomic_width(ty)?;
+    let wasm_op = match op {
+        AtomicRmwOp::Add => Some(AtomicOp::Add),
+        AtomicRmwOp::Sub => Some(AtomicOp::Sub),
+        AtomicRmwOp::And => Some(AtomicOp::And),
+        AtomicRmwOp::Or => Some(AtomicOp::Or),
+        AtomicRmwOp::Xor => Some(AtomicOp::Xor),
+        AtomicRmwOp::Xchg => Some(AtomicOp::Xchg),
+        AtomicRmwOp::Nand
+        | AtomicRmwOp::Umin
+        | AtomicRmwOp::Umax
+        | AtomicRmwOp::Smin
+        | AtomicRmwOp::Smax => None,
+    };
+    if let Some(wasm_op) = wasm_op {
+        builder.atomic_rmw(memory, wasm_op, width, atomic_arg(ty));
+        return Ok(());
+    }
+
+    let load_kind = atomic_load_kind(ty)?;
+    let wide = !is_i32(ty);
+    let (val_ty, and, xor, ne) = if wide {
+        (
+            ValType::I64,
+            BinaryOp::I64And,
+            BinaryOp::I64Xor,
+            BinaryOp::I64Ne,
+        )
+    } else {
+        (
+            ValType::I32,
+            BinaryOp::I32And,
+            BinaryOp::I32Xor,
+            BinaryOp::I32Ne,
+        )
+    };
+    let address = locals.add(wasm_of_pointer(pointer_type));
+    let (operand, old) = (locals.add(val_ty), locals.add(val_ty));
+    builder.local_set(operand).local_set(address);
+
+    let signed = matches!(op, AtomicRmwOp::Smin | AtomicRmwOp::Smax);
+    let push = |builder: &mut InstrSeqBuilder, local| {
+        builder.local_get(local);
+        extend(builder, ty, signed);
+    };
+    builder.loop_(None, |body| {
+        let retry = body.id();
+        body.local_get(address)
+            .local_get(address)
+            .load(memory, load_kind, atomic_arg(ty))
+            .local_tee(old);
+
+        // compute the new value from the old one
+        if op == AtomicRmwOp::Nand {
+            body.local_get(old).local_get(operand).binop(and);
+            if wide {
+                body.i64_const(-1);
+            } else {
+                body.i32_const(-1);
+            }
+            body.binop(xor);
+        } else {
+            let cmp = match (op, wide) {
+                (AtomicRmwOp::Umin, false) => BinaryOp::I32LtU,
+                (AtomicRmwOp::Umin, true) => BinaryOp::I64LtU,
+                (AtomicRmwOp::Umax, false) => BinaryOp::I32GtU,
+                (AtomicRmwOp::Umax, true) => BinaryOp::I64GtU,
+                (AtomicRmwOp::Smin, false) => BinaryOp::I32LtS,
+                (AtomicRmwOp::Smin, true) => BinaryOp::I64LtS,
+                (_, false) => BinaryOp::I32GtS,
+                (_, true) => BinaryOp::I64GtS,
+            };
+            push(body, old);
+            push(body, operand);
+            push(body, old);
+            push(body, operand);
+            body.binop(cmp).select(None);
+        }
+
+        // try again if the value in memory has changed since it was loaded
+        body.cmpxchg(memory, width, atomic_arg(ty))
+            .local_get(old)
+            .binop(ne)
+            .br_if(retry);
+    });
+    builder.local_get(old);
+    Ok(())
+}
@@ -0,0 +1,296 @@
+//! Bitwise operations, shifts and rotations, and counting bits.
+//!
+//! These map directly onto WebAssembly instructions for `i32`s and `i64`s
+//! (both Cranelift and WebAssembly take shift amounts modulo the width of the
+//! shifted value). Narrow integers (see [super::narrow]) need more care: their
+//! shift amounts have to be masked, and they are extended wherever their upper
+//! bits would otherwise be shifted or counted into the result.
+
+use cranelift_codegen::ir::{self, types::I64};
+use walrus::{
+    ir::{BinaryOp, UnaryOp},
+    InstrSeqBuilder, ModuleLocals, ValType,
+};
+
+use crate::error::{ErrorKind, TranslationResult};
+
+use super::narrow::{extend, is_i32, is_narrow};
+
+/// Whether `opcode` is a (binary) bitwise operation, shift or rotation.
+pub(crate) fn is_bits_binop(opcode: ir::Opcode) -> bool {
+    matches!(
+        opcode,
+        ir::Opcode::Band
+            | ir::Opcode::Bor
+            | ir::Opcode::Bxor
+            | ir::Opcode::BandNot
+            | ir::Opcode::BorNot
+            | ir::Opcode::BxorNot
+    ) || is_shift(opcode)
+}
+
+/// Whether `opcode` is a shift or a rotation (whose second operand may have a
+/// different type to the first).
+pub(crate) fn is_shift(opcode: ir::Opcode) -> bool {
+    matches!(
+        opcode,
+        ir::Opcode::Ishl
+            | ir::Opcode::Ushr
+            | ir::Opcode::Sshr
+            | ir::Opcode::Rotl
+            | ir::Opcode::Rotr
+    )
+}
+
+/// Whether `opcode` is a unary bitwise operation (or counts bits).
+pub(crate) fn is_bits_unop(opcode: ir::Opcode) -> bool {
+    matches!(
+        opcode,
+        ir::Opcode::Bnot
+            | ir::Opcode::Clz
+            | ir::Opcode::Ctz
+            | ir::Opcode::Cls
+            | ir::Opcode::Popcnt
+            | ir::Opcode::Bitrev
+    )
+}
+
+/// Converts the shift amount (of type `amount_ty`) on top of the stack into a
+/// WebAssembly value of the same type as the shifted value (of type `ty`).
+///
+/// Only the low bits of the amount are used, so it does not need to be
+/// extended.
+pub(crate) fn build_shift_amount(amount_ty: ir::Type, ty: ir::Type, builder: &mut InstrSeqBuilder) {
+    if is_i32(ty) && amount_ty == I64 {
+        builder.unop(UnaryOp::I32WrapI64);
+    } else if ty == I64 && is_i32(amount_ty) {
+        builder.unop(UnaryOp::I64ExtendUI32);
+    }
+}
+
+/// Applies `opcode` to the two values on top of the stack, the first of which
+/// has type `ty` (and the second of which is a shift amount of the same
+/// WebAssembly type, if `opcode` is a shift).
+pub(crate) fn build_bits_binop(
+    opcode: ir::Opcode,
+    ty: ir::Type,
+    locals: &mut ModuleLocals,
+    builder: &mut InstrSeqBuilder,
+) -> TranslationResult<()> {
+    match opcode {
+        ir::Opcode::Band => {
+            builder.binop(by_width(ty, BinaryOp::I32And, BinaryOp::I64And)?);
+        }
+        ir::Opcode::Bor => {
+            builder.binop(by_width(ty, BinaryOp::I32Or, BinaryOp::I64Or)?);
+        }
+        ir::Opcode::Bxor => {
+            builder.binop(by_width(ty, BinaryOp::I32Xor, BinaryOp::I64Xor)?);
+        }
+        ir::Opcode::BandNot | ir::Opcode::BorNot | ir::Opcode::BxorNot => {
+            build_bits_unop(ir::Opcode::Bnot, ty, locals, builder)?;
+            let op = match opcode {
+                ir::Opcode::BandNot => ir::Opcode::Band,
+                ir::Opcode::BorNot => ir::Opcode::Bor,
+                _ => ir::Opcode::Bxor,
+            };
+            build_bits_binop(op, ty, locals, builder)?;
+        }
+        ir::Opcode::Ishl if !is_narrow(ty) => {
+            builder.binop(by_width(ty, BinaryOp::I32Shl, BinaryOp::I64Shl)?);
+        }
+        ir::Opcode::Ushr if !is_narrow(ty) => {
+            builder.binop(by_width(ty, BinaryOp::I32ShrU, BinaryOp::I64ShrU)?);
+        }
+        ir::Opcode::Sshr if !is_narrow(ty) => {
+            builder.binop(by_width(ty, BinaryOp::I32ShrS, BinaryOp::I64ShrS)?);
+        }
+        ir::Opcode::Rotl if !is_narrow(ty) => {
+            builder.binop(by_width(ty, BinaryOp::I32Rotl, BinaryOp::I64Rotl)?);
+        }
+        ir::Opcode::Rotr if !is_narrow(ty) => {
+            builder.binop(by_width(ty, BinaryOp::I32Rotr, BinaryOp::I64Rotr)?);
+        }
+        ir::Opcode::Ishl | ir::Opcode::Ushr | ir::Opcode::Sshr => {
+            // the upper bits of the shifted value are only discarded by `ishl`
+            let amount = locals.add(ValType::I32);
+            builder
+                .i32_const(ty.bits() as i32 - 1)
+                .binop(BinaryOp::I32And)
+                .local_set(amount);
+            let op = match opcode {
+                ir::Opcode::Ishl => BinaryOp::I32Shl,
+                ir::Opcode::Ushr => {
+                    extend(builder, ty, false);
+                    BinaryOp::I32ShrU
+                }
+                _ => {
+                    extend(builder, ty, true);
+                    BinaryOp::I32ShrS
+                }
+            };
+            builder.local_get(amount).binop(op);
+        }
+        ir::Opcode::Rotl | ir::Opcode::Rotr => {
+            // the value is shifted one way by `amount` and the other way by
+            // `bits - amount`, which moves the bits that were shifted out
+            // back in (or moves nothing, if `amount` is zero)
+            let (amount, value) = (locals.add(ValType::I32), locals.add(ValType::I32));
+            builder
+                .i32_const(ty.bits() as i32 - 1)
+                .binop(BinaryOp::I32And)
+                .local_set(amount)
+                .local_set(value);
+            let left = opcode == ir::Opcode::Rotl;
+            let push_value = |builder: &mut InstrSeqBuilder, zero_extend: bool| {
+                builder.local_get(value);
+                if zero_extend {
+                    extend(builder, ty, false);
+                }
+            };
+            let (first, second) = if left {
+                (BinaryOp::I32Shl, BinaryOp::I32ShrU)
+            } else {
+                (BinaryOp::I32ShrU, BinaryOp::I32Shl)
+            };
+            push_value(builder, !left);
+            builder.local_get(amount).binop(first);
+            push_value(builder, left);
+            builder
+                .i32_const(ty.bits() as i32)
+                .local_get(amount)
+                .binop(BinaryOp::I32Sub)
+                .binop(second)
+                .binop(BinaryOp::I32Or);
+        }
+        _ => return Err(ErrorKind::UnsupportedOpcode(opcode).into()),
+    }
+    Ok(())
+}
+
+/// Applies `opcode` to the value (of type `ty`) on top of the stack.
+pub(crate) fn build_bits_unop(
+    opcode: ir::Opcode,
+    ty: ir::Type,
+    locals: &mut ModuleLocals,
+    builder: &mut InstrSeqBuilder,
+) -> TranslationResult<()> {
+    let wide = by_width(ty, false, true)?;
+    let push_const = |builder: &mut InstrSeqBuilder, value: i64| {
+        if wide {
+            builder.i64_const(value);
+        } else {
+            builder.i32_const(value as i32);
+        }
+    };
+    // the number of upper bits of the WebAssembly value which are not part of
+    // a narrow value
+    let padding = if wide { 0 } else { 32 - ty.bits() as i64 };
+
+    match opcode {
+        ir::Opcode::Bnot => {
+            push_const(builder, -1);
+            builder.binop(by_width(ty, BinaryOp::I32Xor, BinaryOp::I64Xor)?);
+        }
+        ir::Opcode::Clz => {
+            extend(builder, ty, false);
+            builder.unop(by_width(ty, UnaryOp::I32Clz, UnaryOp::I64Clz)?);
+            if padding != 0 {
+                builder.i32_const(padding as i32).binop(BinaryOp::I32Sub);
+            }
+        }
+        ir::Opcode::Ctz => {
+            // a bit just above a narrow value stops the count at its width
+            if is_narrow(ty) {
+                builder.i32_const(1 << ty.bits()).binop(BinaryOp::I32Or);
+            }
+            builder.unop(by_width(ty, UnaryOp::I32Ctz, UnaryOp::I64Ctz)?);
+        }
+        ir::Opcode::Popcnt => {
+            extend(builder, ty, false);
+            builder.unop(by_width(ty, UnaryOp::I32Popcnt, UnaryOp::I64Popcnt)?);
+        }
+        ir::Opcode::Cls => {
+            // the bits which are equal to the next higher bit are zero in
+            // `x ^ (x >> 1)`, so counting them starts from the sign bit
+            let value = locals.add(by_width(ty, ValType::I32, ValType::I64)?);
+            extend(builder, ty, true);
+            builder.local_tee(value).local_get(value);
+            push_const(builder, 1);
+            builder
+                .binop(by_width(ty, BinaryOp::I32ShrS, BinaryOp::I64ShrS)?)
+                .binop(by_width(ty, BinaryOp::I32Xor, BinaryOp::I64Xor)?)
+                .unop(by_width(ty, UnaryOp::I32Clz, UnaryOp::I64Clz)?);
+            push_const(builder, padding + 1);
+            builder.binop(by_width(ty, BinaryOp::I32Sub, BinaryOp::I64Sub)?);
+        }
+        ir::Opcode::Bitrev => swap_groups(1, ty, locals, builder)?,
+        _ => return Err(ErrorKind::UnsupportedOpcode(opcode).into()),
+    }
+    Ok(())
+}
+
+/// Reverses the order of the bytes of the value (of type `ty`) on top of the
+/// stack.
+pub(crate) fn build_bswap(
+    ty: ir::Type,
+    locals: &mut ModuleLocals,
+    builder: &mut InstrSeqBuilder,
+) -> TranslationResult<()> {
+    swap_groups(8, ty, locals, builder)
+}
+
+/// Reverses the order of the groups of `size` bits in the value (of type `ty`)
+/// on top of the stack.
+///
+/// Adjacent groups, then pairs of groups, and so on are swapped (which leaves
+/// the upper bits of a narrow value undefined, as usual).
+fn swap_groups(
+    size: u16,
+    ty: ir::Type,
+    locals: &mut ModuleLocals,
+    builder: &mut InstrSeqBuilder,
+) -> TranslationResult<()> {
+    let wide = by_width(ty, false, true)?;
+    let push_const = |builder: &mut InstrSeqBuilder, value: i64| {
+        if wide {
+            builder.i64_const(value);
+        } else {
+            builder.i32_const(value as i32);
+        }
+    };
+    let value = locals.add(by_width(ty, ValType::I32, ValType::I64)?);
+    let mut step = size;
+    while step < ty.bits() {
+        // the lower half of each group of `2 * step` bits
+        #[allow(clippy::manual_is_multiple_of)] // (which needs Rust 1.87)
+        let mask = (0..64)
+            .filter(|bit| (bit / step) % 2 == 0)
+            .fold(0u64, |mask, bit| mask | 1 << bit) as i64;
+        builder.local_tee(value);
+        push_const(builder, step as i64);
+        builder.binop(by_width(ty, BinaryOp::I32ShrU, BinaryOp::I64ShrU)?);
+        push_const(builder, mask);
+        builder
+            .binop(by_width(ty, BinaryOp::I32And, BinaryOp::I64And)?)
+            .local_get(value);
+        push_const(builder, mask);
+        builder.binop(by_width(ty, BinaryOp::I32And, BinaryOp::I64And)?);
+        push_const(builder, step as i64);
+        builder
+            .binop(by_width(ty, BinaryOp::I32Shl, BinaryOp::I64Shl)?)
+            .binop(by_width(ty, BinaryOp::I32Or, BinaryOp::I64Or)?);
+        step *= 2;
+    }
+    Ok(())
+}
+
+/// Picks the operation for values which are represented by `i32`s or `i64`s,
+/// depending on `ty`.
+fn by_width<T>(ty: ir::Type, i32: T, i64: T) -> TranslationResult<T> {
+    match ty {
+        I64 => Ok(i64),
+        ty if is_i32(ty) => Ok(i32),
+        _ => Err(ErrorKind::UnsupportedType(ty).into()),
+    }
+}
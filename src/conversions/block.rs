@@ -3,40 +3,64 @@ use cranelift_codegen::{
     ir::{self, InstInserterBase, InstructionData},
 };
 use fnv::FnvHashMap;
-use relooper::BranchMode;
-use walrus::{ir::BinaryOp, InstrSeqBuilder, LocalId};
+use walrus::{
+    ir::{BinaryOp, Block as WasmBlock, InstrSeqId, UnaryOp},
+    InstrSeqBuilder, LocalId, ValType,
+};
+
+use crate::{
+    error::{ErrorKind, TranslationError, TranslationResult},
+    structure::Node,
+    IndividualFunctionTranslator,
+};
 
-use crate::IndividualFunctionTranslator;
+use super::{
+    cond::{build_icmp, is_signed},
+    inst::{bind_results, build_wasm_inst, loads_mutable_memory, translate_value},
+    narrow::{extend, extend_argument, is_i32},
+};
 
-use super::inst::translate_value;
+/// How each block which the block being built can branch to is reached.
+pub struct CanBranchTo {
+    pub(crate) destinations: FnvHashMap<u32, Branch>,
+}
 
-pub struct CanBranchTo<'a> {
-    pub(crate) from_relooper: &'a FnvHashMap<u32, BranchMode>,
-    pub(crate) locally_computed: FnvHashMap<u32, BranchInstr>,
+/// How a branch reaches a Cranelift block.
+#[derive(Debug, Clone, Copy)]
+pub enum Branch {
+    /// By branching to the WebAssembly block (or loop) which leads to it.
+    Br(BranchInstr),
+    /// Nothing else branches to the node (of the block), so its code is placed
+    /// at the branch (see [`crate::structure`]).
+    Inline(Node),
 }
 
-#[derive(Debug)]
-pub enum BranchInstr {
-    SetLocal(LocalId),
+/// A branch to a Cranelift block: the label is set to the block (if the code
+/// which is branched to dispatches on it) and the WebAssembly block (or loop)
+/// is then branched to.
+#[derive(Debug, Clone, Copy)]
+pub struct BranchInstr {
+    pub(crate) label: Option<LocalId>,
+    pub(crate) seq: InstrSeqId,
 }
 
 /// Maps Cranlift [cranelift_codegen::ir::Block]s to [walrus::ir::InstrSeq]s.
-pub(crate) fn build_wasm_block<'clif>(
+pub(crate) fn build_wasm_block(
     block: ir::Block,
     t: &mut IndividualFunctionTranslator<'_>,
     builder: &mut InstrSeqBuilder,
     can_branch_to: &CanBranchTo,
-) {
+) -> TranslationResult<()> {
     log::trace!("building block {:?}", block);
     t.cursor.goto_top(block);
-    build_from_pos(t, builder, can_branch_to);
+    build_from_pos(t, builder, can_branch_to)
 }
 
 fn build_from_pos(
     t: &mut IndividualFunctionTranslator,
     builder: &mut InstrSeqBuilder,
     can_branch_to: &CanBranchTo,
-) {
+) -> TranslationResult<()> {
     while let Some(next) = t.cursor.next_inst() {
         log::trace!("building instruction: {:?}", next);
 
@@ -48,80 +72,36 @@ fn build_from_pos(
                 destination,
             } => {
                 log::trace!("instruction {:#?} was a jump", next);
-                if let Some(jump_to) = t.operand_table.block_params.get(destination) {
-                    log::trace!(
-                        "obtained table of cranelift<->wasm correspondence: {:?}",
-                        jump_to
-                    );
-                    let args = args
-                        .as_slice(&t.cursor.func.dfg.value_lists)
-                        .iter()
-                        .map(|x| *x)
-                        .clone()
-                        .collect::<Vec<_>>();
-                    log::trace!("args: {:#?}", args);
-
-                    for (value, (_, local)) in args.iter().zip(jump_to.iter()) {
-                        translate_value(*value, t, builder, can_branch_to);
-                        builder.local_set(*local);
-                    }
-                } else {
-                    log::trace!(
-                        "could not find table of arguments for block {:?}",
-                        destination
-                    );
-                }
-
-                if let Some(method) = can_branch_to.locally_computed.get(&destination.as_u32()) {
-                    log::trace!("found computed branching method: {:#?}", method);
-                    match method {
-                        BranchInstr::SetLocal(local) => {
-                            builder
-                                .i32_const(destination.as_u32() as i32)
-                                .local_set(*local);
-                        }
-                    }
-                } else {
-                    if let Some(mode) = can_branch_to.from_relooper.get(&destination.as_u32()) {
-                        log::trace!("found mode: {:#?}", mode);
-
-                        match mode {
-                            // todo: `LoopBreak` and `LoopContinue` are _not_ the same
-                            BranchMode::LoopContinue(id) => {
-                                // jump back to the top of the loop
-                                let seq_id = t.loop_to_block.get(&id).expect("internal error");
-                                builder.br(*seq_id);
-                            }
-                            BranchMode::LoopBreak(_) => {
-                                // to break from a loop, we simply don't build any of the remaining
-                                // blocks – this means we will reach the end, and then exit
-                                return;
-                            }
-                            // todo: handle these
-                            BranchMode::LoopBreakIntoMulti(_) => todo!(),
-                            BranchMode::LoopContinueIntoMulti(_) => todo!(),
-                            BranchMode::MergedBranch
-                            | BranchMode::MergedBranchIntoMulti
-                            | BranchMode::SetLabelAndBreak => todo!(),
-                        }
-                    } else {
-                        log::trace!("could not find branching mode from relooper");
-                        // todo: is doing nothing the correct way of handling
-                        // this?
-                    };
-                }
+                let args = args.as_slice(&t.cursor.func.dfg.value_lists).to_vec();
+                pass_block_args(*destination, &args, t, builder, can_branch_to)?;
+                build_jump(next, *destination, t, builder, can_branch_to)?;
+            }
+            InstructionData::BranchTable {
+                arg,
+                destination,
+                table,
+                ..
+            } => {
+                log::trace!("instruction {:#?} was a br_table", next);
+                build_br_table(next, *arg, *destination, *table, t, builder, can_branch_to)?;
             }
             ir::InstructionData::MultiAry { opcode, args } => {
                 if opcode == &ir::Opcode::Return {
                     let pool = &t.cursor.data_flow_graph().value_lists;
-                    let args = args.as_slice(pool).iter().map(|x| *x).collect::<Vec<_>>();
+                    let args = args.as_slice(pool).to_vec();
                     log::trace!("args: {:#?}", args);
-                    for arg in args {
-                        translate_value(arg, t, builder, can_branch_to);
+                    let returns = t.cursor.func.signature.returns.clone();
+                    for (arg, ret) in args.into_iter().zip(returns) {
+                        translate_value(arg, t, builder, can_branch_to)?;
+                        extend_argument(builder, ret.value_type, ret.extension);
+                    }
+                    if let Some(frame) = t.frame {
+                        frame.build_epilogue(t.stack_pointer, builder);
                     }
                     builder.return_();
                 } else {
-                    panic!("MultiAry {:#?} has not been implemented", opcode)
+                    return Err(TranslationError::new(ErrorKind::UnsupportedOpcode(*opcode))
+                        .at(next, t.cursor.func));
                 }
             }
             ir::InstructionData::Branch {
@@ -130,66 +110,29 @@ fn build_from_pos(
                 destination,
             } => {
                 log::trace!("instruction {:#?} was a branch", next);
-                // note: this is needed because `br_if` will continue the loop if true
-                // we want to branch based on the truth/false-ness of the operand, so we `br_if`
-                // (i.e.) don't branch IF the condition is false, otherwise we do branch
-                let negate = can_branch_to
-                    .from_relooper
-                    .get(&destination.as_u32())
-                    .map(|x| match x {
-                        BranchMode::LoopBreak(_) => true,
-                        _ => false,
-                    })
-                    .unwrap_or(false);
+                let args = args.as_slice(&t.cursor.func.dfg.value_lists).to_vec();
 
-                log::trace!("negating: {}", negate);
-
-                if let Some(jump_to) = t.operand_table.block_params.get(destination) {
-                    log::trace!(
-                        "obtained table of cranelift<->wasm correspondence: {:?}",
-                        jump_to
-                    );
-                    let args = args
-                        .as_slice(&t.cursor.func.dfg.value_lists)
-                        .iter()
-                        .map(|x| *x)
-                        .clone()
-                        .collect::<Vec<_>>();
-                    log::trace!("args: {:#?}", args);
-
-                    for (value, (_, local)) in args[1..].iter().zip(jump_to.iter()) {
-                        translate_value(*value, t, builder, can_branch_to);
-                        builder.local_set(*local);
-                    }
-                }
-
-                let arg = args.as_slice(&t.cursor.func.dfg.value_lists)[0];
+                let arg = args[0];
                 let ty = t.cursor.data_flow_graph().value_type(arg);
+                // an `i128` is zero if the bitwise or of its halves is
+                let (ty, is_pair) = if ty == ir::types::I128 {
+                    (ir::types::I64, true)
+                } else {
+                    (ty, false)
+                };
 
-                // if we need to, we negate
-                if negate {
-                    if ty.bits() == 64 {
-                        builder.i64_const(0);
-                    } else if ty.bits() <= 32 {
-                        builder.i32_const(0);
-                    }
+                // we compute the condition
+                translate_value(arg, t, builder, can_branch_to)?;
+                if is_pair {
+                    builder.binop(BinaryOp::I64Or);
                 }
 
-                // then we compute the condition
-                translate_value(arg, t, builder, can_branch_to);
-
-                // if we need to, we finish the negation
-                if negate {
-                    if ty.bits() == 64 {
-                        builder.binop(BinaryOp::I64Sub);
-                    } else {
-                        builder.binop(BinaryOp::I32Sub);
-                    }
-                }
+                // only the low bits of a narrow integer are tested
+                extend(builder, ty, false);
 
+                // (booleans are zero if they are false)
                 if opcode == &ir::Opcode::Brz {
-                    if ty.is_bool() {
-                    } else if ty.bits() == 64 {
+                    if ty.bits() == 64 {
                         builder.i64_const(0);
                         builder.binop(BinaryOp::I64Eq);
                     } else if ty.bits() <= 32
@@ -198,12 +141,12 @@ fn build_from_pos(
                         builder.i32_const(0);
                         builder.binop(BinaryOp::I32Eq);
                     } else {
-                        unreachable!();
+                        return Err(TranslationError::new(ErrorKind::UnsupportedType(ty))
+                            .at(next, t.cursor.func));
                     };
                 }
 
                 if opcode == &ir::Opcode::Brnz {
-                    let ty = t.cursor.data_flow_graph().value_type(arg);
                     if ty.is_bool() {
                     } else if ty.bits() == 64 {
                         builder.i64_const(0);
@@ -214,73 +157,118 @@ fn build_from_pos(
                         builder.i32_const(0);
                         builder.binop(BinaryOp::I32Ne);
                     } else {
-                        unreachable!();
+                        return Err(TranslationError::new(ErrorKind::UnsupportedType(ty))
+                            .at(next, t.cursor.func));
                     };
                 }
 
-                // now work out how we are supposed to branch to the next instruction and apply
-                // it
-                if let Some(mode) = can_branch_to.from_relooper.get(&destination.as_u32()) {
-                    match mode {
-                        BranchMode::LoopBreak(_) => {
-                            builder.if_else(
-                                None,
-                                |_then| {
-                                    // don't do anything, so that if we hit this
-                                    // branch, the loop will
-                                    // then immediately exit
-                                },
-                                |alt| {
-                                    build_from_pos(t, alt, can_branch_to);
-                                },
-                            );
-                        }
-                        BranchMode::LoopBreakIntoMulti(_) => todo!(),
-                        BranchMode::LoopContinue(_) => todo!(),
-                        BranchMode::LoopContinueIntoMulti(_) => todo!(),
-                        BranchMode::MergedBranchIntoMulti => todo!(),
-                        BranchMode::SetLabelAndBreak => todo!(),
-                        BranchMode::MergedBranch => {
-                            let (_, label) = can_branch_to.locally_computed.iter().next().unwrap();
-                            let local = match label {
-                                BranchInstr::SetLocal(l) => l,
-                            };
-                            builder.if_else(
-                                None,
-                                |then| {
-                                    then.i32_const(i32::MAX).local_set(*local);
-                                },
-                                |alt| {
-                                    build_from_pos(t, alt, can_branch_to);
-                                },
-                            );
-                        }
-                    }
-                } else if let Some(method) =
-                    can_branch_to.locally_computed.get(&destination.as_u32())
-                {
-                    // otherwise, try switching into a multiple block
+                build_branch(next, *destination, &args[1..], t, builder, can_branch_to)?;
+            }
+            ir::InstructionData::BranchIcmp {
+                opcode: _,
+                cond,
+                destination,
+                args,
+            } => {
+                let args = args.as_slice(&t.cursor.func.dfg.value_lists).to_vec();
 
-                    // we computed this earlier
-                    if opcode == &ir::Opcode::Brz || opcode == &ir::Opcode::Brnz {
-                        match method {
-                            BranchInstr::SetLocal(label) => {
-                                builder.if_else(
-                                    None,
-                                    |then| {
-                                        then.i32_const(destination.as_u32() as i32)
-                                            .local_set(*label);
-                                    },
-                                    |alt| {
-                                        build_from_pos(t, alt, can_branch_to);
-                                    },
-                                );
-                            }
-                        }
-                    } else {
-                        panic!("operation {:#?} not yet supported", opcode)
-                    }
+                let ty = t.cursor.data_flow_graph().value_type(args[0]);
+                for arg in &args[..2] {
+                    translate_value(*arg, t, builder, can_branch_to)?;
+                    extend(builder, ty, is_signed(*cond));
                 }
+                build_icmp(*cond, ty, t.module_locals, builder)
+                    .map_err(|e| e.at(next, t.cursor.func))?;
+
+                build_branch(next, *destination, &args[2..], t, builder, can_branch_to)?;
+            }
+            // calls have side effects, so they are emitted where they appear
+            // (rather than when their results are first used)
+            InstructionData::Call { .. } | InstructionData::CallIndirect { .. } => {
+                build_wasm_inst(next, t, builder, can_branch_to)?;
+                bind_results(next, t, builder)?;
+            }
+            // loads from (and stores to) memory have to happen in the same order
+            // as in the IR
+            InstructionData::StackLoad {
+                opcode: ir::Opcode::StackLoad,
+                ..
+            }
+            | InstructionData::Load { .. }
+            | InstructionData::LoadComplex { .. } => {
+                build_wasm_inst(next, t, builder, can_branch_to)?;
+                bind_results(next, t, builder)?;
+            }
+            // (including `global_value`s which load from memory that is not
+            // read-only)
+            InstructionData::UnaryGlobalValue {
+                opcode: ir::Opcode::GlobalValue,
+                global_value,
+            } if loads_mutable_memory(*global_value, t.cursor.func) => {
+                build_wasm_inst(next, t, builder, can_branch_to)?;
+                bind_results(next, t, builder)?;
+            }
+            InstructionData::StackStore { .. }
+            | InstructionData::Store { .. }
+            | InstructionData::StoreComplex { .. } => {
+                build_wasm_inst(next, t, builder, can_branch_to)?;
+            }
+            // as are atomic operations (and fences, which order them)
+            InstructionData::AtomicRmw { .. }
+            | InstructionData::AtomicCas { .. }
+            | InstructionData::LoadNoOffset {
+                opcode: ir::Opcode::AtomicLoad,
+                ..
+            } => {
+                build_wasm_inst(next, t, builder, can_branch_to)?;
+                bind_results(next, t, builder)?;
+            }
+            InstructionData::StoreNoOffset {
+                opcode: ir::Opcode::AtomicStore,
+                ..
+            }
+            | InstructionData::NullAry {
+                opcode: ir::Opcode::Fence,
+            } => {
+                build_wasm_inst(next, t, builder, can_branch_to)?;
+            }
+            // the results of an `isplit` are bound to locals (as they cannot be
+            // used separately while on the stack)
+            InstructionData::Unary {
+                opcode: ir::Opcode::Isplit,
+                ..
+            } => {
+                build_wasm_inst(next, t, builder, can_branch_to)?;
+                bind_results(next, t, builder)?;
+            }
+            // instructions which can trap (e.g. divisions) are also emitted
+            // where they appear, so that they trap even if their results are
+            // never used
+            InstructionData::Binary { opcode, .. } | InstructionData::Unary { opcode, .. }
+                if opcode.can_trap() =>
+            {
+                build_wasm_inst(next, t, builder, can_branch_to)?;
+                bind_results(next, t, builder)?;
+            }
+            // (Cranelift does not consider divisions by an immediate to trap,
+            // but they do when it is zero, or minus one for `sdiv_imm`)
+            InstructionData::BinaryImm64 {
+                opcode:
+                    ir::Opcode::SdivImm
+                    | ir::Opcode::UdivImm
+                    | ir::Opcode::SremImm
+                    | ir::Opcode::UremImm,
+                ..
+            } => {
+                build_wasm_inst(next, t, builder, can_branch_to)?;
+                bind_results(next, t, builder)?;
+            }
+            // values which are used more than once are assigned to their
+            // locals where they are defined (their first use may be on another
+            // path through the function, e.g. in another entry of a switch)
+            _ if has_normal_use(next, t) => {
+                build_wasm_inst(next, t, builder, can_branch_to)?;
+                bind_results(next, t, builder)?;
             }
             // everything else is handled by `build_wasm_inst`
             sth => {
@@ -288,4 +276,257 @@ fn build_from_pos(
             }
         }
     }
+    Ok(())
+}
+
+/// Whether any of the results of `inst` is used more than once.
+fn has_normal_use(inst: ir::Inst, t: &IndividualFunctionTranslator) -> bool {
+    let results = t.cursor.func.dfg.inst_results(inst);
+    results
+        .iter()
+        .any(|result| t.operand_table.is_normal_use(*result))
+}
+
+/// Branches to `destination` (from the branch `inst`), passing it `args`, if
+/// the `i32` on top of the stack is non-zero. The rest of the block follows.
+fn build_branch(
+    inst: ir::Inst,
+    destination: ir::Block,
+    args: &[ir::Value],
+    t: &mut IndividualFunctionTranslator,
+    builder: &mut InstrSeqBuilder,
+    can_branch_to: &CanBranchTo,
+) -> TranslationResult<()> {
+    let method = branch_instr(inst, destination, t, can_branch_to)?;
+    log::trace!("branching to {:?} with {:#?}", destination, method);
+
+    // the parameters of the destination may still be used if the branch is
+    // not taken, so they are only assigned in the branch
+    if let Branch::Br(BranchInstr { label: None, seq }) = method {
+        if !has_block_params(destination, t) {
+            builder.br_if(seq);
+            return Ok(());
+        }
+    }
+    let mut taken = Ok(());
+    builder.if_else(
+        None,
+        |then| {
+            taken = pass_block_args(destination, args, t, then, can_branch_to)
+                .and_then(|_| build_jump(inst, destination, t, then, can_branch_to));
+        },
+        |_| {},
+    );
+    taken
+}
+
+/// Jumps (from the `jump` or `br_table` instruction `inst`) to `destination`,
+/// whose parameters have already been assigned.
+fn build_jump(
+    inst: ir::Inst,
+    destination: ir::Block,
+    t: &mut IndividualFunctionTranslator,
+    builder: &mut InstrSeqBuilder,
+    can_branch_to: &CanBranchTo,
+) -> TranslationResult<()> {
+    let method = match branch_instr(inst, destination, t, can_branch_to)? {
+        Branch::Br(method) => method,
+        Branch::Inline(node) => {
+            // the rest of the current block is built afterwards
+            let position = t.cursor.position();
+            let inline = t.compile_dominated(builder, node);
+            t.cursor.set_position(position);
+            return inline;
+        }
+    };
+    if let Some(label) = method.label {
+        builder
+            .i32_const(destination.as_u32() as i32)
+            .local_set(label);
+    }
+    builder.br(method.seq);
+    Ok(())
+}
+
+/// How `destination` is branched to (by `inst`).
+fn branch_instr(
+    inst: ir::Inst,
+    destination: ir::Block,
+    t: &IndividualFunctionTranslator,
+    can_branch_to: &CanBranchTo,
+) -> TranslationResult<Branch> {
+    // every destination has been resolved when the control flow was structured
+    can_branch_to
+        .destinations
+        .get(&destination.as_u32())
+        .copied()
+        .ok_or_else(|| {
+            let reason = format!("no way of branching to `{}` was found", destination);
+            TranslationError::new(ErrorKind::Unsupported(reason)).at(inst, t.cursor.func)
+        })
+}
+
+/// Whether any of the parameters of `destination` is used (and so has to be
+/// assigned by the branches to it).
+fn has_block_params(destination: ir::Block, t: &IndividualFunctionTranslator) -> bool {
+    matches!(
+        t.operand_table.block_params.get(&destination),
+        Some(params) if !params.is_empty()
+    )
+}
+
+/// Branches (from the `br_table` instruction `inst`) to the entry of the jump
+/// table `table` selected by `arg`, or to `default` if `arg` is out of bounds.
+///
+/// Destinations which are reached without setting the label are branched to
+/// straight from the `br_table`. Every other destination gets a block of its
+/// own, nested around the `br_table`, which is followed by a jump to the
+/// destination (which sets the label, or is the code of the destination):
+///
+/// ```text
+/// block $jump_1
+///   block $jump_0
+///     ;; the index
+///     br_table $jump_0 $jump_1 ...
+///   end
+///   ;; jump to the first destination
+/// end
+/// ;; jump to the second destination
+/// ```
+fn build_br_table(
+    inst: ir::Inst,
+    arg: ir::Value,
+    default: ir::Block,
+    table: ir::JumpTable,
+    t: &mut IndividualFunctionTranslator,
+    builder: &mut InstrSeqBuilder,
+    can_branch_to: &CanBranchTo,
+) -> TranslationResult<()> {
+    let entries = t.cursor.func.jump_tables[table].as_slice().to_vec();
+    let mut methods = FnvHashMap::default();
+    for destination in entries.iter().chain([&default]) {
+        methods.insert(
+            *destination,
+            branch_instr(inst, *destination, t, can_branch_to)?,
+        );
+    }
+    let direct = |destination: &ir::Block| match methods[destination] {
+        Branch::Br(BranchInstr { label: None, seq }) => Some(seq),
+        Branch::Br(BranchInstr { label: Some(_), .. }) | Branch::Inline(_) => None,
+    };
+
+    // the destinations which need a block of their own (each only once)
+    let mut jumps = Vec::new();
+    for destination in entries.iter().chain([&default]) {
+        if direct(destination).is_none() && !jumps.contains(destination) {
+            jumps.push(*destination);
+        }
+    }
+    let seqs = jumps
+        .iter()
+        .map(|_| builder.dangling_instr_seq(None).id())
+        .collect::<Vec<_>>();
+    let seq_of = |destination: &ir::Block| {
+        direct(destination).unwrap_or_else(|| {
+            let position = jumps.iter().position(|jump| jump == destination);
+            seqs[position.expect("internal error")]
+        })
+    };
+    let blocks = entries.iter().map(seq_of).collect::<Box<[_]>>();
+    let default_seq = seq_of(&default);
+
+    if seqs.is_empty() {
+        build_table_index(inst, arg, entries.len(), t, builder, can_branch_to)?;
+        builder.br_table(blocks, default_seq);
+        return Ok(());
+    }
+
+    let mut dispatch = builder.instr_seq(seqs[0]);
+    build_table_index(inst, arg, entries.len(), t, &mut dispatch, can_branch_to)?;
+    dispatch.br_table(blocks, default_seq);
+
+    // each jump branches away, so none of them continues into the next
+    for (i, destination) in jumps.iter().enumerate() {
+        let outer = seqs.get(i + 1).copied().unwrap_or_else(|| builder.id());
+        let mut outer = builder.instr_seq(outer);
+        outer.instr(WasmBlock { seq: seqs[i] });
+        build_jump(inst, *destination, t, &mut outer, can_branch_to)?;
+    }
+    Ok(())
+}
+
+/// Pushes the (`i32`) index into a jump table with `len` entries which the
+/// `br_table` instruction `inst` uses for its argument `arg`.
+///
+/// An `i64` argument is replaced by `len` (which selects the default
+/// destination) if it is out of bounds, rather than being wrapped.
+fn build_table_index(
+    inst: ir::Inst,
+    arg: ir::Value,
+    len: usize,
+    t: &mut IndividualFunctionTranslator,
+    builder: &mut InstrSeqBuilder,
+    can_branch_to: &CanBranchTo,
+) -> TranslationResult<()> {
+    let ty = t.cursor.data_flow_graph().value_type(arg);
+    if ty == ir::types::I64 {
+        translate_value(arg, t, builder, can_branch_to)?;
+        let index = t.module_locals.add(ValType::I64);
+        builder
+            .local_tee(index)
+            .unop(UnaryOp::I32WrapI64)
+            .i32_const(len as i32)
+            .local_get(index)
+            .i64_const(len as i64)
+            .binop(BinaryOp::I64LtU)
+            .select(None);
+    } else if is_i32(ty) {
+        translate_value(arg, t, builder, can_branch_to)?;
+        extend(builder, ty, false);
+    } else {
+        return Err(TranslationError::new(ErrorKind::UnsupportedType(ty)).at(inst, t.cursor.func));
+    }
+    Ok(())
+}
+
+/// Assigns `args` to the parameters of `destination`.
+///
+/// Every argument is computed before any of the parameters are assigned, as an
+/// argument may refer to the current value of one of the parameters (e.g. when a
+/// loop swaps two of its parameters).
+fn pass_block_args(
+    destination: ir::Block,
+    args: &[ir::Value],
+    t: &mut IndividualFunctionTranslator,
+    builder: &mut InstrSeqBuilder,
+    can_branch_to: &CanBranchTo,
+) -> TranslationResult<()> {
+    let jump_to = match t.operand_table.block_params.get(&destination) {
+        Some(jump_to) => jump_to,
+        None => {
+            log::trace!("{:?} does not use any of its parameters", destination);
+            return Ok(());
+        }
+    };
+    log::trace!(
+        "obtained table of cranelift<->wasm correspondence: {:?}",
+        jump_to
+    );
+
+    // parameters which are never used do not have a local
+    let params = t.cursor.func.dfg.block_params(destination);
+    let assigned = args
+        .iter()
+        .zip(params)
+        .filter_map(|(arg, param)| Some((*arg, jump_to.get(param)?.clone())))
+        .collect::<Vec<_>>();
+    log::trace!("assigning: {:#?}", assigned);
+
+    for (arg, _) in &assigned {
+        translate_value(*arg, t, builder, can_branch_to)?;
+    }
+    for (_, locals) in assigned.iter().rev() {
+        locals.set(builder);
+    }
+    Ok(())
 }
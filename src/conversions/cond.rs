@@ -1,21 +1,133 @@
-use cranelift_codegen::ir::condcodes::IntCC;
-use walrus::ir::BinaryOp;
+//! Integer comparisons.
+//!
+//! Narrow operands (see [super::narrow]) are extended before they are
+//! compared: sign-extended if [is_signed] holds for the condition, and
+//! zero-extended otherwise.
 
+use cranelift_codegen::ir::{self, condcodes::IntCC, types::I64};
+use walrus::{
+    ir::{BinaryOp, UnaryOp},
+    InstrSeqBuilder, ModuleLocals, ValType,
+};
+
+use crate::error::{ErrorKind, TranslationResult};
+
+use super::narrow::{extend, is_i32, is_narrow};
+
+/// Whether the operands of a comparison using `cond` are treated as signed
+/// integers.
+pub(crate) fn is_signed(cond: IntCC) -> bool {
+    matches!(
+        cond,
+        IntCC::SignedLessThan
+            | IntCC::SignedGreaterThanOrEqual
+            | IntCC::SignedGreaterThan
+            | IntCC::SignedLessThanOrEqual
+            | IntCC::Overflow
+            | IntCC::NotOverflow
+    )
+}
+
+/// The WebAssembly comparison which computes `cond` (which must not be one of
+/// the overflow conditions, which WebAssembly does not have).
 pub(crate) fn wasm_of_cond(cond: IntCC, bits_32: bool) -> BinaryOp {
     match cond {
         IntCC::Equal if bits_32 => BinaryOp::I32Eq,
         IntCC::Equal => BinaryOp::I64Eq,
+        IntCC::NotEqual if bits_32 => BinaryOp::I32Ne,
+        IntCC::NotEqual => BinaryOp::I64Ne,
+        IntCC::SignedLessThan if bits_32 => BinaryOp::I32LtS,
+        IntCC::SignedLessThan => BinaryOp::I64LtS,
+        IntCC::SignedGreaterThanOrEqual if bits_32 => BinaryOp::I32GeS,
+        IntCC::SignedGreaterThanOrEqual => BinaryOp::I64GeS,
+        IntCC::SignedGreaterThan if bits_32 => BinaryOp::I32GtS,
+        IntCC::SignedGreaterThan => BinaryOp::I64GtS,
+        IntCC::SignedLessThanOrEqual if bits_32 => BinaryOp::I32LeS,
+        IntCC::SignedLessThanOrEqual => BinaryOp::I64LeS,
+        IntCC::UnsignedLessThan if bits_32 => BinaryOp::I32LtU,
+        IntCC::UnsignedLessThan => BinaryOp::I64LtU,
+        IntCC::UnsignedGreaterThanOrEqual if bits_32 => BinaryOp::I32GeU,
+        IntCC::UnsignedGreaterThanOrEqual => BinaryOp::I64GeU,
+        IntCC::UnsignedGreaterThan if bits_32 => BinaryOp::I32GtU,
+        IntCC::UnsignedGreaterThan => BinaryOp::I64GtU,
         IntCC::UnsignedLessThanOrEqual if bits_32 => BinaryOp::I32LeU,
         IntCC::UnsignedLessThanOrEqual => BinaryOp::I64LeU,
-        IntCC::NotEqual
-        | IntCC::SignedLessThan
-        | IntCC::SignedGreaterThanOrEqual
-        | IntCC::SignedGreaterThan
-        | IntCC::SignedLessThanOrEqual
-        | IntCC::UnsignedLessThan
-        | IntCC::UnsignedGreaterThanOrEqual
-        | IntCC::UnsignedGreaterThan
-        | IntCC::Overflow
-        | IntCC::NotOverflow => todo!(),
+        IntCC::Overflow | IntCC::NotOverflow => {
+            unreachable!("the overflow conditions are computed by `build_icmp`")
+        }
+    }
+}
+
+/// Compares the two values (of type `ty`, and extended as described in the
+/// module documentation) on top of the stack, leaving an `i32` which is one if
+/// `cond` holds.
+///
+/// As on other targets, `overflow` holds if subtracting the second operand
+/// from the first overflows (as a signed integer).
+pub(crate) fn build_icmp(
+    cond: IntCC,
+    ty: ir::Type,
+    locals: &mut ModuleLocals,
+    builder: &mut InstrSeqBuilder,
+) -> TranslationResult<()> {
+    if !is_i32(ty) && ty != I64 {
+        return Err(ErrorKind::UnsupportedType(ty).into());
+    }
+    let bits_32 = is_i32(ty);
+    match cond {
+        IntCC::Overflow | IntCC::NotOverflow if is_narrow(ty) => {
+            // the difference of the (sign-extended) operands always fits in
+            // an `i32`, but it may not fit in `ty`
+            let difference = locals.add(ValType::I32);
+            builder.binop(BinaryOp::I32Sub).local_tee(difference);
+            extend(builder, ty, true);
+            builder.local_get(difference).binop(BinaryOp::I32Ne);
+        }
+        IntCC::Overflow | IntCC::NotOverflow => {
+            // the subtraction overflows if the operands have different signs,
+            // and the sign of the result differs from that of the first
+            let (val_ty, xor, and, sub, lt_s) = if bits_32 {
+                (
+                    ValType::I32,
+                    BinaryOp::I32Xor,
+                    BinaryOp::I32And,
+                    BinaryOp::I32Sub,
+                    BinaryOp::I32LtS,
+                )
+            } else {
+                (
+                    ValType::I64,
+                    BinaryOp::I64Xor,
+                    BinaryOp::I64And,
+                    BinaryOp::I64Sub,
+                    BinaryOp::I64LtS,
+                )
+            };
+            let (x, y) = (locals.add(val_ty), locals.add(val_ty));
+            builder
+                .local_set(y)
+                .local_tee(x)
+                .local_get(y)
+                .binop(xor)
+                .local_get(x)
+                .local_get(x)
+                .local_get(y)
+                .binop(sub)
+                .binop(xor)
+                .binop(and);
+            if bits_32 {
+                builder.i32_const(0);
+            } else {
+                builder.i64_const(0);
+            }
+            builder.binop(lt_s);
+        }
+        cond => {
+            builder.binop(wasm_of_cond(cond, bits_32));
+        }
+    }
+    if cond == IntCC::NotOverflow {
+        builder.unop(UnaryOp::I32Eqz);
     }
+    Ok(())
 }
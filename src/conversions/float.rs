@@ -0,0 +1,314 @@
+//! Floating point operations, and conversions between floats and integers.
+//!
+//! Most of these map directly onto a WebAssembly instruction (Cranelift and
+//! WebAssembly agree on the treatment of NaNs and signed zeros by `fmin`,
+//! `fmax` and `nearest`, and on when a conversion to an integer traps). The
+//! exceptions are the comparisons which WebAssembly does not provide, which
+//! are built from several WebAssembly comparisons, and the saturating
+//! conversions on targets without the non-trapping float-to-int instructions.
+
+use cranelift_codegen::ir::{
+    self,
+    condcodes::{CondCode, FloatCC},
+    types::{F32, F64, I32, I64},
+};
+use walrus::{
+    ir::{BinaryOp, UnaryOp},
+    InstrSeqBuilder, ModuleLocals, ValType,
+};
+
+use crate::error::{ErrorKind, TranslationResult};
+
+use super::narrow::is_i32;
+
+/// Whether `opcode` is a (binary) arithmetic operation on floats.
+pub(crate) fn is_float_binop(opcode: ir::Opcode) -> bool {
+    matches!(
+        opcode,
+        ir::Opcode::Fadd
+            | ir::Opcode::Fsub
+            | ir::Opcode::Fmul
+            | ir::Opcode::Fdiv
+            | ir::Opcode::Fcopysign
+            | ir::Opcode::Fmin
+            | ir::Opcode::Fmax
+    )
+}
+
+/// Whether `opcode` is a unary operation on floats (which does not convert its
+/// operand into another type).
+pub(crate) fn is_float_unop(opcode: ir::Opcode) -> bool {
+    matches!(
+        opcode,
+        ir::Opcode::Sqrt
+            | ir::Opcode::Fabs
+            | ir::Opcode::Fneg
+            | ir::Opcode::Ceil
+            | ir::Opcode::Floor
+            | ir::Opcode::Trunc
+            | ir::Opcode::Nearest
+    )
+}
+
+/// The WebAssembly instruction which computes `opcode` on floats of type `ty`.
+pub(crate) fn float_binop(opcode: ir::Opcode, ty: ir::Type) -> TranslationResult<BinaryOp> {
+    match opcode {
+        ir::Opcode::Fadd => by_type(ty, BinaryOp::F32Add, BinaryOp::F64Add),
+        ir::Opcode::Fsub => by_type(ty, BinaryOp::F32Sub, BinaryOp::F64Sub),
+        ir::Opcode::Fmul => by_type(ty, BinaryOp::F32Mul, BinaryOp::F64Mul),
+        ir::Opcode::Fdiv => by_type(ty, BinaryOp::F32Div, BinaryOp::F64Div),
+        ir::Opcode::Fcopysign => by_type(ty, BinaryOp::F32Copysign, BinaryOp::F64Copysign),
+        ir::Opcode::Fmin => by_type(ty, BinaryOp::F32Min, BinaryOp::F64Min),
+        ir::Opcode::Fmax => by_type(ty, BinaryOp::F32Max, BinaryOp::F64Max),
+        _ => Err(ErrorKind::UnsupportedOpcode(opcode).into()),
+    }
+}
+
+/// The WebAssembly instruction which computes `opcode` on a float of type `ty`.
+pub(crate) fn float_unop(opcode: ir::Opcode, ty: ir::Type) -> TranslationResult<UnaryOp> {
+    match opcode {
+        ir::Opcode::Sqrt => by_type(ty, UnaryOp::F32Sqrt, UnaryOp::F64Sqrt),
+        ir::Opcode::Fabs => by_type(ty, UnaryOp::F32Abs, UnaryOp::F64Abs),
+        ir::Opcode::Fneg => by_type(ty, UnaryOp::F32Neg, UnaryOp::F64Neg),
+        ir::Opcode::Ceil => by_type(ty, UnaryOp::F32Ceil, UnaryOp::F64Ceil),
+        ir::Opcode::Floor => by_type(ty, UnaryOp::F32Floor, UnaryOp::F64Floor),
+        ir::Opcode::Trunc => by_type(ty, UnaryOp::F32Trunc, UnaryOp::F64Trunc),
+        ir::Opcode::Nearest => by_type(ty, UnaryOp::F32Nearest, UnaryOp::F64Nearest),
+        _ => Err(ErrorKind::UnsupportedOpcode(opcode).into()),
+    }
+}
+
+/// Compares the two floats (of type `ty`) on top of the stack, leaving an
+/// `i32` which is one if `cond` holds.
+pub(crate) fn build_fcmp(
+    cond: FloatCC,
+    ty: ir::Type,
+    locals: &mut ModuleLocals,
+    builder: &mut InstrSeqBuilder,
+) -> TranslationResult<()> {
+    let eq = by_type(ty, BinaryOp::F32Eq, BinaryOp::F64Eq)?;
+    let ne = by_type(ty, BinaryOp::F32Ne, BinaryOp::F64Ne)?;
+    let lt = by_type(ty, BinaryOp::F32Lt, BinaryOp::F64Lt)?;
+    let le = by_type(ty, BinaryOp::F32Le, BinaryOp::F64Le)?;
+    let gt = by_type(ty, BinaryOp::F32Gt, BinaryOp::F64Gt)?;
+    let ge = by_type(ty, BinaryOp::F32Ge, BinaryOp::F64Ge)?;
+
+    match cond {
+        // the WebAssembly comparisons are false if either operand is NaN
+        // (other than `ne`, which is true)
+        FloatCC::Equal => builder.binop(eq),
+        FloatCC::NotEqual => builder.binop(ne),
+        FloatCC::LessThan => builder.binop(lt),
+        FloatCC::LessThanOrEqual => builder.binop(le),
+        FloatCC::GreaterThan => builder.binop(gt),
+        FloatCC::GreaterThanOrEqual => builder.binop(ge),
+        // so these are the negations of the ordered comparisons
+        FloatCC::UnorderedOrLessThan
+        | FloatCC::UnorderedOrLessThanOrEqual
+        | FloatCC::UnorderedOrGreaterThan
+        | FloatCC::UnorderedOrGreaterThanOrEqual => {
+            build_fcmp(cond.inverse(), ty, locals, builder)?;
+            builder.unop(UnaryOp::I32Eqz)
+        }
+        // the remaining conditions need each operand more than once
+        FloatCC::Ordered
+        | FloatCC::Unordered
+        | FloatCC::OrderedNotEqual
+        | FloatCC::UnorderedOrEqual => {
+            let val_ty = by_type(ty, ValType::F32, ValType::F64)?;
+            let (a, b) = (locals.add(val_ty), locals.add(val_ty));
+            builder.local_set(b).local_set(a);
+            match cond {
+                // only NaN is not equal to itself
+                FloatCC::Ordered => builder
+                    .local_get(a)
+                    .local_get(a)
+                    .binop(eq)
+                    .local_get(b)
+                    .local_get(b)
+                    .binop(eq)
+                    .binop(BinaryOp::I32And),
+                FloatCC::Unordered => builder
+                    .local_get(a)
+                    .local_get(a)
+                    .binop(ne)
+                    .local_get(b)
+                    .local_get(b)
+                    .binop(ne)
+                    .binop(BinaryOp::I32Or),
+                FloatCC::OrderedNotEqual => builder
+                    .local_get(a)
+                    .local_get(b)
+                    .binop(lt)
+                    .local_get(a)
+                    .local_get(b)
+                    .binop(gt)
+                    .binop(BinaryOp::I32Or),
+                _ => builder
+                    .local_get(a)
+                    .local_get(b)
+                    .binop(lt)
+                    .local_get(a)
+                    .local_get(b)
+                    .binop(gt)
+                    .binop(BinaryOp::I32Or)
+                    .unop(UnaryOp::I32Eqz),
+            }
+        }
+    };
+    Ok(())
+}
+
+/// The WebAssembly instruction which converts a value of type `from` into one
+/// of type `to`, for the conversions which need only a single instruction:
+/// `fpromote`, `fdemote`, the trapping `fcvt_to_*int`, `fcvt_from_*int`
+/// (once the integer has been extended to 32 bits, if it is narrow) and
+/// `bitcast`/`raw_bitcast`.
+pub(crate) fn conversion_op(
+    opcode: ir::Opcode,
+    from: ir::Type,
+    to: ir::Type,
+) -> TranslationResult<UnaryOp> {
+    // narrow integers are converted as `i32`s
+    let from_i32 = is_i32(from);
+    Ok(match (opcode, from, to) {
+        (ir::Opcode::Fpromote, F32, F64) => UnaryOp::F64PromoteF32,
+        (ir::Opcode::Fdemote, F64, F32) => UnaryOp::F32DemoteF64,
+        // the WebAssembly conversions trap on NaN and on values which are out
+        // of range, just like Cranelift's
+        (ir::Opcode::FcvtToSint, F32, I32) => UnaryOp::I32TruncSF32,
+        (ir::Opcode::FcvtToSint, F64, I32) => UnaryOp::I32TruncSF64,
+        (ir::Opcode::FcvtToSint, F32, I64) => UnaryOp::I64TruncSF32,
+        (ir::Opcode::FcvtToSint, F64, I64) => UnaryOp::I64TruncSF64,
+        (ir::Opcode::FcvtToUint, F32, I32) => UnaryOp::I32TruncUF32,
+        (ir::Opcode::FcvtToUint, F64, I32) => UnaryOp::I32TruncUF64,
+        (ir::Opcode::FcvtToUint, F32, I64) => UnaryOp::I64TruncUF32,
+        (ir::Opcode::FcvtToUint, F64, I64) => UnaryOp::I64TruncUF64,
+        (ir::Opcode::FcvtFromSint, _, F32) if from_i32 => UnaryOp::F32ConvertSI32,
+        (ir::Opcode::FcvtFromSint, I64, F32) => UnaryOp::F32ConvertSI64,
+        (ir::Opcode::FcvtFromSint, _, F64) if from_i32 => UnaryOp::F64ConvertSI32,
+        (ir::Opcode::FcvtFromSint, I64, F64) => UnaryOp::F64ConvertSI64,
+        (ir::Opcode::FcvtFromUint, _, F32) if from_i32 => UnaryOp::F32ConvertUI32,
+        (ir::Opcode::FcvtFromUint, I64, F32) => UnaryOp::F32ConvertUI64,
+        (ir::Opcode::FcvtFromUint, _, F64) if from_i32 => UnaryOp::F64ConvertUI32,
+        (ir::Opcode::FcvtFromUint, I64, F64) => UnaryOp::F64ConvertUI64,
+        (ir::Opcode::Bitcast | ir::Opcode::RawBitcast, F32, I32) => UnaryOp::I32ReinterpretF32,
+        (ir::Opcode::Bitcast | ir::Opcode::RawBitcast, F64, I64) => UnaryOp::I64ReinterpretF64,
+        (ir::Opcode::Bitcast | ir::Opcode::RawBitcast, I32, F32) => UnaryOp::F32ReinterpretI32,
+        (ir::Opcode::Bitcast | ir::Opcode::RawBitcast, I64, F64) => UnaryOp::F64ReinterpretI64,
+        _ => {
+            return Err(ErrorKind::Unsupported(format!(
+                "cannot translate `{}` from {} to {}",
+                opcode, from, to
+            ))
+            .into())
+        }
+    })
+}
+
+/// Converts the float (of type `from`) on top of the stack into an integer of
+/// type `to`, rounding towards zero and saturating: NaN is converted to zero
+/// and values which are out of range to the closest integer.
+///
+/// If `nontrapping` is false, the non-trapping float-to-int instructions may
+/// not be used, so the float is compared against the bounds of the integer
+/// type before it is converted.
+pub(crate) fn build_fcvt_sat(
+    signed: bool,
+    from: ir::Type,
+    to: ir::Type,
+    nontrapping: bool,
+    locals: &mut ModuleLocals,
+    builder: &mut InstrSeqBuilder,
+) -> TranslationResult<()> {
+    let (sat, trunc) = match (signed, from, to) {
+        (true, F32, I32) => (UnaryOp::I32TruncSSatF32, UnaryOp::I32TruncSF32),
+        (true, F64, I32) => (UnaryOp::I32TruncSSatF64, UnaryOp::I32TruncSF64),
+        (true, F32, I64) => (UnaryOp::I64TruncSSatF32, UnaryOp::I64TruncSF32),
+        (true, F64, I64) => (UnaryOp::I64TruncSSatF64, UnaryOp::I64TruncSF64),
+        (false, F32, I32) => (UnaryOp::I32TruncUSatF32, UnaryOp::I32TruncUF32),
+        (false, F64, I32) => (UnaryOp::I32TruncUSatF64, UnaryOp::I32TruncUF64),
+        (false, F32, I64) => (UnaryOp::I64TruncUSatF32, UnaryOp::I64TruncUF32),
+        (false, F64, I64) => (UnaryOp::I64TruncUSatF64, UnaryOp::I64TruncUF64),
+        (_, F32 | F64, _) => return Err(ErrorKind::UnsupportedType(to).into()),
+        _ => return Err(ErrorKind::UnsupportedType(from).into()),
+    };
+    if nontrapping {
+        builder.unop(sat);
+        return Ok(());
+    }
+
+    // the smallest value which is too large, the largest value which is not
+    // too small, and the integers which they saturate to (all of the bounds
+    // are powers of two, so they can be represented exactly)
+    let bits = to.bits() as i32;
+    let (low, high, min, max) = if signed {
+        (
+            -(2f64.powi(bits - 1)),
+            2f64.powi(bits - 1),
+            i64::MIN >> (64 - bits),
+            i64::MAX >> (64 - bits),
+        )
+    } else {
+        (0.0, 2f64.powi(bits), 0, (u64::MAX >> (64 - bits)) as i64)
+    };
+    let result = if to == I64 {
+        ValType::I64
+    } else {
+        ValType::I32
+    };
+    let int_const = |builder: &mut InstrSeqBuilder, value: i64| {
+        if to == I64 {
+            builder.i64_const(value);
+        } else {
+            builder.i32_const(value as i32);
+        }
+    };
+    let float_const = |builder: &mut InstrSeqBuilder, value: f64| {
+        if from == F32 {
+            builder.f32_const(value as f32);
+        } else {
+            builder.f64_const(value);
+        }
+    };
+    let (ne, lt, ge) = (
+        by_type(from, BinaryOp::F32Ne, BinaryOp::F64Ne)?,
+        by_type(from, BinaryOp::F32Lt, BinaryOp::F64Lt)?,
+        by_type(from, BinaryOp::F32Ge, BinaryOp::F64Ge)?,
+    );
+
+    let x = locals.add(by_type(from, ValType::F32, ValType::F64)?);
+    builder.local_tee(x).local_get(x).binop(ne).if_else(
+        result,
+        |nan| int_const(nan, 0),
+        |not_nan| {
+            not_nan.local_get(x);
+            float_const(not_nan, low);
+            not_nan.binop(lt).if_else(
+                result,
+                |too_small| int_const(too_small, min),
+                |not_too_small| {
+                    not_too_small.local_get(x);
+                    float_const(not_too_small, high);
+                    not_too_small.binop(ge).if_else(
+                        result,
+                        |too_large| int_const(too_large, max),
+                        |in_range| {
+                            in_range.local_get(x).unop(trunc);
+                        },
+                    );
+                },
+            );
+        },
+    );
+    Ok(())
+}
+
+/// Picks the operation for `f32`s or `f64`s, depending on `ty`.
+fn by_type<T>(ty: ir::Type, f32: T, f64: T) -> TranslationResult<T> {
+    match ty {
+        F32 => Ok(f32),
+        F64 => Ok(f64),
+        _ => Err(ErrorKind::UnsupportedType(ty).into()),
+    }
+}
@@ -1,8 +1,34 @@
 use cranelift_codegen::ir::{self, InstInserterBase};
-use walrus::{ir::BinaryOp, InstrSeqBuilder};
+use cranelift_module::{DataId, FuncId, Linkage};
+use walrus::{
+    ir::{BinaryOp, MemArg, UnaryOp},
+    InstrSeqBuilder,
+};
 
 use crate::{
-    conversions::{cond::wasm_of_cond, ty::wasm_of_cranelift},
+    conversions::{
+        arith::{build_muldiv, is_muldiv},
+        atomic::{
+            build_atomic_cas, build_atomic_load, build_atomic_rmw, build_atomic_store, check_shared,
+        },
+        bits::{
+            build_bits_binop, build_bits_unop, build_shift_amount, is_bits_binop, is_bits_unop,
+            is_shift,
+        },
+        cond::{build_icmp, is_signed},
+        float::{
+            build_fcmp, build_fcvt_sat, conversion_op, float_binop, float_unop, is_float_binop,
+            is_float_unop,
+        },
+        mem::{build_load, build_store, is_big_endian, load_kind, store_kind},
+        narrow::{extend, extend_argument, extend_imm, is_i32},
+        pair::{is_pair_inst, translate_pair_inst},
+        sig::wasm_of_sig,
+        ty::wasm_of_cranelift,
+    },
+    data::PendingSymbolRef,
+    error::{ErrorKind, TranslationResult},
+    locals::ValueLocals,
     optable::Operand,
     IndividualFunctionTranslator,
 };
@@ -15,18 +41,34 @@ use super::block::CanBranchTo;
 /// representation (operations that require a multithreaded environment are not
 /// translated) and (b) do not require any control flow (so jumps and branches
 /// are handled seperately).
+///
+/// If the instruction cannot be translated, the error refers to it (or to the
+/// instruction defining one of its operands, if that cannot be translated).
 pub fn build_wasm_inst(
     inst: ir::Inst,
     t: &mut IndividualFunctionTranslator<'_>,
     builder: &mut InstrSeqBuilder,
     can_branch_to: &CanBranchTo,
-) {
+) -> TranslationResult<()> {
+    translate_inst(inst, t, builder, can_branch_to).map_err(|e| e.at(inst, t.cursor.func))
+}
+
+fn translate_inst(
+    inst: ir::Inst,
+    t: &mut IndividualFunctionTranslator<'_>,
+    builder: &mut InstrSeqBuilder,
+    can_branch_to: &CanBranchTo,
+) -> TranslationResult<()> {
     log::trace!("building instruction {:#?}", inst);
+    // `i128`s are handled separately, since each one takes up two values
+    if is_pair_inst(inst, t.cursor.func) {
+        return translate_pair_inst(inst, t, builder, can_branch_to);
+    }
+
+    let inst_opcode = t.cursor.func.dfg[inst].opcode();
+    let unsupported_opcode =
+        || -> TranslationResult<()> { Err(ErrorKind::UnsupportedOpcode(inst_opcode).into()) };
     match &t.cursor.func.dfg[inst].clone() {
-        // operations that are unsupportable on WebAssembly
-        ir::InstructionData::AtomicCas { .. } | ir::InstructionData::AtomicRmw { .. } => {
-            panic!("this operation is not supported on WebAssembly")
-        }
         ir::InstructionData::Binary { opcode, args } => {
             log::trace!(
                 "instruction is a binary operation with code {:#?} and args {:#?}",
@@ -35,202 +77,828 @@ pub fn build_wasm_inst(
             );
             for operand in args {
                 log::trace!("translating operand {:#?}", operand);
-                translate_value(*operand, t, builder, can_branch_to);
+                translate_value(*operand, t, builder, can_branch_to)?;
             }
             match opcode {
                 ir::Opcode::Iadd => {
                     log::trace!("opcode is `Iadd`");
                     let [left, _] = args;
                     let ty = t.cursor.data_flow_graph().value_type(*left);
-                    if ty == ir::types::I32 {
+                    if is_i32(ty) {
                         log::trace!("found ty to be i32");
                         builder.binop(BinaryOp::I32Add);
                     } else if ty == ir::types::I64 {
                         log::trace!("found ty to be i64");
                         builder.binop(BinaryOp::I64Add);
                     } else {
-                        // todo: it's not unreachable yet!
-                        unreachable!()
+                        return Err(ErrorKind::UnsupportedType(ty).into());
                     }
                 }
                 ir::Opcode::Isub => {
                     log::trace!("opcode is `Isub`");
                     let [left, _] = args;
                     let ty = t.cursor.data_flow_graph().value_type(*left);
-                    if ty == ir::types::I32 {
+                    if is_i32(ty) {
                         log::trace!("found ty to be i32");
                         builder.binop(BinaryOp::I32Sub);
                     } else if ty == ir::types::I64 {
                         log::trace!("found ty to be i64");
                         builder.binop(BinaryOp::I64Sub);
                     } else {
-                        // todo: it's not unreachable yet!
-                        unreachable!()
+                        return Err(ErrorKind::UnsupportedType(ty).into());
                     }
                 }
-                sth => panic!("{:#?} is not yet supported", sth),
-            }
-        }
-        ir::InstructionData::UnaryImm { opcode, imm } => {
-            if opcode == &ir::Opcode::Iconst {
-                if t.cursor.data_flow_graph().has_results(inst) {
-                    let val = t.cursor.data_flow_graph().inst_results(inst)[0];
-                    let ty = t.cursor.data_flow_graph().value_type(val);
-                    assert!(ty.is_int());
-                    if ty.bits() == 64 {
-                        builder.i64_const(imm.bits());
-                        log::trace!("finished compiling instruction");
-                        return;
-                    } else if ty.bits() == 32 {
-                        builder.i32_const(imm.bits() as i32);
-                        log::trace!("finished compiling instruction");
-                        return;
+                opcode if is_float_binop(*opcode) => {
+                    let ty = t.cursor.data_flow_graph().value_type(args[0]);
+                    builder.binop(float_binop(*opcode, ty)?);
+                }
+                opcode if is_bits_binop(*opcode) || is_muldiv(*opcode) => {
+                    let ty = t.cursor.data_flow_graph().value_type(args[0]);
+                    if is_shift(*opcode) {
+                        let amount_ty = t.cursor.data_flow_graph().value_type(args[1]);
+                        build_shift_amount(amount_ty, ty, builder);
                     }
-                } else {
-                    panic!()
+                    build_int_binop(*opcode, ty, t, builder)?;
                 }
-            } else {
-                panic!("this operation is not yet supported")
+                _ => return unsupported_opcode(),
             }
         }
-        ir::InstructionData::IntCompare { opcode, args, cond } => {
-            for arg in args {
-                translate_value(*arg, t, builder, can_branch_to);
+        ir::InstructionData::UnaryIeee32 {
+            opcode: ir::Opcode::F32const,
+            imm,
+        } => {
+            builder.f32_const(f32::from_bits(imm.bits()));
+        }
+        ir::InstructionData::UnaryIeee64 {
+            opcode: ir::Opcode::F64const,
+            imm,
+        } => {
+            builder.f64_const(f64::from_bits(imm.bits()));
+        }
+        ir::InstructionData::UnaryImm {
+            opcode: ir::Opcode::Iconst,
+            imm,
+        } => {
+            let val = t.cursor.data_flow_graph().inst_results(inst)[0];
+            let ty = t.cursor.data_flow_graph().value_type(val);
+            if ty == ir::types::I64 {
+                builder.i64_const(imm.bits());
+            } else if is_i32(ty) {
+                builder.i32_const(imm.bits() as i32);
+            } else {
+                return Err(ErrorKind::UnsupportedType(ty).into());
             }
-            let ty = t.cursor.data_flow_graph().value_type(args[0]);
-            assert!(ty.is_int());
-            if opcode == &ir::Opcode::Icmp {
-                match cond {
-                    ir::condcodes::IntCC::NotEqual => {
-                        if ty.bits() == 64 {
-                            builder.binop(BinaryOp::I64Ne);
-                        } else if ty.bits() == 32 {
-                            builder.binop(BinaryOp::I32Ne);
+        }
+        ir::InstructionData::UnaryImm { .. } => return unsupported_opcode(),
+        ir::InstructionData::Unary { opcode, arg } => {
+            let from = t.cursor.data_flow_graph().value_type(*arg);
+            let result = t.cursor.data_flow_graph().inst_results(inst)[0];
+            let to = t.cursor.data_flow_graph().value_type(result);
+            let is_int = |ty| is_i32(ty) || ty == ir::types::I64;
+            let check_ints = || -> TranslationResult<()> {
+                match [from, to].into_iter().find(|ty| !is_int(*ty)) {
+                    Some(ty) => Err(ErrorKind::UnsupportedType(ty).into()),
+                    None => Ok(()),
+                }
+            };
+            match opcode {
+                ir::Opcode::Uextend | ir::Opcode::Sextend => {
+                    check_ints()?;
+                    let signed = opcode == &ir::Opcode::Sextend;
+                    translate_value(*arg, t, builder, can_branch_to)?;
+                    extend(builder, from, signed);
+                    if to == ir::types::I64 && from != ir::types::I64 {
+                        builder.unop(if signed {
+                            UnaryOp::I64ExtendSI32
                         } else {
-                            panic!("integers must be 32 or 64 bits")
-                        }
+                            UnaryOp::I64ExtendUI32
+                        });
                     }
-                    ir::condcodes::IntCC::Equal => {
-                        if ty.bits() == 64 {
-                            builder.binop(BinaryOp::I64Eq);
-                        } else if ty.bits() == 32 {
-                            builder.binop(BinaryOp::I32Eq);
-                        } else {
-                            panic!("integers must be 32 or 64 bits")
-                        }
+                }
+                ir::Opcode::Ireduce => {
+                    check_ints()?;
+                    // the upper bits of the result are ignored, so reducing to
+                    // a narrow type does not require any instructions
+                    translate_value(*arg, t, builder, can_branch_to)?;
+                    if from == ir::types::I64 && to != ir::types::I64 {
+                        builder.unop(UnaryOp::I32WrapI64);
                     }
-                    ir::condcodes::IntCC::SignedLessThan
-                    | ir::condcodes::IntCC::SignedGreaterThanOrEqual
-                    | ir::condcodes::IntCC::SignedGreaterThan
-                    | ir::condcodes::IntCC::SignedLessThanOrEqual
-                    | ir::condcodes::IntCC::UnsignedLessThan
-                    | ir::condcodes::IntCC::UnsignedGreaterThanOrEqual
-                    | ir::condcodes::IntCC::UnsignedGreaterThan
-                    | ir::condcodes::IntCC::UnsignedLessThanOrEqual
-                    | ir::condcodes::IntCC::Overflow
-                    | ir::condcodes::IntCC::NotOverflow => todo!(),
                 }
-            } else {
-                panic!("operation not yet supported");
+                opcode if is_bits_unop(*opcode) => {
+                    check_ints()?;
+                    translate_value(*arg, t, builder, can_branch_to)?;
+                    build_bits_unop(*opcode, from, t.module_locals, builder)?;
+                }
+                opcode if is_float_unop(*opcode) => {
+                    let op = float_unop(*opcode, from)?;
+                    translate_value(*arg, t, builder, can_branch_to)?;
+                    builder.unop(op);
+                }
+                ir::Opcode::FcvtFromSint | ir::Opcode::FcvtFromUint => {
+                    let op = conversion_op(*opcode, from, to)?;
+                    translate_value(*arg, t, builder, can_branch_to)?;
+                    extend(builder, from, opcode == &ir::Opcode::FcvtFromSint);
+                    builder.unop(op);
+                }
+                ir::Opcode::Fpromote
+                | ir::Opcode::Fdemote
+                | ir::Opcode::FcvtToSint
+                | ir::Opcode::FcvtToUint
+                | ir::Opcode::Bitcast
+                | ir::Opcode::RawBitcast => {
+                    let op = conversion_op(*opcode, from, to)?;
+                    translate_value(*arg, t, builder, can_branch_to)?;
+                    builder.unop(op);
+                }
+                ir::Opcode::FcvtToSintSat | ir::Opcode::FcvtToUintSat => {
+                    translate_value(*arg, t, builder, can_branch_to)?;
+                    build_fcvt_sat(
+                        opcode == &ir::Opcode::FcvtToSintSat,
+                        from,
+                        to,
+                        t.nontrapping_fptoint,
+                        t.module_locals,
+                        builder,
+                    )?;
+                }
+                _ => return unsupported_opcode(),
+            }
+        }
+        ir::InstructionData::IntCompare {
+            opcode: ir::Opcode::Icmp,
+            args,
+            cond,
+        } => {
+            let ty = t.cursor.data_flow_graph().value_type(args[0]);
+            for arg in args {
+                translate_value(*arg, t, builder, can_branch_to)?;
+                extend(builder, ty, is_signed(*cond));
             }
+            build_icmp(*cond, ty, t.module_locals, builder)?;
+        }
+        ir::InstructionData::FloatCompare {
+            opcode: ir::Opcode::Fcmp,
+            args,
+            cond,
+        } => {
+            let ty = t.cursor.data_flow_graph().value_type(args[0]);
+            for arg in args {
+                translate_value(*arg, t, builder, can_branch_to)?;
+            }
+            build_fcmp(*cond, ty, t.module_locals, builder)?;
         }
         ir::InstructionData::IntCompareImm {
-            opcode,
+            opcode: ir::Opcode::IcmpImm,
             arg,
             cond,
             imm,
         } => {
             let ty = t.cursor.data_flow_graph().value_type(*arg);
-            assert!(ty.is_int());
-            if opcode == &ir::Opcode::IcmpImm {
-                translate_value(*arg, t, builder, can_branch_to);
-                if ty.bits() == 64 {
-                    builder.i64_const(imm.bits());
-                } else if ty.bits() == 32 {
-                    builder.i32_const(imm.bits() as i32);
-                } else {
-                    unimplemented!()
-                }
-                builder.binop(wasm_of_cond(*cond, ty.bits() == 32));
+            let signed = is_signed(*cond);
+            translate_value(*arg, t, builder, can_branch_to)?;
+            extend(builder, ty, signed);
+            if ty == ir::types::I64 {
+                builder.i64_const(imm.bits());
+            } else if is_i32(ty) {
+                builder.i32_const(extend_imm(imm.bits(), ty, signed) as i32);
             } else {
-                panic!("{:#?} not yet supported", opcode);
+                return Err(ErrorKind::UnsupportedType(ty).into());
             }
+            build_icmp(*cond, ty, t.module_locals, builder)?;
         }
-        ir::InstructionData::Jump { .. }
-        | ir::InstructionData::Branch { .. }
-        | ir::InstructionData::MultiAry { .. } => {
-            unreachable!("this operation should already have been handled")
+        ir::InstructionData::Call {
+            opcode: _,
+            args,
+            func_ref,
+        } => {
+            let args = args.as_slice(&t.cursor.func.dfg.value_lists).to_vec();
+            let sig_ref = t.cursor.func.dfg.ext_funcs[*func_ref].signature;
+            let params = t.cursor.func.dfg.signatures[sig_ref].params.clone();
+            for (arg, param) in args.into_iter().zip(params) {
+                translate_value(arg, t, builder, can_branch_to)?;
+                extend_argument(builder, param.value_type, param.extension);
+            }
+
+            let callee = resolve_function(*func_ref, t)?;
+            builder.call(callee);
+        }
+        ir::InstructionData::CallIndirect {
+            opcode: _,
+            args,
+            sig_ref,
+        } => {
+            let args = args.as_slice(&t.cursor.func.dfg.value_lists).to_vec();
+            // the callee is the first argument, but has to be pushed last
+            let (callee, args) = args.split_first().unwrap();
+            let params = t.cursor.func.dfg.signatures[*sig_ref].params.clone();
+            for (arg, param) in args.iter().zip(params) {
+                translate_value(*arg, t, builder, can_branch_to)?;
+                extend_argument(builder, param.value_type, param.extension);
+            }
+
+            translate_value(*callee, t, builder, can_branch_to)?;
+            if t.cursor.data_flow_graph().value_type(*callee) == ir::types::I64 {
+                builder.unop(UnaryOp::I32WrapI64);
+            }
+
+            let (params, returns) = wasm_of_sig(t.cursor.func.dfg.signatures[*sig_ref].clone())?;
+            let ty = t.types.add(&params, &returns);
+            builder.call_indirect(ty, t.table.id);
+        }
+        ir::InstructionData::FuncAddr {
+            opcode: _,
+            func_ref,
+        } => {
+            // function pointers are indices into the function table
+            let func = resolve_function(*func_ref, t)?;
+            let index = t.table.index_of(func);
+
+            let result = t.cursor.data_flow_graph().inst_results(inst)[0];
+            if t.cursor.data_flow_graph().value_type(result) == ir::types::I64 {
+                builder.i64_const(index as i64);
+            } else {
+                builder.i32_const(index as i32);
+            }
+        }
+        ir::InstructionData::StackLoad {
+            opcode,
+            stack_slot,
+            offset,
+        } => {
+            // every function which uses stack slots has a frame
+            let frame = t.frame.unwrap();
+            let offset = frame.offset_of(*stack_slot) as i64 + i64::from(*offset);
+            builder.local_get(frame.frame_pointer);
+
+            match opcode {
+                ir::Opcode::StackAddr => {
+                    if t.pointer_type == ir::types::I64 {
+                        builder.i64_const(offset).binop(BinaryOp::I64Add);
+                    } else {
+                        builder.i32_const(offset as i32).binop(BinaryOp::I32Add);
+                    }
+                }
+                ir::Opcode::StackLoad => {
+                    let result = t.cursor.data_flow_graph().inst_results(inst)[0];
+                    let ty = t.cursor.data_flow_graph().value_type(result);
+                    builder.load(
+                        t.memory_id,
+                        load_kind(ty)?,
+                        MemArg {
+                            align: ty.bytes(),
+                            offset: offset as u32,
+                        },
+                    );
+                }
+                _ => return unsupported_opcode(),
+            }
+        }
+        ir::InstructionData::StackStore {
+            opcode: _,
+            arg,
+            stack_slot,
+            offset,
+        } => {
+            let frame = t.frame.unwrap();
+            let offset = frame.offset_of(*stack_slot) as i64 + i64::from(*offset);
+            builder.local_get(frame.frame_pointer);
+            translate_value(*arg, t, builder, can_branch_to)?;
+
+            let ty = t.cursor.data_flow_graph().value_type(*arg);
+            builder.store(
+                t.memory_id,
+                store_kind(ty)?,
+                MemArg {
+                    align: ty.bytes(),
+                    offset: offset as u32,
+                },
+            );
+        }
+        ir::InstructionData::Load {
+            opcode,
+            arg,
+            flags,
+            offset,
+        } => {
+            let offset = translate_address(&[*arg], *offset, t, builder, can_branch_to)?;
+            let result = t.cursor.data_flow_graph().inst_results(inst)[0];
+            let ty = t.cursor.data_flow_graph().value_type(result);
+            build_load(
+                *opcode,
+                ty,
+                *flags,
+                offset,
+                t.memory_id,
+                t.module_locals,
+                builder,
+            )?;
+        }
+        ir::InstructionData::LoadComplex {
+            opcode,
+            args,
+            flags,
+            offset,
+        } => {
+            let addresses = args.as_slice(&t.cursor.func.dfg.value_lists).to_vec();
+            let offset = translate_address(&addresses, *offset, t, builder, can_branch_to)?;
+            let result = t.cursor.data_flow_graph().inst_results(inst)[0];
+            let ty = t.cursor.data_flow_graph().value_type(result);
+            build_load(
+                *opcode,
+                ty,
+                *flags,
+                offset,
+                t.memory_id,
+                t.module_locals,
+                builder,
+            )?;
+        }
+        ir::InstructionData::Store {
+            opcode,
+            args: [arg, address],
+            flags,
+            offset,
+        } => {
+            let offset = translate_address(&[*address], *offset, t, builder, can_branch_to)?;
+            translate_value(*arg, t, builder, can_branch_to)?;
+            let ty = t.cursor.data_flow_graph().value_type(*arg);
+            build_store(
+                *opcode,
+                ty,
+                *flags,
+                offset,
+                t.memory_id,
+                t.module_locals,
+                builder,
+            )?;
+        }
+        ir::InstructionData::StoreComplex {
+            opcode,
+            args,
+            flags,
+            offset,
+        } => {
+            let args = args.as_slice(&t.cursor.func.dfg.value_lists).to_vec();
+            let offset = translate_address(&args[1..], *offset, t, builder, can_branch_to)?;
+            translate_value(args[0], t, builder, can_branch_to)?;
+            let ty = t.cursor.data_flow_graph().value_type(args[0]);
+            build_store(
+                *opcode,
+                ty,
+                *flags,
+                offset,
+                t.memory_id,
+                t.module_locals,
+                builder,
+            )?;
+        }
+        ir::InstructionData::AtomicRmw {
+            opcode: _,
+            args: [address, arg],
+            flags,
+            op,
+        } => {
+            check_atomic(*flags, t)?;
+            translate_address(&[*address], 0.into(), t, builder, can_branch_to)?;
+            translate_value(*arg, t, builder, can_branch_to)?;
+            let ty = t.cursor.data_flow_graph().value_type(*arg);
+            build_atomic_rmw(
+                *op,
+                ty,
+                t.pointer_type,
+                t.memory_id,
+                t.module_locals,
+                builder,
+            )?;
+        }
+        ir::InstructionData::AtomicCas {
+            opcode: _,
+            args: [address, expected, replacement],
+            flags,
+        } => {
+            check_atomic(*flags, t)?;
+            translate_address(&[*address], 0.into(), t, builder, can_branch_to)?;
+            let ty = t.cursor.data_flow_graph().value_type(*expected);
+            // the value in memory is zero-extended before it is compared
+            translate_value(*expected, t, builder, can_branch_to)?;
+            extend(builder, ty, false);
+            translate_value(*replacement, t, builder, can_branch_to)?;
+            build_atomic_cas(ty, t.memory_id, builder)?;
+        }
+        ir::InstructionData::LoadNoOffset {
+            opcode: ir::Opcode::AtomicLoad,
+            arg,
+            flags,
+        } => {
+            check_atomic(*flags, t)?;
+            translate_address(&[*arg], 0.into(), t, builder, can_branch_to)?;
+            let result = t.cursor.data_flow_graph().inst_results(inst)[0];
+            let ty = t.cursor.data_flow_graph().value_type(result);
+            build_atomic_load(ty, t.memory_id, builder)?;
+        }
+        ir::InstructionData::StoreNoOffset {
+            opcode: ir::Opcode::AtomicStore,
+            args: [arg, address],
+            flags,
+        } => {
+            check_atomic(*flags, t)?;
+            translate_address(&[*address], 0.into(), t, builder, can_branch_to)?;
+            translate_value(*arg, t, builder, can_branch_to)?;
+            let ty = t.cursor.data_flow_graph().value_type(*arg);
+            build_atomic_store(ty, t.memory_id, builder)?;
+        }
+        ir::InstructionData::NullAry {
+            opcode: ir::Opcode::Fence,
+        } => {
+            check_shared(t.shared_memory)?;
+            builder.atomic_fence();
+        }
+        ir::InstructionData::UnaryGlobalValue {
+            opcode: _,
+            global_value,
+        } => {
+            // `symbol_value` and `global_value` are computed in the same way
+            let result = t.cursor.data_flow_graph().inst_results(inst)[0];
+            let ty = t.cursor.data_flow_graph().value_type(result);
+            translate_global_value(*global_value, ty, t, builder)?;
         }
         ir::InstructionData::BinaryImm64 { opcode, arg, imm } => {
             if opcode == &ir::Opcode::IaddImm {
                 let ty = t.cursor.data_flow_graph().value_type(*arg);
-                assert!(ty.is_int());
-                translate_value(*arg, t, builder, can_branch_to);
-                if ty.bits() == 64 {
+                translate_value(*arg, t, builder, can_branch_to)?;
+                if ty == ir::types::I64 {
                     builder.i64_const(imm.bits());
                     builder.binop(BinaryOp::I64Add);
-                } else if ty.bits() == 32 {
+                } else if is_i32(ty) {
                     builder.i32_const(imm.bits() as i32);
                     builder.binop(BinaryOp::I32Add);
                 } else {
-                    unimplemented!();
+                    return Err(ErrorKind::UnsupportedType(ty).into());
+                }
+            } else if let Some(opcode) = without_imm(*opcode) {
+                // the immediate is used in the same way as a (non-immediate)
+                // operand of the same type
+                let ty = t.cursor.data_flow_graph().value_type(*arg);
+                translate_value(*arg, t, builder, can_branch_to)?;
+                if ty == ir::types::I64 {
+                    builder.i64_const(imm.bits());
+                } else if is_i32(ty) {
+                    builder.i32_const(imm.bits() as i32);
+                } else {
+                    return Err(ErrorKind::UnsupportedType(ty).into());
                 }
+                build_int_binop(opcode, ty, t, builder)?;
+            } else {
+                return unsupported_opcode();
             }
         }
-        // operations that have not yet been implemented
-        sth => {
-            panic!("support for {:#?} has not yet been implemented", sth)
-        }
+        // operations that have not yet been implemented (as well as control
+        // flow, which is handled by `build_from_pos`)
+        _ => return unsupported_opcode(),
     }
     log::trace!("finished compiling instruction");
+    Ok(())
+}
+/// Applies the (binary) integer operation `opcode` to the two values on top of
+/// the stack, the first of which has type `ty`.
+fn build_int_binop(
+    opcode: ir::Opcode,
+    ty: ir::Type,
+    t: &mut IndividualFunctionTranslator<'_>,
+    builder: &mut InstrSeqBuilder,
+) -> TranslationResult<()> {
+    if is_muldiv(opcode) {
+        build_muldiv(opcode, ty, t.module_locals, builder)
+    } else {
+        build_bits_binop(opcode, ty, t.module_locals, builder)
+    }
+}
+
+/// The operation which the `_imm` form `opcode` applies to its operand and
+/// immediate.
+pub(crate) fn without_imm(opcode: ir::Opcode) -> Option<ir::Opcode> {
+    Some(match opcode {
+        ir::Opcode::ImulImm => ir::Opcode::Imul,
+        ir::Opcode::UdivImm => ir::Opcode::Udiv,
+        ir::Opcode::SdivImm => ir::Opcode::Sdiv,
+        ir::Opcode::UremImm => ir::Opcode::Urem,
+        ir::Opcode::SremImm => ir::Opcode::Srem,
+        ir::Opcode::BandImm => ir::Opcode::Band,
+        ir::Opcode::BorImm => ir::Opcode::Bor,
+        ir::Opcode::BxorImm => ir::Opcode::Bxor,
+        ir::Opcode::IshlImm => ir::Opcode::Ishl,
+        ir::Opcode::UshrImm => ir::Opcode::Ushr,
+        ir::Opcode::SshrImm => ir::Opcode::Sshr,
+        ir::Opcode::RotlImm => ir::Opcode::Rotl,
+        ir::Opcode::RotrImm => ir::Opcode::Rotr,
+        _ => return None,
+    })
+}
+
+/// Whether computing `global_value` loads from memory which may be written to
+/// (so that it has to be computed where it appears, rather than where it is
+/// used).
+pub(crate) fn loads_mutable_memory(global_value: ir::GlobalValue, func: &ir::Function) -> bool {
+    match func.global_values[global_value] {
+        ir::GlobalValueData::Load {
+            readonly: false, ..
+        } => true,
+        ir::GlobalValueData::Load { base, .. } | ir::GlobalValueData::IAddImm { base, .. } => {
+            loads_mutable_memory(base, func)
+        }
+        _ => false,
+    }
+}
+
+/// Computes the value of a Cranelift global value (which has type `ty`).
+fn translate_global_value(
+    global_value: ir::GlobalValue,
+    ty: ir::Type,
+    t: &mut IndividualFunctionTranslator<'_>,
+    builder: &mut InstrSeqBuilder,
+) -> TranslationResult<()> {
+    let wide = ty == ir::types::I64;
+    match t.cursor.func.global_values[global_value].clone() {
+        ir::GlobalValueData::Symbol {
+            name,
+            offset,
+            colocated: _,
+            tls: false,
+        } => match name {
+            ir::ExternalName::User { namespace: 0, .. } => {
+                // function pointers are indices into the function table
+                let func = resolve_function_name(&name, t)?;
+                let index = t.table.index_of(func) as i64 + offset.bits();
+                if wide {
+                    builder.i64_const(index);
+                } else {
+                    builder.i32_const(index as i32);
+                }
+            }
+            ir::ExternalName::User { namespace: 1, .. } => {
+                let data = DataId::from_name(&name);
+                let decl = t.decls.get_data_decl(data);
+                if decl.linkage == Linkage::Import {
+                    return Err(ErrorKind::Unsupported(format!(
+                        "cannot take the address of the imported data `{}`",
+                        decl.name
+                    ))
+                    .into());
+                }
+
+                let address = match t.data_layout.address_of(data) {
+                    Some(address) => address as i64 + offset.bits(),
+                    None => {
+                        // the data object has not been defined yet, so we emit
+                        // a placeholder which is replaced once it has been
+                        t.pending_symbol_refs.push(PendingSymbolRef {
+                            func: t.func_id,
+                            seq: builder.id(),
+                            position: builder.instrs().len(),
+                            ty: wasm_of_cranelift(ty)?,
+                            target: data,
+                            addend: offset.bits(),
+                        });
+                        0
+                    }
+                };
+                if wide {
+                    builder.i64_const(address);
+                } else {
+                    builder.i32_const(address as i32);
+                }
+            }
+            name => {
+                return Err(ErrorKind::Unsupported(format!(
+                    "cannot refer to {} (only module symbols are supported)",
+                    name
+                ))
+                .into())
+            }
+        },
+        ir::GlobalValueData::IAddImm {
+            base,
+            offset,
+            global_type,
+        } => {
+            translate_global_value(base, global_type, t, builder)?;
+            if wide {
+                builder.i64_const(offset.bits());
+                builder.binop(BinaryOp::I64Add);
+            } else {
+                builder.i32_const(offset.bits() as i32);
+                builder.binop(BinaryOp::I32Add);
+            }
+        }
+        ir::GlobalValueData::Load {
+            base,
+            offset,
+            global_type,
+            readonly: _,
+        } => {
+            let pointer_type = t.pointer_type;
+            translate_global_value(base, pointer_type, t, builder)?;
+
+            // the offset in a `memarg` cannot be negative, so these have to be
+            // added to the address first
+            let offset: i32 = offset.into();
+            let offset = if offset < 0 {
+                if pointer_type == ir::types::I64 {
+                    builder.i64_const(offset as i64).binop(BinaryOp::I64Add);
+                } else {
+                    builder.i32_const(offset).binop(BinaryOp::I32Add);
+                }
+                0
+            } else {
+                offset as u32
+            };
+
+            builder.load(
+                t.memory_id,
+                load_kind(global_type)?,
+                MemArg {
+                    align: global_type.bytes(),
+                    offset,
+                },
+            );
+        }
+        ir::GlobalValueData::Symbol { tls: true, .. } => {
+            return Err(ErrorKind::Unsupported(
+                "thread local symbols are not supported".to_string(),
+            )
+            .into())
+        }
+        ir::GlobalValueData::VMContext => {
+            return Err(
+                ErrorKind::Unsupported("there is no `vmctx` on WebAssembly".to_string()).into(),
+            )
+        }
+    }
+    Ok(())
+}
+
+/// Checks that an atomic instruction with the given `flags` can be translated.
+fn check_atomic(
+    flags: ir::MemFlags,
+    t: &IndividualFunctionTranslator<'_>,
+) -> TranslationResult<()> {
+    check_shared(t.shared_memory)?;
+    if is_big_endian(flags) {
+        return Err(ErrorKind::Unsupported(
+            "big-endian atomic instructions are not supported".to_string(),
+        )
+        .into());
+    }
+    Ok(())
 }
+
+/// Pushes the address accessed by a load or store: the sum of `addresses` (of
+/// the pointer type) and `offset`.
+///
+/// Returns the offset which is left to the `memarg`, which cannot be negative
+/// (so negative offsets are added to the address instead).
+pub(crate) fn translate_address(
+    addresses: &[ir::Value],
+    offset: ir::immediates::Offset32,
+    t: &mut IndividualFunctionTranslator<'_>,
+    builder: &mut InstrSeqBuilder,
+    can_branch_to: &CanBranchTo,
+) -> TranslationResult<u32> {
+    let pointer_type = t.pointer_type;
+    let add = if pointer_type == ir::types::I64 {
+        BinaryOp::I64Add
+    } else {
+        BinaryOp::I32Add
+    };
+    for (n, address) in addresses.iter().enumerate() {
+        let ty = t.cursor.data_flow_graph().value_type(*address);
+        if ty != pointer_type {
+            return Err(ErrorKind::UnsupportedType(ty).into());
+        }
+        translate_value(*address, t, builder, can_branch_to)?;
+        if n > 0 {
+            builder.binop(add);
+        }
+    }
+
+    let offset: i32 = offset.into();
+    if offset >= 0 {
+        return Ok(offset as u32);
+    }
+    if pointer_type == ir::types::I64 {
+        builder.i64_const(offset as i64);
+    } else {
+        builder.i32_const(offset);
+    }
+    builder.binop(add);
+    Ok(0)
+}
+
+/// Finds the Walrus function which corresponds to a Cranelift function
+/// reference.
+fn resolve_function(
+    func_ref: ir::FuncRef,
+    t: &IndividualFunctionTranslator<'_>,
+) -> TranslationResult<walrus::FunctionId> {
+    let name = &t.cursor.func.dfg.ext_funcs[func_ref].name;
+    resolve_function_name(name, t)
+}
+
+fn resolve_function_name(
+    name: &ir::ExternalName,
+    t: &IndividualFunctionTranslator<'_>,
+) -> TranslationResult<walrus::FunctionId> {
+    match name {
+        ir::ExternalName::User { namespace: 0, .. } => t
+            .functions
+            .get(&FuncId::from_name(name))
+            .copied()
+            .ok_or_else(|| ErrorKind::Unsupported(format!("{} was never declared", name)).into()),
+        _ => Err(ErrorKind::Unsupported(format!(
+            "cannot reference {} (only module functions are supported)",
+            name
+        ))
+        .into()),
+    }
+}
+
+/// Stores the results of an instruction which has already been emitted (and
+/// whose results are therefore on top of the stack) in locals, so that later
+/// uses can retrieve them without evaluating the instruction again.
+///
+/// This is used for instructions with side effects (e.g. calls), which must be
+/// executed exactly once and at the position where they appear in the IR.
+pub(crate) fn bind_results(
+    inst: ir::Inst,
+    t: &mut IndividualFunctionTranslator<'_>,
+    builder: &mut InstrSeqBuilder,
+) -> TranslationResult<()> {
+    let results = t.cursor.data_flow_graph().inst_results(inst).to_vec();
+    // the last result is on the top of the stack, so we pop them in reverse
+    for result in results.into_iter().rev() {
+        let ty = t.cursor.data_flow_graph().value_type(result);
+        let locals =
+            ValueLocals::new(t.module_locals, ty).map_err(|e| e.at(inst, t.cursor.func))?;
+        log::trace!("{:#?} has been bound to {:#?}", result, locals);
+        locals.set(builder);
+        t.locals.insert(result, locals);
+    }
+    Ok(())
+}
+
 pub(crate) fn translate_value(
     operand: ir::Value,
     t: &mut IndividualFunctionTranslator<'_>,
     builder: &mut InstrSeqBuilder,
     can_branch_to: &CanBranchTo,
-) {
+) -> TranslationResult<()> {
+    // values which have already been bound to a local (e.g. the results of a
+    // call) must never be recomputed
+    if let Some(locals) = t.locals.get(&operand) {
+        locals.get(builder);
+        return Ok(());
+    }
+
     match t.cursor.data_flow_graph().value_def(operand) {
-        ir::ValueDef::Result(_, _) => match Operand::from_table(operand, &t.operand_table) {
+        ir::ValueDef::Result(_, _) => match Operand::from_table(operand, t.operand_table) {
             Operand::SingleUse(val) => {
                 let def = t.cursor.data_flow_graph().value_def(val).unwrap_inst();
-                build_wasm_inst(def, t, builder, can_branch_to);
+                build_wasm_inst(def, t, builder, can_branch_to)?;
             }
             Operand::NormalUse(val) => {
-                if let Some(local) = t.locals.get(&val) {
+                if let Some(locals) = t.locals.get(&val) {
                     log::trace!("{:#?} is `NormalUse` and has previously been used", val);
-                    log::trace!("retrieving {:#?} from local {:#?}", val, local);
-                    builder.local_get(*local);
+                    log::trace!("retrieving {:#?} from {:#?}", val, locals);
+                    locals.get(builder);
                 } else {
                     log::trace!("{:#?} is `NormalUse` and has not previously been used", val);
                     let def = t.cursor.data_flow_graph().value_def(val).unwrap_inst();
-                    build_wasm_inst(def, t, builder, can_branch_to);
+                    build_wasm_inst(def, t, builder, can_branch_to)?;
 
-                    let arg = t.module_locals.add({
-                        let ty = t.cursor.data_flow_graph().value_type(val);
-                        wasm_of_cranelift(ty)
-                    });
+                    let ty = t.cursor.data_flow_graph().value_type(val);
+                    let locals = ValueLocals::new(t.module_locals, ty)
+                        .map_err(|e| e.at(def, t.cursor.func))?;
 
-                    t.locals.insert(val, arg);
-                    log::trace!("{:#?} has been assigned to local {:#?}", val, arg);
-                    builder.local_set(arg);
-                    builder.local_get(arg);
+                    log::trace!("{:#?} has been assigned to {:#?}", val, locals);
+                    locals.set(builder);
+                    locals.get(builder);
+                    t.locals.insert(val, locals);
                 }
             }
             Operand::Rematerialise(val) => {
                 let def = t.cursor.data_flow_graph().value_def(val).unwrap_inst();
-                build_wasm_inst(def, t, builder, can_branch_to);
+                build_wasm_inst(def, t, builder, can_branch_to)?;
             }
         },
         ir::ValueDef::Param(block, _) => {
-            let local = t
+            let locals = t
                 .operand_table
                 .block_params
                 .get(&block)
-                .map(|res| res.get(&operand))
-                .flatten()
+                .and_then(|res| res.get(&operand))
                 .unwrap();
-            builder.local_get(*local);
+            locals.get(builder);
         }
     }
+    Ok(())
 }
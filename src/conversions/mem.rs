@@ -0,0 +1,220 @@
+//! Selects the WebAssembly memory instructions used to access values of a given
+//! Cranelift type, and translates loads and stores.
+//!
+//! WebAssembly memory is little-endian, so a big-endian access (see
+//! [ir::MemFlags]) is translated as a little-endian one whose value has its
+//! bytes reversed.
+
+use cranelift_codegen::ir::{
+    self,
+    types::{F32, F64, I16, I32, I64, I8},
+    Endianness,
+};
+use walrus::{
+    ir::{ExtendedLoad, LoadKind, MemArg, StoreKind, UnaryOp},
+    InstrSeqBuilder, MemoryId, ModuleLocals,
+};
+
+use crate::error::{ErrorKind, TranslationResult};
+
+use super::{bits::build_bswap, narrow::extend};
+
+/// Returns the kind of load which reads a value of type `ty` from memory.
+pub(crate) fn load_kind(ty: ir::Type) -> TranslationResult<LoadKind> {
+    Ok(match ty {
+        // the upper bits of a narrow integer are never used, so it does not
+        // matter how it is extended
+        ir::types::I8 => LoadKind::I32_8 {
+            kind: ExtendedLoad::ZeroExtend,
+        },
+        ir::types::I16 => LoadKind::I32_16 {
+            kind: ExtendedLoad::ZeroExtend,
+        },
+        ir::types::I32 => LoadKind::I32 { atomic: false },
+        ir::types::I64 => LoadKind::I64 { atomic: false },
+        ir::types::F32 => LoadKind::F32,
+        ir::types::F64 => LoadKind::F64,
+        _ => return Err(ErrorKind::UnsupportedType(ty).into()),
+    })
+}
+
+/// Returns the kind of store which writes a value of type `ty` to memory.
+pub(crate) fn store_kind(ty: ir::Type) -> TranslationResult<StoreKind> {
+    Ok(match ty {
+        ir::types::I8 => StoreKind::I32_8 { atomic: false },
+        ir::types::I16 => StoreKind::I32_16 { atomic: false },
+        ir::types::I32 => StoreKind::I32 { atomic: false },
+        ir::types::I64 => StoreKind::I64 { atomic: false },
+        ir::types::F32 => StoreKind::F32,
+        ir::types::F64 => StoreKind::F64,
+        _ => return Err(ErrorKind::UnsupportedType(ty).into()),
+    })
+}
+
+/// Returns the type of the value which is read from (or written to) memory by
+/// the load (or store) `opcode`, whose result (or stored value) has type `ty`.
+pub(crate) fn access_type(opcode: ir::Opcode, ty: ir::Type) -> ir::Type {
+    match opcode {
+        ir::Opcode::Uload8
+        | ir::Opcode::Sload8
+        | ir::Opcode::Istore8
+        | ir::Opcode::Uload8Complex
+        | ir::Opcode::Sload8Complex
+        | ir::Opcode::Istore8Complex => I8,
+        ir::Opcode::Uload16
+        | ir::Opcode::Sload16
+        | ir::Opcode::Istore16
+        | ir::Opcode::Uload16Complex
+        | ir::Opcode::Sload16Complex
+        | ir::Opcode::Istore16Complex => I16,
+        ir::Opcode::Uload32
+        | ir::Opcode::Sload32
+        | ir::Opcode::Istore32
+        | ir::Opcode::Uload32Complex
+        | ir::Opcode::Sload32Complex
+        | ir::Opcode::Istore32Complex => I32,
+        _ => ty,
+    }
+}
+
+/// Whether `flags` describe a big-endian access.
+pub(crate) fn is_big_endian(flags: ir::MemFlags) -> bool {
+    flags.endianness(Endianness::Little) == Endianness::Big
+}
+
+/// The `memarg` of an access to a value of type `access_ty` at `offset` bytes
+/// from the address on the stack.
+///
+/// Only accesses which Cranelift knows to be aligned are marked as such.
+pub(crate) fn mem_arg(offset: u32, access_ty: ir::Type, flags: ir::MemFlags) -> MemArg {
+    MemArg {
+        align: if flags.aligned() {
+            access_ty.bytes()
+        } else {
+            1
+        },
+        offset,
+    }
+}
+
+/// Loads a value of type `ty` from the address on top of the stack, as the
+/// load `opcode` does.
+pub(crate) fn build_load(
+    opcode: ir::Opcode,
+    ty: ir::Type,
+    flags: ir::MemFlags,
+    offset: u32,
+    memory: MemoryId,
+    locals: &mut ModuleLocals,
+    builder: &mut InstrSeqBuilder,
+) -> TranslationResult<()> {
+    let access_ty = access_type(opcode, ty);
+    let signed = matches!(
+        opcode,
+        ir::Opcode::Sload8
+            | ir::Opcode::Sload16
+            | ir::Opcode::Sload32
+            | ir::Opcode::Sload8Complex
+            | ir::Opcode::Sload16Complex
+            | ir::Opcode::Sload32Complex
+    );
+    let arg = mem_arg(offset, access_ty, flags);
+
+    if !is_big_endian(flags) || access_ty.bytes() == 1 {
+        let kind = if access_ty == ty {
+            load_kind(ty)?
+        } else {
+            let kind = if signed {
+                ExtendedLoad::SignExtend
+            } else {
+                ExtendedLoad::ZeroExtend
+            };
+            match (access_ty, ty) {
+                (I8, I64) => LoadKind::I64_8 { kind },
+                (I16, I64) => LoadKind::I64_16 { kind },
+                (I32, I64) => LoadKind::I64_32 { kind },
+                (I8, _) => LoadKind::I32_8 { kind },
+                _ => LoadKind::I32_16 { kind },
+            }
+        };
+        builder.load(memory, kind, arg);
+        return Ok(());
+    }
+
+    // floats are loaded (and their bytes reversed) as integers
+    let int_ty = int_of(access_ty);
+    builder.load(memory, load_kind(int_ty)?, arg);
+    build_bswap(int_ty, locals, builder)?;
+    match access_ty {
+        F32 => {
+            builder.unop(UnaryOp::F32ReinterpretI32);
+        }
+        F64 => {
+            builder.unop(UnaryOp::F64ReinterpretI64);
+        }
+        _ if access_ty != ty => {
+            extend(builder, access_ty, signed);
+            if ty == I64 {
+                builder.unop(if signed {
+                    UnaryOp::I64ExtendSI32
+                } else {
+                    UnaryOp::I64ExtendUI32
+                });
+            }
+        }
+        _ => (),
+    }
+    Ok(())
+}
+
+/// Stores the value (of type `ty`) on top of the stack at the address below
+/// it, as the store `opcode` does.
+pub(crate) fn build_store(
+    opcode: ir::Opcode,
+    ty: ir::Type,
+    flags: ir::MemFlags,
+    offset: u32,
+    memory: MemoryId,
+    locals: &mut ModuleLocals,
+    builder: &mut InstrSeqBuilder,
+) -> TranslationResult<()> {
+    let access_ty = access_type(opcode, ty);
+    let arg = mem_arg(offset, access_ty, flags);
+
+    if !is_big_endian(flags) || access_ty.bytes() == 1 {
+        let kind = match (access_ty, ty) {
+            (I8, I64) => StoreKind::I64_8 { atomic: false },
+            (I16, I64) => StoreKind::I64_16 { atomic: false },
+            (I32, I64) => StoreKind::I64_32 { atomic: false },
+            _ => store_kind(access_ty)?,
+        };
+        builder.store(memory, kind, arg);
+        return Ok(());
+    }
+
+    match access_ty {
+        F32 => {
+            builder.unop(UnaryOp::I32ReinterpretF32);
+        }
+        F64 => {
+            builder.unop(UnaryOp::I64ReinterpretF64);
+        }
+        I16 | I32 if ty == I64 => {
+            builder.unop(UnaryOp::I32WrapI64);
+        }
+        _ => (),
+    }
+    let int_ty = int_of(access_ty);
+    build_bswap(int_ty, locals, builder)?;
+    builder.store(memory, store_kind(int_ty)?, arg);
+    Ok(())
+}
+
+/// The integer type of the same width as `ty`, if it is a float.
+fn int_of(ty: ir::Type) -> ir::Type {
+    match ty {
+        F32 => I32,
+        F64 => I64,
+        ty => ty,
+    }
+}
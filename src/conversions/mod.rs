@@ -1,5 +1,12 @@
+pub mod arith;
+pub mod atomic;
+pub mod bits;
 pub mod block;
 pub mod cond;
+pub mod float;
 pub mod inst;
+pub mod mem;
+pub mod narrow;
+pub mod pair;
 pub mod sig;
 pub mod ty;
@@ -0,0 +1,73 @@
+//! Integers which are narrower than 32 bits (`i8` and `i16`).
+//!
+//! These are represented by `i32`s, of which only the low bits are meaningful:
+//! operations which wrap (e.g. `iadd`) are simply performed on the whole `i32`,
+//! so the upper bits may contain anything. Wherever the upper bits would affect
+//! the result (e.g. comparisons, extensions or a zero test) the value is first
+//! zero- or sign-extended, using [extend].
+
+use cranelift_codegen::ir::{self, ArgumentExtension};
+use walrus::{
+    ir::{BinaryOp, UnaryOp},
+    InstrSeqBuilder,
+};
+
+/// Whether `ty` is an integer type which is narrower than 32 bits.
+pub(crate) fn is_narrow(ty: ir::Type) -> bool {
+    ty.is_int() && ty.bits() < 32
+}
+
+/// Whether values of type `ty` are represented by a WebAssembly `i32` (and
+/// so use the `i32` instructions).
+pub(crate) fn is_i32(ty: ir::Type) -> bool {
+    ty.is_int() && ty.bits() <= 32
+}
+
+/// Zero- or sign-extends the value of type `ty` on top of the stack to 32
+/// bits. Nothing is emitted if `ty` is not narrow.
+pub(crate) fn extend(builder: &mut InstrSeqBuilder, ty: ir::Type, signed: bool) {
+    match (ty, signed) {
+        (ir::types::I8, true) => {
+            builder.unop(UnaryOp::I32Extend8S);
+        }
+        (ir::types::I16, true) => {
+            builder.unop(UnaryOp::I32Extend16S);
+        }
+        (ty, false) if is_narrow(ty) => {
+            builder.i32_const(mask(ty) as i32).binop(BinaryOp::I32And);
+        }
+        _ => (),
+    }
+}
+
+/// Extends the value of type `ty` on top of the stack as required by the
+/// `extension` of the parameter (or return value) in which it is passed.
+pub(crate) fn extend_argument(
+    builder: &mut InstrSeqBuilder,
+    ty: ir::Type,
+    extension: ArgumentExtension,
+) {
+    match extension {
+        ArgumentExtension::None => (),
+        ArgumentExtension::Uext => extend(builder, ty, false),
+        ArgumentExtension::Sext => extend(builder, ty, true),
+    }
+}
+
+/// Zero- or sign-extends an immediate of type `ty` to 64 bits (in the same way
+/// that [extend] extends a value).
+pub(crate) fn extend_imm(imm: i64, ty: ir::Type, signed: bool) -> i64 {
+    if !is_narrow(ty) {
+        imm
+    } else if signed {
+        let shift = 64 - ty.bits();
+        (imm << shift) >> shift
+    } else {
+        imm & mask(ty)
+    }
+}
+
+/// The bits of a (narrow) integer of type `ty`.
+fn mask(ty: ir::Type) -> i64 {
+    (1 << ty.bits()) - 1
+}
@@ -0,0 +1,871 @@
+//! `i128` values, which are represented by pairs of `i64`s.
+//!
+//! On the stack, an `i128` is its low half followed by its high half (so the
+//! high half is on top), and when it is stored in locals it takes up two (see
+//! [crate::locals::ValueLocals]). `i128` parameters and return values are
+//! passed in the same way.
+//!
+//! Most operations need each half of their operands more than once, so the
+//! operands are first moved into locals.
+
+use cranelift_codegen::ir::{
+    self,
+    condcodes::IntCC,
+    types::{I128, I64},
+};
+use walrus::{
+    ir::{BinaryOp, LoadKind, MemArg, StoreKind, UnaryOp},
+    InstrSeqBuilder, LocalId, ValType,
+};
+
+use crate::{
+    error::{ErrorKind, TranslationResult},
+    locals::ValueLocals,
+    IndividualFunctionTranslator,
+};
+
+use super::{
+    bits::{
+        build_bits_binop, build_bits_unop, build_bswap, build_shift_amount, is_bits_binop,
+        is_bits_unop, is_shift,
+    },
+    block::CanBranchTo,
+    inst::{translate_address, translate_value, without_imm},
+    mem::{is_big_endian, mem_arg},
+    narrow::{extend, is_i32},
+    ty::wasm_of_pointer,
+};
+
+/// Whether `inst` operates on (or produces) an `i128`, and so should be
+/// translated by [translate_pair_inst].
+///
+/// Calls are translated as usual, as the halves of their `i128` arguments are
+/// simply pushed onto the stack in order.
+pub(crate) fn is_pair_inst(inst: ir::Inst, func: &ir::Function) -> bool {
+    match func.dfg[inst] {
+        ir::InstructionData::Call { .. } | ir::InstructionData::CallIndirect { .. } => false,
+        _ => func
+            .dfg
+            .inst_args(inst)
+            .iter()
+            .chain(func.dfg.inst_results(inst))
+            .any(|value| func.dfg.value_type(*value) == I128),
+    }
+}
+
+/// Translates an instruction which operates on `i128`s.
+pub(crate) fn translate_pair_inst(
+    inst: ir::Inst,
+    t: &mut IndividualFunctionTranslator<'_>,
+    builder: &mut InstrSeqBuilder,
+    can_branch_to: &CanBranchTo,
+) -> TranslationResult<()> {
+    let opcode = t.cursor.func.dfg[inst].opcode();
+    let unsupported_opcode =
+        || -> TranslationResult<()> { Err(ErrorKind::UnsupportedOpcode(opcode).into()) };
+    match t.cursor.func.dfg[inst].clone() {
+        ir::InstructionData::UnaryImm {
+            opcode: ir::Opcode::Iconst,
+            imm,
+        } => {
+            // the immediate is zero-extended
+            builder.i64_const(imm.bits()).i64_const(0);
+        }
+        ir::InstructionData::Binary {
+            opcode: ir::Opcode::Iconcat,
+            args,
+        } => {
+            for arg in args {
+                translate_value(arg, t, builder, can_branch_to)?;
+            }
+        }
+        ir::InstructionData::Unary {
+            opcode: ir::Opcode::Isplit,
+            arg,
+        } => {
+            // the results are bound to locals by `build_from_pos`
+            translate_value(arg, t, builder, can_branch_to)?;
+        }
+        ir::InstructionData::Unary { opcode, arg } => {
+            let from = t.cursor.func.dfg.value_type(arg);
+            match opcode {
+                ir::Opcode::Uextend | ir::Opcode::Sextend if is_i32(from) || from == I64 => {
+                    let signed = opcode == ir::Opcode::Sextend;
+                    translate_value(arg, t, builder, can_branch_to)?;
+                    extend(builder, from, signed);
+                    if from != I64 {
+                        builder.unop(if signed {
+                            UnaryOp::I64ExtendSI32
+                        } else {
+                            UnaryOp::I64ExtendUI32
+                        });
+                    }
+                    if signed {
+                        // the high half is a copy of the sign bit
+                        let lo = t.module_locals.add(ValType::I64);
+                        builder
+                            .local_tee(lo)
+                            .local_get(lo)
+                            .i64_const(63)
+                            .binop(BinaryOp::I64ShrS);
+                    } else {
+                        builder.i64_const(0);
+                    }
+                }
+                opcode if is_bits_unop(opcode) && from == I128 => {
+                    let value = halves(arg, t, builder, can_branch_to)?;
+                    bits_unop(opcode, value, t, builder)?;
+                }
+                ir::Opcode::Ireduce => {
+                    translate_value(arg, t, builder, can_branch_to)?;
+                    builder.drop();
+                    let to = t.cursor.func.dfg.ctrl_typevar(inst);
+                    if is_i32(to) {
+                        builder.unop(UnaryOp::I32WrapI64);
+                    } else if to != I64 {
+                        return Err(ErrorKind::UnsupportedType(to).into());
+                    }
+                }
+                _ => return unsupported_opcode(),
+            }
+        }
+        ir::InstructionData::Binary { opcode, args } => {
+            let [a, b] = args;
+            match opcode {
+                // only the low bits of an `i128` shift amount are used
+                opcode if is_shift(opcode) && t.cursor.func.dfg.value_type(a) != I128 => {
+                    let ty = t.cursor.func.dfg.value_type(a);
+                    translate_value(a, t, builder, can_branch_to)?;
+                    translate_value(b, t, builder, can_branch_to)?;
+                    builder.drop();
+                    build_shift_amount(I64, ty, builder);
+                    build_bits_binop(opcode, ty, t.module_locals, builder)?;
+                }
+                ir::Opcode::Iadd | ir::Opcode::Isub | ir::Opcode::Imul => {
+                    let a = halves(a, t, builder, can_branch_to)?;
+                    let b = halves(b, t, builder, can_branch_to)?;
+                    arith(opcode, a, b, t, builder);
+                }
+                opcode if is_bits_binop(opcode) && !is_shift(opcode) => {
+                    let a = halves(a, t, builder, can_branch_to)?;
+                    let b = halves(b, t, builder, can_branch_to)?;
+                    bitwise(opcode, a, b, t, builder)?;
+                }
+                ir::Opcode::Ishl
+                | ir::Opcode::Ushr
+                | ir::Opcode::Sshr
+                | ir::Opcode::Rotl
+                | ir::Opcode::Rotr => {
+                    let value = halves(a, t, builder, can_branch_to)?;
+                    let amount = t.module_locals.add(ValType::I64);
+                    let amount_ty = t.cursor.func.dfg.value_type(b);
+                    translate_value(b, t, builder, can_branch_to)?;
+                    if amount_ty == I128 {
+                        builder.drop();
+                    } else if is_i32(amount_ty) {
+                        builder.unop(UnaryOp::I64ExtendUI32);
+                    }
+                    builder
+                        .i64_const(127)
+                        .binop(BinaryOp::I64And)
+                        .local_set(amount);
+                    if opcode == ir::Opcode::Rotl || opcode == ir::Opcode::Rotr {
+                        rotate(opcode, value, Half::Local(amount), t, builder)?;
+                    } else {
+                        shift(opcode, value, Half::Local(amount), t, builder)?;
+                    }
+                }
+                _ => return unsupported_opcode(),
+            }
+        }
+        ir::InstructionData::BinaryImm64 { opcode, arg, imm } => {
+            let value = halves(arg, t, builder, can_branch_to)?;
+            match opcode {
+                // as with `iconst`, the immediate is zero-extended
+                ir::Opcode::IaddImm => arith(
+                    ir::Opcode::Iadd,
+                    value,
+                    (Half::Const(imm.bits()), Half::Const(0)),
+                    t,
+                    builder,
+                ),
+                ir::Opcode::ImulImm => arith(
+                    ir::Opcode::Imul,
+                    value,
+                    (Half::Const(imm.bits()), Half::Const(0)),
+                    t,
+                    builder,
+                ),
+                ir::Opcode::IshlImm => shift(
+                    ir::Opcode::Ishl,
+                    value,
+                    Half::Const(imm.bits() & 127),
+                    t,
+                    builder,
+                )?,
+                ir::Opcode::UshrImm => shift(
+                    ir::Opcode::Ushr,
+                    value,
+                    Half::Const(imm.bits() & 127),
+                    t,
+                    builder,
+                )?,
+                ir::Opcode::SshrImm => shift(
+                    ir::Opcode::Sshr,
+                    value,
+                    Half::Const(imm.bits() & 127),
+                    t,
+                    builder,
+                )?,
+                ir::Opcode::RotlImm | ir::Opcode::RotrImm => rotate(
+                    without_imm(opcode).unwrap(),
+                    value,
+                    Half::Const(imm.bits() & 127),
+                    t,
+                    builder,
+                )?,
+                ir::Opcode::BandImm | ir::Opcode::BorImm | ir::Opcode::BxorImm => bitwise(
+                    without_imm(opcode).unwrap(),
+                    value,
+                    (Half::Const(imm.bits()), Half::Const(0)),
+                    t,
+                    builder,
+                )?,
+                _ => return unsupported_opcode(),
+            }
+        }
+        ir::InstructionData::IntCompare {
+            opcode: ir::Opcode::Icmp,
+            args,
+            cond,
+        } => {
+            let [a, b] = args;
+            let a = halves(a, t, builder, can_branch_to)?;
+            let b = halves(b, t, builder, can_branch_to)?;
+            compare(cond, a, b, t, builder)?;
+        }
+        ir::InstructionData::IntCompareImm {
+            opcode: ir::Opcode::IcmpImm,
+            arg,
+            cond,
+            imm,
+        } => {
+            let a = halves(arg, t, builder, can_branch_to)?;
+            compare(
+                cond,
+                a,
+                (Half::Const(imm.bits()), Half::Const(0)),
+                t,
+                builder,
+            )?;
+        }
+        ir::InstructionData::StackLoad {
+            opcode: ir::Opcode::StackLoad,
+            stack_slot,
+            offset,
+        } => {
+            let frame = t.frame.unwrap();
+            let offset = frame.offset_of(stack_slot) as i64 + i64::from(offset);
+            for half in 0..2 {
+                builder.local_get(frame.frame_pointer).load(
+                    t.memory_id,
+                    LoadKind::I64 { atomic: false },
+                    MemArg {
+                        align: 8,
+                        offset: (offset + half * 8) as u32,
+                    },
+                );
+            }
+        }
+        ir::InstructionData::StackStore {
+            opcode: ir::Opcode::StackStore,
+            arg,
+            stack_slot,
+            offset,
+        } => {
+            let frame = t.frame.unwrap();
+            let offset = frame.offset_of(stack_slot) as i64 + i64::from(offset);
+            let (lo, hi) = halves(arg, t, builder, can_branch_to)?;
+            for (n, half) in [lo, hi].iter().enumerate() {
+                builder.local_get(frame.frame_pointer);
+                half.push(builder);
+                builder.store(
+                    t.memory_id,
+                    StoreKind::I64 { atomic: false },
+                    MemArg {
+                        align: 8,
+                        offset: (offset + n as i64 * 8) as u32,
+                    },
+                );
+            }
+        }
+        ir::InstructionData::Load {
+            opcode: ir::Opcode::Load,
+            arg,
+            flags,
+            offset,
+        } => {
+            let offset = translate_address(&[arg], offset, t, builder, can_branch_to)?;
+            load(flags, offset, t, builder)?;
+        }
+        ir::InstructionData::LoadComplex {
+            opcode: ir::Opcode::LoadComplex,
+            args,
+            flags,
+            offset,
+        } => {
+            let addresses = args.as_slice(&t.cursor.func.dfg.value_lists).to_vec();
+            let offset = translate_address(&addresses, offset, t, builder, can_branch_to)?;
+            load(flags, offset, t, builder)?;
+        }
+        ir::InstructionData::Store {
+            opcode: ir::Opcode::Store,
+            args: [arg, address],
+            flags,
+            offset,
+        } => {
+            let offset = translate_address(&[address], offset, t, builder, can_branch_to)?;
+            store(arg, flags, offset, t, builder, can_branch_to)?;
+        }
+        ir::InstructionData::StoreComplex {
+            opcode: ir::Opcode::StoreComplex,
+            args,
+            flags,
+            offset,
+        } => {
+            let args = args.as_slice(&t.cursor.func.dfg.value_lists).to_vec();
+            let offset = translate_address(&args[1..], offset, t, builder, can_branch_to)?;
+            store(args[0], flags, offset, t, builder, can_branch_to)?;
+        }
+        _ => return unsupported_opcode(),
+    }
+    Ok(())
+}
+
+/// The offsets (from the start of an `i128` in memory) of its low and high
+/// halves: the halves of a big-endian `i128` are in the opposite order (and
+/// the bytes of each are reversed).
+fn half_offsets(flags: ir::MemFlags) -> [u32; 2] {
+    if is_big_endian(flags) {
+        [8, 0]
+    } else {
+        [0, 8]
+    }
+}
+
+/// Loads an `i128` from the address on top of the stack (plus `offset`).
+fn load(
+    flags: ir::MemFlags,
+    offset: u32,
+    t: &mut IndividualFunctionTranslator<'_>,
+    builder: &mut InstrSeqBuilder,
+) -> TranslationResult<()> {
+    let address = t.module_locals.add(wasm_of_pointer(t.pointer_type));
+    builder.local_set(address);
+    for half_offset in half_offsets(flags) {
+        builder.local_get(address).load(
+            t.memory_id,
+            LoadKind::I64 { atomic: false },
+            mem_arg(offset + half_offset, I64, flags),
+        );
+        if is_big_endian(flags) {
+            build_bswap(I64, t.module_locals, builder)?;
+        }
+    }
+    Ok(())
+}
+
+/// Stores the `i128` `value` at the address on top of the stack (plus
+/// `offset`).
+fn store(
+    value: ir::Value,
+    flags: ir::MemFlags,
+    offset: u32,
+    t: &mut IndividualFunctionTranslator<'_>,
+    builder: &mut InstrSeqBuilder,
+    can_branch_to: &CanBranchTo,
+) -> TranslationResult<()> {
+    let address = t.module_locals.add(wasm_of_pointer(t.pointer_type));
+    builder.local_set(address);
+    let (lo, hi) = halves(value, t, builder, can_branch_to)?;
+    for (half, half_offset) in [lo, hi].iter().zip(half_offsets(flags)) {
+        builder.local_get(address);
+        half.push(builder);
+        if is_big_endian(flags) {
+            build_bswap(I64, t.module_locals, builder)?;
+        }
+        builder.store(
+            t.memory_id,
+            StoreKind::I64 { atomic: false },
+            mem_arg(offset + half_offset, I64, flags),
+        );
+    }
+    Ok(())
+}
+
+/// One half of an `i128`.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Half {
+    Local(LocalId),
+    Const(i64),
+}
+
+impl Half {
+    fn push(self, builder: &mut InstrSeqBuilder) {
+        match self {
+            Half::Local(local) => {
+                builder.local_get(local);
+            }
+            Half::Const(value) => {
+                builder.i64_const(value);
+            }
+        }
+    }
+}
+
+/// Makes the (low and high) halves of an `i128` value available in locals.
+fn halves(
+    value: ir::Value,
+    t: &mut IndividualFunctionTranslator<'_>,
+    builder: &mut InstrSeqBuilder,
+    can_branch_to: &CanBranchTo,
+) -> TranslationResult<(Half, Half)> {
+    let locals = match t.locals.get(&value) {
+        Some(locals) => locals.clone(),
+        None => {
+            translate_value(value, t, builder, can_branch_to)?;
+            let locals = ValueLocals::new(t.module_locals, I128)?;
+            locals.set(builder);
+            locals
+        }
+    };
+    match locals.ids() {
+        [lo, hi] => Ok((Half::Local(*lo), Half::Local(*hi))),
+        _ => Err(ErrorKind::UnsupportedType(t.cursor.func.dfg.value_type(value)).into()),
+    }
+}
+
+/// Pushes the result of `iadd`, `isub` or `imul` on `a` and `b`.
+fn arith(
+    opcode: ir::Opcode,
+    (a_lo, a_hi): (Half, Half),
+    (b_lo, b_hi): (Half, Half),
+    t: &mut IndividualFunctionTranslator<'_>,
+    builder: &mut InstrSeqBuilder,
+) {
+    match opcode {
+        ir::Opcode::Iadd => {
+            // there is a carry if the low half wraps around (and so is less
+            // than either operand)
+            let lo = t.module_locals.add(ValType::I64);
+            a_lo.push(builder);
+            b_lo.push(builder);
+            builder.binop(BinaryOp::I64Add).local_tee(lo);
+            a_hi.push(builder);
+            b_hi.push(builder);
+            builder.binop(BinaryOp::I64Add).local_get(lo);
+            a_lo.push(builder);
+            builder
+                .binop(BinaryOp::I64LtU)
+                .unop(UnaryOp::I64ExtendUI32)
+                .binop(BinaryOp::I64Add);
+        }
+        ir::Opcode::Isub => {
+            a_lo.push(builder);
+            b_lo.push(builder);
+            builder.binop(BinaryOp::I64Sub);
+            a_hi.push(builder);
+            b_hi.push(builder);
+            builder.binop(BinaryOp::I64Sub);
+            a_lo.push(builder);
+            b_lo.push(builder);
+            builder
+                .binop(BinaryOp::I64LtU)
+                .unop(UnaryOp::I64ExtendUI32)
+                .binop(BinaryOp::I64Sub);
+        }
+        ir::Opcode::Imul => {
+            // (a_hi * 2^64 + a_lo) * (b_hi * 2^64 + b_lo), of which only the
+            // low 128 bits are kept
+            a_lo.push(builder);
+            b_lo.push(builder);
+            builder.binop(BinaryOp::I64Mul);
+            umulhi64(a_lo, b_lo, builder);
+            a_lo.push(builder);
+            b_hi.push(builder);
+            builder.binop(BinaryOp::I64Mul).binop(BinaryOp::I64Add);
+            a_hi.push(builder);
+            b_lo.push(builder);
+            builder.binop(BinaryOp::I64Mul).binop(BinaryOp::I64Add);
+        }
+        _ => unreachable!(),
+    }
+}
+
+/// Pushes the high 64 bits of the (128-bit) product of two `i64`s, which is
+/// computed from the products of their 32-bit halves.
+pub(crate) fn umulhi64(x: Half, y: Half, builder: &mut InstrSeqBuilder) {
+    let lo32 = |builder: &mut InstrSeqBuilder, half: Half| {
+        half.push(builder);
+        builder.i64_const(0xffff_ffff).binop(BinaryOp::I64And);
+    };
+    let hi32 = |builder: &mut InstrSeqBuilder, half: Half| {
+        half.push(builder);
+        builder.i64_const(32).binop(BinaryOp::I64ShrU);
+    };
+
+    // x_hi * y_hi
+    hi32(builder, x);
+    hi32(builder, y);
+    builder.binop(BinaryOp::I64Mul);
+    // + the high halves of the cross products
+    hi32(builder, x);
+    lo32(builder, y);
+    builder
+        .binop(BinaryOp::I64Mul)
+        .i64_const(32)
+        .binop(BinaryOp::I64ShrU)
+        .binop(BinaryOp::I64Add);
+    lo32(builder, x);
+    hi32(builder, y);
+    builder
+        .binop(BinaryOp::I64Mul)
+        .i64_const(32)
+        .binop(BinaryOp::I64ShrU)
+        .binop(BinaryOp::I64Add);
+    // + the carry out of the middle 32 bits (which cannot overflow, as each of
+    // the three terms is less than 2^32)
+    lo32(builder, x);
+    lo32(builder, y);
+    builder
+        .binop(BinaryOp::I64Mul)
+        .i64_const(32)
+        .binop(BinaryOp::I64ShrU);
+    hi32(builder, x);
+    lo32(builder, y);
+    builder
+        .binop(BinaryOp::I64Mul)
+        .i64_const(0xffff_ffff)
+        .binop(BinaryOp::I64And)
+        .binop(BinaryOp::I64Add);
+    lo32(builder, x);
+    hi32(builder, y);
+    builder
+        .binop(BinaryOp::I64Mul)
+        .i64_const(0xffff_ffff)
+        .binop(BinaryOp::I64And)
+        .binop(BinaryOp::I64Add)
+        .i64_const(32)
+        .binop(BinaryOp::I64ShrU)
+        .binop(BinaryOp::I64Add);
+}
+
+/// Pushes the result of shifting `value` by `amount` (which must be less than
+/// 128).
+///
+/// WebAssembly only uses the low six bits of a shift amount, so shifting a half
+/// by `amount` shifts it by `amount - 64` if `amount` is at least 64.
+fn shift(
+    opcode: ir::Opcode,
+    (lo, hi): (Half, Half),
+    amount: Half,
+    t: &mut IndividualFunctionTranslator<'_>,
+    builder: &mut InstrSeqBuilder,
+) -> TranslationResult<()> {
+    // the bits which move from one half to the other (if `amount` is less
+    // than 64) are shifted by `64 - amount` in two steps, so that nothing is
+    // moved if `amount` is zero
+    let carried = |builder: &mut InstrSeqBuilder, from: Half, op: BinaryOp| {
+        from.push(builder);
+        builder.i64_const(1).binop(op).i64_const(63);
+        amount.push(builder);
+        builder.binop(BinaryOp::I64Sub).binop(op);
+    };
+    let small = |builder: &mut InstrSeqBuilder| {
+        amount.push(builder);
+        builder.i64_const(64).binop(BinaryOp::I64LtU);
+    };
+    let shifted = |builder: &mut InstrSeqBuilder, half: Half, op: BinaryOp| {
+        half.push(builder);
+        amount.push(builder);
+        builder.binop(op);
+    };
+
+    let result_lo = t.module_locals.add(ValType::I64);
+    match opcode {
+        ir::Opcode::Ishl => {
+            // lo
+            shifted(builder, lo, BinaryOp::I64Shl);
+            builder.i64_const(0);
+            small(builder);
+            builder.select(None).local_set(result_lo);
+            // hi
+            shifted(builder, hi, BinaryOp::I64Shl);
+            carried(builder, lo, BinaryOp::I64ShrU);
+            builder.binop(BinaryOp::I64Or);
+            shifted(builder, lo, BinaryOp::I64Shl);
+        }
+        ir::Opcode::Ushr | ir::Opcode::Sshr => {
+            let op = if opcode == ir::Opcode::Ushr {
+                BinaryOp::I64ShrU
+            } else {
+                BinaryOp::I64ShrS
+            };
+            // lo
+            shifted(builder, lo, BinaryOp::I64ShrU);
+            carried(builder, hi, BinaryOp::I64Shl);
+            builder.binop(BinaryOp::I64Or);
+            shifted(builder, hi, op);
+            small(builder);
+            builder.select(None).local_set(result_lo);
+            // hi
+            shifted(builder, hi, op);
+            if opcode == ir::Opcode::Ushr {
+                builder.i64_const(0);
+            } else {
+                hi.push(builder);
+                builder.i64_const(63).binop(BinaryOp::I64ShrS);
+            }
+        }
+        _ => return Err(ErrorKind::UnsupportedOpcode(opcode).into()),
+    }
+    small(builder);
+    builder.select(None);
+
+    // the high half is on top of the stack
+    let result_hi = t.module_locals.add(ValType::I64);
+    builder
+        .local_set(result_hi)
+        .local_get(result_lo)
+        .local_get(result_hi);
+    Ok(())
+}
+
+/// Pushes the result of rotating `value` by `amount` (which must be less than
+/// 128).
+///
+/// This is the combination of shifting `value` by `amount` in one direction
+/// and by `128 - amount` in the other (both of which are the identity if
+/// `amount` is zero).
+fn rotate(
+    opcode: ir::Opcode,
+    value: (Half, Half),
+    amount: Half,
+    t: &mut IndividualFunctionTranslator<'_>,
+    builder: &mut InstrSeqBuilder,
+) -> TranslationResult<()> {
+    let (first, second) = match opcode {
+        ir::Opcode::Rotl => (ir::Opcode::Ishl, ir::Opcode::Ushr),
+        ir::Opcode::Rotr => (ir::Opcode::Ushr, ir::Opcode::Ishl),
+        _ => return Err(ErrorKind::UnsupportedOpcode(opcode).into()),
+    };
+    let other = match amount {
+        Half::Const(amount) => Half::Const((128 - amount) & 127),
+        Half::Local(amount) => {
+            let other = t.module_locals.add(ValType::I64);
+            builder
+                .i64_const(128)
+                .local_get(amount)
+                .binop(BinaryOp::I64Sub)
+                .i64_const(127)
+                .binop(BinaryOp::I64And)
+                .local_set(other);
+            Half::Local(other)
+        }
+    };
+
+    shift(first, value, amount, t, builder)?;
+    let shifted = halves_of_stack(t, builder);
+    shift(second, value, other, t, builder)?;
+    let other_shifted = halves_of_stack(t, builder);
+    bitwise(ir::Opcode::Bor, shifted, other_shifted, t, builder)
+}
+
+/// Moves the `i128` on top of the stack into locals.
+fn halves_of_stack(
+    t: &mut IndividualFunctionTranslator<'_>,
+    builder: &mut InstrSeqBuilder,
+) -> (Half, Half) {
+    let (lo, hi) = (
+        t.module_locals.add(ValType::I64),
+        t.module_locals.add(ValType::I64),
+    );
+    builder.local_set(hi).local_set(lo);
+    (Half::Local(lo), Half::Local(hi))
+}
+
+/// Pushes the result of a (binary) bitwise operation on `a` and `b`, which is
+/// applied to each half separately.
+fn bitwise(
+    opcode: ir::Opcode,
+    (a_lo, a_hi): (Half, Half),
+    (b_lo, b_hi): (Half, Half),
+    t: &mut IndividualFunctionTranslator<'_>,
+    builder: &mut InstrSeqBuilder,
+) -> TranslationResult<()> {
+    for (a, b) in [(a_lo, b_lo), (a_hi, b_hi)] {
+        a.push(builder);
+        b.push(builder);
+        build_bits_binop(opcode, I64, t.module_locals, builder)?;
+    }
+    Ok(())
+}
+
+/// Pushes the result of a unary bitwise operation (or of counting the bits) of
+/// `value`.
+fn bits_unop(
+    opcode: ir::Opcode,
+    (lo, hi): (Half, Half),
+    t: &mut IndividualFunctionTranslator<'_>,
+    builder: &mut InstrSeqBuilder,
+) -> TranslationResult<()> {
+    match opcode {
+        ir::Opcode::Bnot => {
+            for half in [lo, hi] {
+                half.push(builder);
+                build_bits_unop(opcode, I64, t.module_locals, builder)?;
+            }
+            return Ok(());
+        }
+        ir::Opcode::Bitrev => {
+            // the halves are reversed and swapped
+            for half in [hi, lo] {
+                half.push(builder);
+                build_bits_unop(opcode, I64, t.module_locals, builder)?;
+            }
+            return Ok(());
+        }
+        ir::Opcode::Clz => leading_zeros((lo, hi), builder),
+        ir::Opcode::Cls => {
+            // the leading zeros after flipping every bit if the sign bit is
+            // set, excluding the sign bit itself
+            let sign = t.module_locals.add(ValType::I64);
+            hi.push(builder);
+            builder
+                .i64_const(63)
+                .binop(BinaryOp::I64ShrS)
+                .local_set(sign);
+            for half in [lo, hi] {
+                half.push(builder);
+                builder.local_get(sign).binop(BinaryOp::I64Xor);
+            }
+            let flipped = halves_of_stack(t, builder);
+            leading_zeros(flipped, builder);
+            builder.i64_const(1).binop(BinaryOp::I64Sub);
+        }
+        ir::Opcode::Ctz => {
+            // the low half is only counted past if it is zero
+            hi.push(builder);
+            builder
+                .unop(UnaryOp::I64Ctz)
+                .i64_const(64)
+                .binop(BinaryOp::I64Add);
+            lo.push(builder);
+            builder.unop(UnaryOp::I64Ctz);
+            lo.push(builder);
+            builder.unop(UnaryOp::I64Eqz).select(None);
+        }
+        ir::Opcode::Popcnt => {
+            lo.push(builder);
+            builder.unop(UnaryOp::I64Popcnt);
+            hi.push(builder);
+            builder.unop(UnaryOp::I64Popcnt).binop(BinaryOp::I64Add);
+        }
+        _ => return Err(ErrorKind::UnsupportedOpcode(opcode).into()),
+    }
+    // the counts are small enough that the high half is always zero
+    builder.i64_const(0);
+    Ok(())
+}
+
+/// Pushes the number of leading zeros of `value` (as an `i64`).
+fn leading_zeros((lo, hi): (Half, Half), builder: &mut InstrSeqBuilder) {
+    // the high half is only counted past if it is zero
+    lo.push(builder);
+    builder
+        .unop(UnaryOp::I64Clz)
+        .i64_const(64)
+        .binop(BinaryOp::I64Add);
+    hi.push(builder);
+    builder.unop(UnaryOp::I64Clz);
+    hi.push(builder);
+    builder.unop(UnaryOp::I64Eqz).select(None);
+}
+
+/// Pushes the result (an `i32`) of comparing `a` and `b`.
+fn compare(
+    cond: IntCC,
+    (a_lo, a_hi): (Half, Half),
+    (b_lo, b_hi): (Half, Half),
+    t: &mut IndividualFunctionTranslator<'_>,
+    builder: &mut InstrSeqBuilder,
+) -> TranslationResult<()> {
+    match cond {
+        IntCC::Equal | IntCC::NotEqual => {
+            a_lo.push(builder);
+            b_lo.push(builder);
+            builder.binop(BinaryOp::I64Xor);
+            a_hi.push(builder);
+            b_hi.push(builder);
+            builder
+                .binop(BinaryOp::I64Xor)
+                .binop(BinaryOp::I64Or)
+                .unop(UnaryOp::I64Eqz);
+            if cond == IntCC::NotEqual {
+                builder.unop(UnaryOp::I32Eqz);
+            }
+        }
+        IntCC::Overflow | IntCC::NotOverflow => {
+            // as for other types, the subtraction overflows if the operands
+            // have different signs, and the sign of the result differs from
+            // that of the first
+            arith(ir::Opcode::Isub, (a_lo, a_hi), (b_lo, b_hi), t, builder);
+            let difference_hi = t.module_locals.add(ValType::I64);
+            builder.local_set(difference_hi).drop();
+            a_hi.push(builder);
+            b_hi.push(builder);
+            builder.binop(BinaryOp::I64Xor);
+            a_hi.push(builder);
+            builder
+                .local_get(difference_hi)
+                .binop(BinaryOp::I64Xor)
+                .binop(BinaryOp::I64And)
+                .i64_const(0)
+                .binop(BinaryOp::I64LtS);
+            if cond == IntCC::NotOverflow {
+                builder.unop(UnaryOp::I32Eqz);
+            }
+        }
+        ordering => {
+            // the high halves decide the result, unless they are equal (in
+            // which case the low halves are compared, always unsigned)
+            let (strict, lo) = ordering_ops(ordering);
+            a_lo.push(builder);
+            b_lo.push(builder);
+            builder.binop(lo);
+            a_hi.push(builder);
+            b_hi.push(builder);
+            builder.binop(strict);
+            a_hi.push(builder);
+            b_hi.push(builder);
+            builder.binop(BinaryOp::I64Eq).select(None);
+        }
+    }
+    Ok(())
+}
+
+/// For an ordering condition, the strict comparison of the high halves and
+/// the (unsigned) comparison of the low halves.
+fn ordering_ops(cond: IntCC) -> (BinaryOp, BinaryOp) {
+    match cond {
+        IntCC::SignedLessThan => (BinaryOp::I64LtS, BinaryOp::I64LtU),
+        IntCC::SignedLessThanOrEqual => (BinaryOp::I64LtS, BinaryOp::I64LeU),
+        IntCC::SignedGreaterThan => (BinaryOp::I64GtS, BinaryOp::I64GtU),
+        IntCC::SignedGreaterThanOrEqual => (BinaryOp::I64GtS, BinaryOp::I64GeU),
+        IntCC::UnsignedLessThan => (BinaryOp::I64LtU, BinaryOp::I64LtU),
+        IntCC::UnsignedLessThanOrEqual => (BinaryOp::I64LtU, BinaryOp::I64LeU),
+        IntCC::UnsignedGreaterThan => (BinaryOp::I64GtU, BinaryOp::I64GtU),
+        IntCC::UnsignedGreaterThanOrEqual => (BinaryOp::I64GtU, BinaryOp::I64GeU),
+        _ => unreachable!(),
+    }
+}
@@ -1,21 +1,53 @@
 //! Converts WebAssembly to Cranelift signatures.
 
-use cranelift_codegen::ir::{self, AbiParam};
+use cranelift_codegen::ir::{self, AbiParam, ArgumentPurpose};
 use walrus::ValType;
 
-use crate::conversions::ty::wasm_of_cranelift;
+use crate::{
+    conversions::ty::wasm_values_of_cranelift,
+    error::{ErrorKind, TranslationError, TranslationResult},
+};
 
 /// Transforms a Cranelift [cranelift_codegen::ir::Signature] into the
 /// corresponding [walrus::ValType]'s, returning them in the form
 /// `(Vec<parameters>, Vec<return_values>)`.
-pub(crate) fn wasm_of_sig(sig: ir::Signature) -> (Vec<ValType>, Vec<ValType>) {
-    // todo: handle some of the other information
-    fn map_abi_param(param: AbiParam) -> ValType {
-        wasm_of_cranelift(param.value_type)
+///
+/// An `i128` parameter (or return value) is passed as two `i64`s.
+pub(crate) fn wasm_of_sig(sig: ir::Signature) -> TranslationResult<(Vec<ValType>, Vec<ValType>)> {
+    let params = wasm_of_abi_params(&sig.params)?;
+    let returns = wasm_of_abi_params(&sig.returns)?;
+
+    Ok((params, returns))
+}
+
+fn wasm_of_abi_params(params: &[AbiParam]) -> TranslationResult<Vec<ValType>> {
+    let mut types = Vec::new();
+    for param in params {
+        types.extend(wasm_of_abi_param(param)?);
     }
+    Ok(types)
+}
 
-    let params = sig.params.into_iter().map(map_abi_param).collect();
-    let returns = sig.returns.into_iter().map(map_abi_param).collect();
+/// Returns every reason why `sig` cannot be translated (rather than only the
+/// first, like [wasm_of_sig]).
+pub(crate) fn check_sig(sig: &ir::Signature) -> Vec<TranslationError> {
+    sig.params
+        .iter()
+        .chain(sig.returns.iter())
+        .filter_map(|param| wasm_of_abi_param(param).err())
+        .collect()
+}
 
-    (params, returns)
+fn wasm_of_abi_param(param: &AbiParam) -> TranslationResult<Vec<ValType>> {
+    match param.purpose {
+        // these are passed like any other value
+        ArgumentPurpose::Normal
+        | ArgumentPurpose::StructReturn
+        | ArgumentPurpose::VMContext
+        | ArgumentPurpose::SignatureId => wasm_values_of_cranelift(param.value_type),
+        // the remaining purposes either describe the native ABI (which
+        // WebAssembly hides from us) or expect the callee to do something
+        // which we do not do (e.g. copy a struct or check the stack limit)
+        purpose => Err(ErrorKind::UnsupportedArgumentPurpose(purpose).into()),
+    }
 }
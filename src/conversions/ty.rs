@@ -1,31 +1,53 @@
 //! Translates atomic types (i.e. not functions) from Cranelift to WebAssembly.
 
-use cranelift_codegen::ir::types::Type as CraneliftType;
+use cranelift_codegen::ir::types::{self, Type as CraneliftType};
 use walrus::ValType;
 
+use crate::error::{ErrorKind, TranslationResult};
+
 /// Convert a Cranelift type into its corresponding WebAssembly form.
 ///
-/// note: this function panics if it does not yet know how to represent the type
-/// todo: report a proper error
-pub(crate) fn wasm_of_cranelift(ty: CraneliftType) -> ValType {
-    // integers
-    if ty.is_int() && ty.bits() == 32 {
-        return ValType::I32;
+/// Returns an error if we do not yet know how to represent the type.
+pub(crate) fn wasm_of_cranelift(ty: CraneliftType) -> TranslationResult<ValType> {
+    // integers (narrow integers are represented by `i32`s, see
+    // `conversions::narrow`)
+    if ty.is_int() && ty.bits() <= 32 {
+        return Ok(ValType::I32);
     } else if ty.is_int() && ty.bits() == 64 {
-        return ValType::I64;
+        return Ok(ValType::I64);
     }
 
     // floats
     if ty.is_float() && ty.bits() == 32 {
-        return ValType::F32;
+        return Ok(ValType::F32);
     } else if ty.is_float() && ty.bits() == 64 {
-        return ValType::F64;
+        return Ok(ValType::F64);
     }
 
     // todo: is this correct?
     if ty.is_bool() {
-        return ValType::I32;
+        return Ok(ValType::I32);
     }
 
-    todo!()
+    Err(ErrorKind::UnsupportedType(ty).into())
+}
+
+/// Converts a Cranelift type into the WebAssembly values which represent it:
+/// a single value for most types, but two `i64`s (the low and then the high
+/// half) for an `i128` (see `conversions::pair`).
+pub(crate) fn wasm_values_of_cranelift(ty: CraneliftType) -> TranslationResult<Vec<ValType>> {
+    if ty == types::I128 {
+        Ok(vec![ValType::I64, ValType::I64])
+    } else {
+        Ok(vec![wasm_of_cranelift(ty)?])
+    }
+}
+
+/// The WebAssembly type of a pointer (which is always either `i32` or `i64`).
+pub(crate) fn wasm_of_pointer(pointer_type: CraneliftType) -> ValType {
+    if pointer_type.bits() == 64 {
+        ValType::I64
+    } else {
+        ValType::I32
+    }
 }
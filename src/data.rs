@@ -0,0 +1,100 @@
+//! Lays out data objects in linear memory.
+
+use cranelift_module::DataId;
+use fnv::FnvHashMap;
+use walrus::{ir::InstrSeqId, FunctionId, ValType};
+
+/// The alignment of data objects which do not specify one.
+pub(crate) const DEFAULT_DATA_ALIGNMENT: u64 = 8;
+
+/// A pointer to a data object which has to be written into another data object
+/// once the address of the former is known.
+#[derive(Debug)]
+pub(crate) struct PendingDataReloc {
+    /// The data object into which the pointer is written.
+    pub(crate) source: DataId,
+    /// The offset (in `source`) at which the pointer is written.
+    pub(crate) offset: usize,
+    /// The data object which is pointed to.
+    pub(crate) target: DataId,
+    /// The value to add to the address of `target`.
+    pub(crate) addend: i64,
+}
+
+/// A constant in the body of a function which has to be replaced with the
+/// address of a data object once the data object has been defined.
+#[derive(Debug)]
+pub(crate) struct PendingSymbolRef {
+    /// The function containing the constant.
+    pub(crate) func: FunctionId,
+    /// The instruction sequence containing the constant.
+    pub(crate) seq: InstrSeqId,
+    /// The position of the constant in the instruction sequence.
+    pub(crate) position: usize,
+    /// The type of the constant (i.e. the pointer type).
+    pub(crate) ty: ValType,
+    /// The data object whose address should be used.
+    pub(crate) target: DataId,
+    /// The value to add to the address of `target`.
+    pub(crate) addend: i64,
+}
+
+/// Writes a (little-endian) pointer which is `pointer_bytes` wide into
+/// `contents` at `offset`.
+pub(crate) fn write_pointer(
+    contents: &mut [u8],
+    offset: usize,
+    pointer: u64,
+    pointer_bytes: usize,
+) {
+    contents[offset..offset + pointer_bytes]
+        .copy_from_slice(&pointer.to_le_bytes()[..pointer_bytes]);
+}
+
+/// Assigns addresses in linear memory to data objects.
+///
+/// Data objects are placed one after another (in the order in which they are
+/// defined), starting at a fixed address.
+#[derive(Debug)]
+pub(crate) struct DataLayout {
+    /// The first address which has not yet been assigned to a data object.
+    next: u64,
+    /// The address assigned to each data object.
+    addresses: FnvHashMap<DataId, u64>,
+}
+
+impl DataLayout {
+    /// Creates a new layout which places data objects at or after `start`.
+    pub(crate) fn new(start: u64) -> Self {
+        Self {
+            next: start,
+            addresses: Default::default(),
+        }
+    }
+
+    /// Reserves `size` bytes (aligned to `align` bytes) for the data object,
+    /// returning the address of the data object.
+    pub(crate) fn allocate(&mut self, data: DataId, size: u64, align: u64) -> u64 {
+        let address = self.align(align);
+        self.next = address + size;
+        self.addresses.insert(data, address);
+        address
+    }
+
+    /// Returns the end of the data objects if a data object of the given size
+    /// and alignment were to be allocated.
+    pub(crate) fn end_after(&self, size: u64, align: u64) -> u64 {
+        self.align(align) + size
+    }
+
+    /// Returns the next free address which has the given alignment.
+    fn align(&self, align: u64) -> u64 {
+        debug_assert!(align.is_power_of_two());
+        (self.next + align - 1) & !(align - 1)
+    }
+
+    /// Returns the address of the data object (if it has been defined).
+    pub(crate) fn address_of(&self, data: DataId) -> Option<u64> {
+        self.addresses.get(&data).copied()
+    }
+}
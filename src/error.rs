@@ -0,0 +1,103 @@
+//! Errors which occur while translating Cranelift IR to WebAssembly.
+
+use std::fmt;
+
+use cranelift_codegen::ir::{self, ArgumentPurpose};
+use cranelift_module::ModuleError;
+
+/// The result of translating (part of) a function.
+pub type TranslationResult<T> = Result<T, TranslationError>;
+
+/// Describes why a function could not be translated.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ErrorKind {
+    /// The opcode cannot (yet) be translated.
+    UnsupportedOpcode(ir::Opcode),
+    /// Values of this type cannot (yet) be represented in WebAssembly.
+    UnsupportedType(ir::Type),
+    /// A parameter (or return value) of a signature has a special purpose
+    /// which has no meaning on WebAssembly.
+    UnsupportedArgumentPurpose(ArgumentPurpose),
+    /// The relooper chose a way of branching which cannot (yet) be translated
+    /// (this contains the name of the branch mode).
+    UnsupportedBranchMode(String),
+    /// Any other construct which cannot be translated.
+    Unsupported(String),
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ErrorKind::UnsupportedOpcode(opcode) => write!(f, "unsupported opcode `{}`", opcode),
+            ErrorKind::UnsupportedType(ty) => write!(f, "unsupported type `{}`", ty),
+            ErrorKind::UnsupportedArgumentPurpose(purpose) => {
+                write!(f, "unsupported argument purpose `{}`", purpose)
+            }
+            ErrorKind::UnsupportedBranchMode(mode) => {
+                write!(f, "unsupported branch mode `{}`", mode)
+            }
+            ErrorKind::Unsupported(reason) => write!(f, "unsupported: {}", reason),
+        }
+    }
+}
+
+/// An error which occurred while translating a function, together with the
+/// instruction which could not be translated (if the error is caused by a
+/// specific instruction).
+///
+/// This is returned (wrapped in [`ModuleError::Backend`]) by
+/// [`cranelift_module::Module::define_function`], and can be retrieved using
+/// `anyhow::Error::downcast_ref`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TranslationError {
+    /// What went wrong.
+    pub kind: ErrorKind,
+    /// The instruction which could not be translated.
+    pub inst: Option<ir::Inst>,
+    /// The CLIF text of `inst`.
+    pub clif: Option<String>,
+}
+
+impl TranslationError {
+    pub(crate) fn new(kind: ErrorKind) -> Self {
+        Self {
+            kind,
+            inst: None,
+            clif: None,
+        }
+    }
+
+    /// Records that the error was caused by `inst` (unless the error has
+    /// already been attributed to another instruction).
+    pub(crate) fn at(mut self, inst: ir::Inst, func: &ir::Function) -> Self {
+        if self.inst.is_none() {
+            self.inst = Some(inst);
+            self.clif = Some(func.dfg.display_inst(inst).to_string());
+        }
+        self
+    }
+}
+
+impl From<ErrorKind> for TranslationError {
+    fn from(kind: ErrorKind) -> Self {
+        Self::new(kind)
+    }
+}
+
+impl fmt::Display for TranslationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.kind)?;
+        if let (Some(inst), Some(clif)) = (self.inst, &self.clif) {
+            write!(f, " in {} (`{}`)", inst, clif)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for TranslationError {}
+
+impl From<TranslationError> for ModuleError {
+    fn from(e: TranslationError) -> Self {
+        ModuleError::Backend(e.into())
+    }
+}
@@ -0,0 +1,118 @@
+//! A description of the WebAssembly "instruction set".
+//!
+//! Cranelift does not generate WebAssembly itself, so this does not provide a
+//! code generator; it only describes the target (e.g. the pointer type) to
+//! frontends and to `cranelift_module`.
+
+use std::fmt;
+
+use anyhow::anyhow;
+use cranelift_codegen::{
+    ir::{self, condcodes::IntCC},
+    isa::{CallConv, TargetFrontendConfig, TargetIsa},
+    settings,
+};
+use cranelift_module::{ModuleError, ModuleResult};
+use target_lexicon::{Architecture, BinaryFormat, PointerWidth, Triple};
+
+/// The `wasm32` or `wasm64` target.
+#[derive(Clone)]
+pub struct WasmIsa {
+    triple: Triple,
+    flags: settings::Flags,
+}
+
+impl WasmIsa {
+    /// Describes the target given by `triple`, which must be a `wasm32` or
+    /// `wasm64` triple (with the WebAssembly binary format).
+    pub fn new(triple: Triple, flags: settings::Flags) -> ModuleResult<Self> {
+        if !matches!(
+            triple.architecture,
+            Architecture::Wasm32 | Architecture::Wasm64
+        ) {
+            return Err(ModuleError::Backend(anyhow!(
+                "unsupported architecture `{}`: only wasm32 and wasm64 are supported",
+                triple.architecture
+            )));
+        }
+        if triple.binary_format != BinaryFormat::Wasm {
+            return Err(ModuleError::Backend(anyhow!(
+                "unsupported binary format `{}`: only WebAssembly is supported (for other \
+                 targets, you may want to look at `cranelift_object`)",
+                triple.binary_format
+            )));
+        }
+
+        Ok(Self { triple, flags })
+    }
+
+    /// The type of a pointer (`i32` for wasm32 and `i64` for wasm64).
+    pub fn pointer_type(&self) -> ir::Type {
+        match self.pointer_width() {
+            PointerWidth::U64 => ir::types::I64,
+            _ => ir::types::I32,
+        }
+    }
+
+    /// The calling convention used for functions in the module.
+    ///
+    /// WebAssembly functions have their own calling convention, which does not
+    /// depend on the Cranelift calling convention, so this is always
+    /// [`CallConv::SystemV`]. Note that this should be used instead of
+    /// `<dyn TargetIsa>::default_call_conv`, which panics for wasm32 triples
+    /// (Cranelift does not know the "wasm basic C ABI").
+    pub fn default_call_conv(&self) -> CallConv {
+        CallConv::SystemV
+    }
+
+    /// The information needed by frontends producing Cranelift IR.
+    pub fn frontend_config(&self) -> TargetFrontendConfig {
+        TargetFrontendConfig {
+            default_call_conv: self.default_call_conv(),
+            pointer_width: self.pointer_width(),
+        }
+    }
+
+    fn pointer_width(&self) -> PointerWidth {
+        match self.triple.architecture {
+            Architecture::Wasm64 => PointerWidth::U64,
+            _ => PointerWidth::U32,
+        }
+    }
+}
+
+impl fmt::Display for WasmIsa {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("WasmIsa")
+            .field("name", &self.name())
+            .field("triple", &self.triple)
+            .field("flags", &format!("{}", self.flags))
+            .finish()
+    }
+}
+
+impl TargetIsa for WasmIsa {
+    fn name(&self) -> &'static str {
+        match self.triple.architecture {
+            Architecture::Wasm64 => "wasm64",
+            _ => "wasm32",
+        }
+    }
+
+    fn triple(&self) -> &Triple {
+        &self.triple
+    }
+
+    fn flags(&self) -> &settings::Flags {
+        &self.flags
+    }
+
+    fn isa_flags(&self) -> Vec<settings::Value> {
+        Vec::new()
+    }
+
+    fn unsigned_add_overflow_condition(&self) -> IntCC {
+        // `a + b` overflows if the (wrapped) result is less than `a`
+        IntCC::UnsignedLessThan
+    }
+}
@@ -1,42 +1,86 @@
 //! A WebAssembly module for Cranelift.
 
+// `ModuleError` is large, but it is the error type of `cranelift_module` (and so
+// of the `Module` trait), which the rest of the crate returns as well
+#![allow(clippy::result_large_err)]
+
 #[cfg(test)]
 mod tests;
 
+mod check;
 mod conversions;
+mod data;
+mod error;
+mod isa;
+mod locals;
+mod memory;
+mod memory64;
 mod optable;
+mod precompiled;
+mod stack;
+mod structure;
+mod table;
 
 use std::path::Path;
 
+use anyhow::anyhow;
 use cranelift_codegen::{
     binemit,
     cursor::{Cursor, FuncCursor},
     ir::{self, instructions::BranchInfo, Block},
-    isa::TargetIsa,
-    Context,
+    isa::{TargetFrontendConfig, TargetIsa},
+    settings, Context,
 };
 use cranelift_module::{
     DataContext, DataId, FuncId, Linkage, Module as CraneliftModule, ModuleCompiledFunction,
-    ModuleDeclarations, ModuleResult,
+    ModuleDeclarations, ModuleError, ModuleResult,
 };
-use fnv::FnvHashMap;
+use data::{write_pointer, DataLayout, PendingDataReloc, PendingSymbolRef, DEFAULT_DATA_ALIGNMENT};
+pub use error::{ErrorKind, TranslationError, TranslationResult};
+use fnv::{FnvHashMap, FnvHashSet};
+pub use isa::WasmIsa;
+use locals::ValueLocals;
+pub use memory::{MemoryConfig, MemoryImport, PAGE_SIZE};
 use optable::OperandTable;
-use relooper::{reloop, ShapedBlock};
+use precompiled::{BodyEnvironment, BodyReloc, BodyRelocTarget, PrecompiledBody, RelocValue};
+use relooper::{reloop, BranchMode, MultipleBlock, ShapedBlock, SimpleBlock};
+use stack::StackFrame;
+pub use stack::{StackConfig, StackPlacement};
+use structure::{DominatorStructure, Node};
+pub use structure::{IrreducibleControlFlow, Structurer};
+use table::FunctionTable;
+use target_lexicon::Triple;
 use wabt::wasm2wat;
 use walrus::{
-    ir::{BinaryOp, InstrSeqId},
-    DataKind, FunctionBuilder, InstrSeqBuilder, LocalId, MemoryId, Module as WalrusModule,
-    ModuleConfig, ModuleLocals, ValType,
+    ir::{BinaryOp, Const, Instr, InstrSeqId, Value},
+    ActiveData, ActiveDataLocation, DataKind, FunctionBuilder, GlobalId, InitExpr, InstrSeqBuilder,
+    LocalId, MemoryId, Module as WalrusModule, ModuleConfig, ModuleLocals, ModuleTypes, ValType,
 };
 
 use crate::conversions::{
-    block::{build_wasm_block, BranchInstr, CanBranchTo},
+    block::{build_wasm_block, Branch, BranchInstr, CanBranchTo},
     sig::wasm_of_sig,
     ty,
 };
 
+/// The address in linear memory at which the first data object is placed.
+///
+/// Address zero is never assigned to a data object, so that null pointers can
+/// be distinguished from pointers to data.
+///
+/// If the stack is placed before the data objects, the first data object is
+/// placed after the stack instead.
+pub const DATA_START: u64 = 1024;
+
+/// The WebAssembly module from which functions declared with
+/// [`Linkage::Import`] are imported, unless another module has been chosen using
+/// [`WasmModule::set_import_module`].
+pub const DEFAULT_IMPORT_MODULE: &str = "env";
+
 /// A WebAssembly module.
 pub struct WasmModule {
+    /// The target (wasm32 or wasm64).
+    isa: WasmIsa,
     /// data we are receiving from Cranelift
     decls: ModuleDeclarations,
     /// data we are creating with Walrus.
@@ -44,48 +88,238 @@ pub struct WasmModule {
     /// configuration from Cranelift.
     #[allow(unused)]
     config: ModuleConfig,
-    #[allow(unused)]
+    /// The linear memory in which data objects are placed.
     memory_id: MemoryId,
+    /// The configuration of the linear memory.
+    memory_config: MemoryConfig,
+    /// The global which stores the stack pointer.
+    stack_pointer: GlobalId,
     /// Maps Cranelift functions to Walrus functions.
     functions: FnvHashMap<FuncId, walrus::FunctionId>,
-    /// Maps Cranelift data items to Walrus data items.
+    /// The functions which have been defined (and so cannot be defined again).
+    defined_functions: FnvHashSet<FuncId>,
+    /// Maps Cranelift data items to the Walrus (active) data segments which
+    /// initialise them. Data items which are initialised with zeros do not
+    /// need a data segment.
     data: FnvHashMap<DataId, walrus::DataId>,
+    /// The addresses of data items in linear memory.
+    data_layout: DataLayout,
+    /// Pointers to data items which have not yet been defined (and so do not
+    /// yet have an address).
+    pending_data_relocs: Vec<PendingDataReloc>,
+    /// References (in functions) to data items which have not yet been
+    /// defined.
+    pending_symbol_refs: Vec<PendingSymbolRef>,
+    /// The WebAssembly module which each imported function should be imported
+    /// from (if it should not be imported from [`DEFAULT_IMPORT_MODULE`]).
+    import_modules: FnvHashMap<String, String>,
+    /// The table used to call functions indirectly.
+    table: FunctionTable,
+    /// Whether the non-trapping float-to-int conversions may be used.
+    nontrapping_fptoint: bool,
+    /// The algorithm used to structure the control flow of functions.
+    structurer: Structurer,
+    /// How the dominator tree structurer handles irreducible control flow.
+    irreducible_control_flow: IrreducibleControlFlow,
 }
 
 impl WasmModule {
-    /// Constructs a new WebAssembly module.
-    ///
-    /// todo: check the target isa (or maybe don't take it as a parameter, and
-    /// generate it instead)
-    pub fn new(config: ModuleConfig) -> Self {
-        // if !matches!(
-        //     isa.triple().binary_format,
-        //     target_lexicon::BinaryFormat::Wasm
-        // ) {
-        //     panic!(
-        //         "only WebAssembly is supported! for other targets, you may want to
-        // look at     `cranelift_object`."
-        //     )
-        // }
+    /// Constructs a new WebAssembly module for the target `triple` (which must
+    /// be a wasm32 or wasm64 triple) with the Cranelift settings `flags`, which
+    /// uses the linear memory described by `memory`.
+    pub fn new(
+        triple: Triple,
+        flags: settings::Flags,
+        config: ModuleConfig,
+        memory: MemoryConfig,
+    ) -> ModuleResult<Self> {
+        let isa = WasmIsa::new(triple, flags)?;
+        memory.validate(isa.pointer_type())?;
 
         let mut module = WalrusModule::default();
 
-        let memory_id = module.memories.add_local(false, 1000, None);
+        let memory_id = memory.create(&mut module);
+        let stack_top = match isa.pointer_type() {
+            ir::types::I64 => Value::I64(memory.stack.top() as i64),
+            _ => Value::I32(memory.stack.top() as i32),
+        };
+        let stack_pointer = module.globals.add_local(
+            ty::wasm_of_pointer(isa.pointer_type()),
+            true,
+            InitExpr::Value(stack_top),
+        );
+        let table = FunctionTable::new(&mut module);
 
-        Self {
+        Ok(Self {
+            isa,
             decls: Default::default(),
             module,
             config,
             memory_id,
+            stack_pointer,
             functions: Default::default(),
+            defined_functions: Default::default(),
             data: Default::default(),
+            data_layout: DataLayout::new(memory.data_start()),
+            pending_data_relocs: Vec::new(),
+            pending_symbol_refs: Vec::new(),
+            import_modules: Default::default(),
+            table,
+            memory_config: memory,
+            nontrapping_fptoint: true,
+            structurer: Structurer::Relooper,
+            irreducible_control_flow: IrreducibleControlFlow::Dispatch,
+        })
+    }
+
+    /// Returns the address in linear memory of a data object, or `None` if the
+    /// data object has not (yet) been defined.
+    pub fn data_address(&self, data: DataId) -> Option<u64> {
+        self.data_layout.address_of(data)
+    }
+
+    /// The type of a pointer.
+    fn pointer_type(&self) -> ir::Type {
+        self.isa.pointer_type()
+    }
+
+    /// Returns an error if the function `func_id` has already been defined.
+    fn check_not_defined(&self, func_id: FuncId) -> ModuleResult<()> {
+        if self.defined_functions.contains(&func_id) {
+            let decl = self.decls.get_function_decl(func_id);
+            return Err(ModuleError::DuplicateDefinition(decl.name.clone()));
+        }
+        Ok(())
+    }
+
+    /// Finds the Walrus function which a relocation refers to.
+    fn resolve_function_reloc(&self, name: &ir::ExternalName) -> ModuleResult<walrus::FunctionId> {
+        match name {
+            ir::ExternalName::User { namespace: 0, .. } => self
+                .functions
+                .get(&FuncId::from_name(name))
+                .copied()
+                .ok_or_else(|| ModuleError::Undeclared(name.to_string())),
+            _ => Err(ModuleError::Backend(anyhow!(
+                "cannot resolve a reference to {} (only module functions are supported)",
+                name
+            ))),
+        }
+    }
+
+    /// Finds the data object which a relocation refers to (which must not be
+    /// imported, as imported data objects do not have an address).
+    fn resolve_data_reloc(&self, name: &ir::ExternalName) -> ModuleResult<DataId> {
+        let id = match name {
+            ir::ExternalName::User { namespace: 1, .. } => DataId::from_name(name),
+            _ => {
+                return Err(ModuleError::Backend(anyhow!(
+                    "cannot resolve a reference to {} (only module data objects are supported)",
+                    name
+                )))
+            }
+        };
+
+        let decl = self
+            .decls
+            .get_data_objects()
+            .find(|(data, _)| *data == id)
+            .map(|(_, decl)| decl)
+            .ok_or_else(|| ModuleError::Undeclared(name.to_string()))?;
+
+        if decl.linkage == Linkage::Import {
+            return Err(ModuleError::Backend(anyhow!(
+                "cannot take the address of the imported data object `{}`",
+                decl.name
+            )));
         }
+
+        Ok(id)
+    }
+
+    /// Exports the table through which functions are called indirectly, so
+    /// that the host can call Cranelift function pointers.
+    pub fn export_function_table(&mut self, name: &str) {
+        self.module.exports.add(name, self.table.id);
+    }
+
+    /// Returns the index of the function in the function table (i.e. the value
+    /// of a Cranelift function pointer to it), adding it to the table if
+    /// necessary.
+    ///
+    /// note: this function panics if `func` has not been declared.
+    pub fn function_table_index(&mut self, func: FuncId) -> u32 {
+        let id = self
+            .functions
+            .get(&func)
+            .expect("function was never declared");
+        self.table.index_of(*id)
+    }
+
+    /// Sets the WebAssembly module from which the function called `name` will
+    /// be imported (when it is declared with [`Linkage::Import`]).
+    ///
+    /// This must be called before the function is declared; functions for
+    /// which no module has been set are imported from
+    /// [`DEFAULT_IMPORT_MODULE`].
+    pub fn set_import_module(&mut self, name: impl Into<String>, module: impl Into<String>) {
+        self.import_modules.insert(name.into(), module.into());
+    }
+
+    /// Sets whether the saturating float-to-int conversions (`fcvt_to_sint_sat`
+    /// and `fcvt_to_uint_sat`) may use the instructions from the non-trapping
+    /// float-to-int conversions proposal (which are used by default). If they
+    /// may not, the float is compared against the bounds of the integer type
+    /// before it is converted, so that only WebAssembly 1.0 instructions are
+    /// needed.
+    ///
+    /// This applies to the functions which are defined afterwards.
+    pub fn set_nontrapping_fptoint(&mut self, enabled: bool) {
+        self.nontrapping_fptoint = enabled;
+    }
+
+    /// Sets the algorithm used to structure the control flow of functions (the
+    /// relooper is used by default).
+    ///
+    /// This applies to the functions which are defined afterwards.
+    pub fn set_structurer(&mut self, structurer: Structurer) {
+        self.structurer = structurer;
+    }
+
+    /// Sets how [`Structurer::Dominators`] handles loops which can be entered
+    /// at more than one of their blocks (by default, it dispatches on a label).
+    ///
+    /// This applies to the functions which are defined afterwards.
+    pub fn set_irreducible_control_flow(&mut self, irreducible: IrreducibleControlFlow) {
+        self.irreducible_control_flow = irreducible;
     }
 
     /// Emit the generated  a series of bytes (which can be interpreted as a
     /// WebAssembly module).
-    pub fn emit(&mut self) -> Vec<u8> {
-        self.module.emit_wasm()
+    ///
+    /// This fails if a data object or function contains a pointer to a data
+    /// object which was declared, but never defined.
+    pub fn emit(&mut self) -> ModuleResult<Vec<u8>> {
+        if let Some(reloc) = self.pending_data_relocs.first() {
+            return Err(ModuleError::Backend(anyhow!(
+                "`{}` contains a pointer to `{}`, which was never defined",
+                self.decls.get_data_decl(reloc.source).name,
+                self.decls.get_data_decl(reloc.target).name
+            )));
+        }
+        if let Some(symbol_ref) = self.pending_symbol_refs.first() {
+            return Err(ModuleError::Backend(anyhow!(
+                "a function refers to `{}`, which was never defined",
+                self.decls.get_data_decl(symbol_ref.target).name
+            )));
+        }
+        self.table.finish(&mut self.module);
+        let wasm = self.module.emit_wasm();
+        if self.memory_config.memory64 {
+            Ok(memory64::rewrite_memory64(&wasm))
+        } else {
+            Ok(wasm)
+        }
     }
 
     /// Emit WebAssembly code in the WebAssembly text format. The code generated
@@ -94,9 +328,9 @@ impl WasmModule {
     ///
     /// todo: at present this function calls a C++ library under the hood which
     /// will make compiling this to wasm a pain; remove this
-    pub fn emit_wat(&mut self) -> String {
-        let wasm = self.emit();
-        wasm2wat(&wasm).unwrap()
+    pub fn emit_wat(&mut self) -> ModuleResult<String> {
+        let wasm = self.emit()?;
+        Ok(wasm2wat(&wasm).unwrap())
     }
 
     /// Writes the current module to a graphviz dot file which can be used to
@@ -110,7 +344,33 @@ impl WasmModule {
 
 impl CraneliftModule for WasmModule {
     fn isa(&self) -> &dyn TargetIsa {
-        panic!("the WebAssembly ISA still needs to be defined");
+        &self.isa
+    }
+
+    // the following methods are overridden because `<dyn
+    // TargetIsa>::default_call_conv` panics for wasm32
+
+    fn target_config(&self) -> TargetFrontendConfig {
+        self.isa.frontend_config()
+    }
+
+    fn make_context(&self) -> Context {
+        let mut ctx = Context::new();
+        ctx.func.signature.call_conv = self.isa.default_call_conv();
+        ctx
+    }
+
+    fn clear_context(&self, ctx: &mut Context) {
+        ctx.clear();
+        ctx.func.signature.call_conv = self.isa.default_call_conv();
+    }
+
+    fn make_signature(&self) -> ir::Signature {
+        ir::Signature::new(self.isa.default_call_conv())
+    }
+
+    fn clear_signature(&self, sig: &mut ir::Signature) {
+        sig.clear(self.isa.default_call_conv());
     }
 
     fn declarations(&self) -> &ModuleDeclarations {
@@ -123,12 +383,36 @@ impl CraneliftModule for WasmModule {
         linkage: Linkage,
         signature: &ir::Signature,
     ) -> ModuleResult<FuncId> {
+        // check that the function can be represented before declaring it
+        let (params, ret) = wasm_of_sig(signature.clone())?;
+        if let Linkage::Preemptible | Linkage::Hidden = linkage {
+            return Err(ModuleError::Backend(anyhow!(
+                "`{}` cannot be declared with {:?} linkage",
+                name,
+                linkage
+            )));
+        }
+
         let (clif_id, _) = self.decls.declare_function(name, linkage, signature)?;
 
-        let (params, ret) = wasm_of_sig(signature.clone());
+        // functions may be declared more than once (e.g. once in each function
+        // which calls them), but should only appear once in the module
+        if self.functions.contains_key(&clif_id) {
+            return Ok(clif_id);
+        }
 
         match linkage {
-            Linkage::Import => todo!(),
+            Linkage::Import => {
+                let ty = self.module.types.add(&params, &ret);
+                let module = self
+                    .import_modules
+                    .get(name)
+                    .map(String::as_str)
+                    .unwrap_or(DEFAULT_IMPORT_MODULE);
+                let (import, _) = self.module.add_import_func(module, name, ty);
+                self.module.funcs.get_mut(import).name = Some(name.to_string());
+                self.functions.insert(clif_id, import);
+            }
             Linkage::Local => {
                 let mut builder = FunctionBuilder::new(&mut self.module.types, &params, &ret);
                 builder.name(name.to_string());
@@ -136,7 +420,7 @@ impl CraneliftModule for WasmModule {
                 let local = builder.finish(vec![], &mut self.module.funcs);
                 self.functions.insert(clif_id, local);
             }
-            Linkage::Preemptible | Linkage::Hidden => unimplemented!(),
+            Linkage::Preemptible | Linkage::Hidden => unreachable!(),
             Linkage::Export => {
                 let mut builder = FunctionBuilder::new(&mut self.module.types, &params, &ret);
                 builder.name(name.to_string());
@@ -156,19 +440,17 @@ impl CraneliftModule for WasmModule {
 
     fn declare_data(
         &mut self,
-        _name: &str,
-        _linkage: Linkage,
+        name: &str,
+        linkage: Linkage,
         writable: bool,
         tls: bool,
     ) -> ModuleResult<DataId> {
-        self.decls.declare_anonymous_data(writable, tls)
+        let (id, _) = self.decls.declare_data(name, linkage, writable, tls)?;
+        Ok(id)
     }
 
     fn declare_anonymous_data(&mut self, writable: bool, tls: bool) -> ModuleResult<DataId> {
-        let clif_data_id = self.decls.declare_anonymous_data(writable, tls)?;
-        let walrus_data_id = self.module.data.add(DataKind::Passive, Vec::new());
-        self.data.insert(clif_data_id, walrus_data_id);
-        Ok(clif_data_id)
+        self.decls.declare_anonymous_data(writable, tls)
     }
 
     fn define_function(
@@ -180,98 +462,155 @@ impl CraneliftModule for WasmModule {
     ) -> ModuleResult<ModuleCompiledFunction> {
         log::trace!("started compiling function with id {:#?}", func_id);
 
+        let pointer_type = self.pointer_type();
+
+        let decl = self.decls.get_function_decl(func_id);
         let id = self
             .functions
             .get(&func_id)
-            .expect("function declared but never defined!");
+            .ok_or_else(|| ModuleError::Undeclared(decl.name.clone()))?;
 
         log::trace!("module details: {:#?}", self.module);
 
-        // retrieve WebAssembly function
-        let func = self.module.funcs.get_mut(*id);
-
-        log::trace!("found function: {:#?}", func);
-        let mut builder = match func.kind {
-            walrus::FunctionKind::Import(_) => unreachable!(),
-            walrus::FunctionKind::Local(ref mut loc) => loc.builder_mut().func_body(),
-            walrus::FunctionKind::Uninitialized(_) => unreachable!(),
-        };
+        if let walrus::FunctionKind::Import(_) = self.module.funcs.get(*id).kind {
+            return Err(ModuleError::InvalidImportDefinition(decl.name.clone()));
+        }
+        self.check_not_defined(func_id)?;
 
         // set up Cranelift
         let mut cursor = FuncCursor::new(&mut ctx.func);
 
-        let operand_table = OperandTable::fill(&mut cursor, &mut self.module.locals);
+        let entry = cursor.layout().entry_block().ok_or_else(|| {
+            TranslationError::new(ErrorKind::Unsupported(format!(
+                "`{}` has no blocks",
+                decl.name
+            )))
+        })?;
+
+        let operand_table = OperandTable::fill(&mut cursor, &mut self.module.locals)?;
 
         log::trace!("computed operand table: {:#?}", operand_table);
 
+        let frame = StackFrame::layout(cursor.func, &mut self.module.locals, pointer_type);
+
+        // the parameters of the entry block are the arguments of the function
+        let mut args = Vec::new();
+        for param in cursor.func.dfg.block_params(entry) {
+            match operand_table
+                .block_params
+                .get(&entry)
+                .and_then(|params| params.get(param))
+            {
+                Some(locals) => args.extend_from_slice(locals.ids()),
+                None => {
+                    let ty = cursor.func.dfg.value_type(*param);
+                    let locals = ValueLocals::new(&mut self.module.locals, ty)?;
+                    args.extend_from_slice(locals.ids());
+                }
+            }
+        }
+
+        // retrieve WebAssembly function
+        let func = self.module.funcs.get_mut(*id);
+        let func_ty = func.ty();
+
+        log::trace!("found function: {:#?}", func);
+        let mut builder = match func.kind {
+            walrus::FunctionKind::Local(ref mut loc) => {
+                loc.args = args;
+                loc.builder_mut().func_body()
+            }
+            walrus::FunctionKind::Import(_) | walrus::FunctionKind::Uninitialized(_) => {
+                unreachable!()
+            }
+        };
+
         // todo: check if function is empty!
-        let blocks: Vec<_> = cursor
-            .func
-            .layout
-            .blocks()
-            .map(|block| block.clone())
-            .collect();
+        let blocks: Vec<_> = cursor.func.layout.blocks().collect();
+
+        let structure = match self.structurer {
+            Structurer::Dominators => Some(DominatorStructure::compute(
+                &mut cursor,
+                self.irreducible_control_flow,
+            )),
+            Structurer::Relooper => None,
+        };
+        log::trace!("structured control flow using dominators: {:#?}", structure);
 
         // note: the relooper crate does not have much documentation, but the original
         // Emscripten paper explains it quite well: https://dl.acm.org/doi/10.1145/2048147.2048224
         // also available at https://github.com/emscripten-core/emscripten/blob/main/docs/paper.pdf
-        let mut relooper_blocks = Vec::new();
-
-        for block in &blocks {
-            let mut branches = vec![];
-            cursor.goto_top(*block);
-
-            while let Some(inst) = cursor.next_inst() {
-                match cursor.func.dfg.analyze_branch(inst) {
-                    BranchInfo::NotABranch => (),
-                    BranchInfo::SingleDest(block, _) => {
-                        branches.push(block.as_u32());
-                    }
-                    BranchInfo::Table(_, _) => {
-                        todo!()
-                    }
-                }
-            }
-
-            relooper_blocks.push((block.as_u32(), branches))
-        }
+        let structured = match structure {
+            Some(_) => None,
+            None => {
+                let relooper_blocks = relooper_input(&mut cursor, &blocks);
 
-        log::trace!("generated relooper input: {:#?}", relooper_blocks);
+                log::trace!("generated relooper input: {:#?}", relooper_blocks);
 
-        let first = cursor.func.layout.entry_block().unwrap().as_u32();
+                let first = entry.as_u32();
 
-        let structured = reloop(relooper_blocks, first);
+                let structured = reloop(relooper_blocks, first);
 
-        log::trace!("recovered control flow: {:#?}", structured);
+                log::trace!("recovered control flow: {:#?}", structured);
+                Some(structured)
+            }
+        };
 
-        let (mut block_to_seq, mut loop_to_block, mut multi_to_block) =
-            (Default::default(), Default::default(), Default::default());
+        let (mut block_to_seq, mut multi_to_block) = (Default::default(), Default::default());
 
         let mut locals: FnvHashMap<_, _> = Default::default();
 
-        let params = cursor
-            .func
-            .dfg
-            .block_params(cursor.layout().entry_block().unwrap());
-        for each in params {
-            let ty = cursor.func.dfg.value_type(*each);
-            let local_id = self.module.locals.add(ty::wasm_of_cranelift(ty));
-            locals.insert(*each, local_id);
+        // if the function cannot be translated, the changes made to the module
+        // are undone (so that the module can still be used)
+        let (table_len, pending_symbol_refs_len) =
+            (self.table.len(), self.pending_symbol_refs.len());
+
+        if let Some(ref frame) = frame {
+            frame.build_prologue(self.stack_pointer, &mut builder);
         }
 
         let mut translator = IndividualFunctionTranslator::new(
             &mut self.module.locals,
             &mut cursor,
             &mut block_to_seq,
-            &mut loop_to_block,
             &mut multi_to_block,
             &operand_table,
             &mut locals,
+            &self.functions,
+            &mut self.table,
+            &mut self.module.types,
+            &self.decls,
+            &self.data_layout,
+            &mut self.pending_symbol_refs,
+            *id,
+            self.memory_id,
+            pointer_type,
+            frame.as_ref(),
+            self.stack_pointer,
+            self.nontrapping_fptoint,
+            self.memory_config.shared,
         );
 
-        translator.compile_structured(&mut builder, &structured, None, has_next(&structured));
+        translator.structure = structure.as_ref();
+
+        let result = match structured {
+            Some(ref structured) => translator.compile_structured(&mut builder, structured),
+            None => translator.compile_dominator_tree(&mut builder),
+        };
         builder.unreachable();
 
+        if let Err(e) = result {
+            self.table.truncate(table_len);
+            self.pending_symbol_refs.truncate(pending_symbol_refs_len);
+            let ty = self.module.types.get(func_ty);
+            let (params, results) = (ty.params().to_vec(), ty.results().to_vec());
+            let loc = self.module.funcs.get_mut(*id).kind.unwrap_local_mut();
+            *loc.builder_mut() = FunctionBuilder::new(&mut self.module.types, &params, &results);
+            loc.args = vec![];
+            return Err(e.into());
+        }
+
+        self.defined_functions.insert(func_id);
         log::trace!("finished compiling func with id {:#?}", func_id);
 
         Ok(ModuleCompiledFunction {
@@ -280,27 +619,270 @@ impl CraneliftModule for WasmModule {
         })
     }
 
+    /// Defines a function using the encoding of a WebAssembly function body
+    /// (see the `precompiled` module for the format of the body and of the
+    /// relocations).
     fn define_function_bytes(
         &mut self,
-        _func: FuncId,
-        _bytes: &[u8],
-        _relocs: &[cranelift_module::RelocRecord],
+        func_id: FuncId,
+        bytes: &[u8],
+        relocs: &[cranelift_module::RelocRecord],
     ) -> ModuleResult<ModuleCompiledFunction> {
-        todo!()
+        let decl = self.decls.get_function_decl(func_id);
+        if !decl.linkage.is_definable() {
+            return Err(ModuleError::InvalidImportDefinition(decl.name.clone()));
+        }
+        self.check_not_defined(func_id)?;
+        // bodies are validated by walrus, which does not support 64-bit
+        // memories
+        if self.memory_config.memory64 {
+            return Err(ModuleError::Backend(anyhow!(
+                "`{}` cannot be defined from bytes, as the memory is a 64-bit memory",
+                decl.name
+            )));
+        }
+        let id = *self
+            .functions
+            .get(&func_id)
+            .ok_or_else(|| ModuleError::Undeclared(decl.name.clone()))?;
+        let (params, results) = wasm_of_sig(decl.signature.clone())?;
+
+        let relocs = relocs
+            .iter()
+            .map(|reloc| {
+                let target = match reloc.name {
+                    ir::ExternalName::User { namespace: 1, .. } => {
+                        BodyRelocTarget::Data(self.resolve_data_reloc(&reloc.name)?)
+                    }
+                    _ => {
+                        let func = self.resolve_function_reloc(&reloc.name)?;
+                        let signature = &self
+                            .decls
+                            .get_function_decl(FuncId::from_name(&reloc.name))
+                            .signature;
+                        let (params, results) = wasm_of_sig(signature.clone())?;
+                        BodyRelocTarget::Function(func, params, results)
+                    }
+                };
+                Ok(BodyReloc {
+                    offset: reloc.offset as usize,
+                    kind: reloc.reloc,
+                    target,
+                    addend: reloc.addend,
+                })
+            })
+            .collect::<ModuleResult<Vec<_>>>()?;
+
+        let env = BodyEnvironment {
+            memory_config: &self.memory_config,
+            memory: self.memory_id,
+            table: self.table.id,
+            stack_pointer: self.stack_pointer,
+            pointer_type: ty::wasm_of_pointer(self.pointer_type()),
+        };
+        let body = PrecompiledBody::parse(id, &params, &results, bytes, &relocs, &env)?;
+
+        // the body is valid, so the values of the relocated constants can be
+        // computed (which may add functions to the table)
+        let values = relocs
+            .iter()
+            .filter_map(|reloc| {
+                if body.is_call(reloc.offset) {
+                    return None;
+                }
+                let value = match reloc.target {
+                    BodyRelocTarget::Function(func, ..) => {
+                        RelocValue::Known(self.table.index_of(func) as i64)
+                    }
+                    BodyRelocTarget::Data(data) => match self.data_layout.address_of(data) {
+                        Some(address) => RelocValue::Known(address as i64 + reloc.addend),
+                        None => RelocValue::Pending(data, reloc.addend),
+                    },
+                };
+                Some((reloc.offset, value))
+            })
+            .collect::<Vec<_>>();
+
+        let target = match self.module.funcs.get_mut(id).kind {
+            walrus::FunctionKind::Local(ref mut loc) => loc,
+            walrus::FunctionKind::Import(_) | walrus::FunctionKind::Uninitialized(_) => {
+                unreachable!()
+            }
+        };
+        let pending = body.copy_into(
+            target,
+            &mut self.module.locals,
+            &mut self.module.types,
+            &values,
+            &env,
+        );
+        for (seq, position, target, addend) in pending {
+            self.pending_symbol_refs.push(PendingSymbolRef {
+                func: id,
+                seq,
+                position,
+                ty: env.pointer_type,
+                target,
+                addend,
+            });
+        }
+
+        self.defined_functions.insert(func_id);
+
+        Ok(ModuleCompiledFunction {
+            size: bytes.len() as u32,
+        })
     }
 
     fn define_data(&mut self, data: DataId, data_ctx: &DataContext) -> ModuleResult<()> {
-        let walrus_id = self.data.get(&data).unwrap();
-        let data = self.module.data.get_mut(*walrus_id);
+        let decl = self.decls.get_data_decl(data);
+        if !decl.linkage.is_definable() {
+            return Err(ModuleError::InvalidImportDefinition(decl.name.clone()));
+        }
+        if self.data_layout.address_of(data).is_some() {
+            return Err(ModuleError::DuplicateDefinition(decl.name.clone()));
+        }
 
         let desc = data_ctx.description();
+        let pointer_bytes = self.pointer_type().bytes() as usize;
+
+        // linear memory is zeroed when it is created, so data which is
+        // initialised with zeros (and contains no pointers) only needs to have
+        // space reserved for it
+        let (size, contents) = match &desc.init {
+            cranelift_module::Init::Uninitialized => {
+                return Err(ModuleError::Backend(anyhow!(
+                    "the contents of `{}` were never set",
+                    decl.name
+                )))
+            }
+            cranelift_module::Init::Zeros { size }
+                if desc.function_relocs.is_empty() && desc.data_relocs.is_empty() =>
+            {
+                (*size, None)
+            }
+            cranelift_module::Init::Zeros { size } => (*size, Some(vec![0; *size])),
+            cranelift_module::Init::Bytes { contents } => (contents.len(), Some(contents.to_vec())),
+        };
+
+        // resolve all the relocations before modifying the module, so that it
+        // is left unchanged if any of them are invalid
+        let check_offset = |offset: u32| {
+            if offset as usize + pointer_bytes > size {
+                Err(ModuleError::Backend(anyhow!(
+                    "relocation at offset {} is outside of the data object (which is {} bytes)",
+                    offset,
+                    size
+                )))
+            } else {
+                Ok(offset as usize)
+            }
+        };
+        let function_relocs = desc
+            .function_relocs
+            .iter()
+            .map(|(offset, func_ref)| {
+                let func = self.resolve_function_reloc(&desc.function_decls[*func_ref])?;
+                Ok((check_offset(*offset)?, func))
+            })
+            .collect::<ModuleResult<Vec<_>>>()?;
+        let data_relocs = desc
+            .data_relocs
+            .iter()
+            .map(|(offset, global_value, addend)| {
+                let target = self.resolve_data_reloc(&desc.data_decls[*global_value])?;
+                Ok((check_offset(*offset)?, target, *addend))
+            })
+            .collect::<ModuleResult<Vec<_>>>()?;
+
+        let align = desc.align.unwrap_or(DEFAULT_DATA_ALIGNMENT);
+        if self.data_layout.end_after(size as u64, align) > self.memory_config.initial_bytes() {
+            return Err(ModuleError::Backend(anyhow!(
+                "`{}` does not fit into the initial memory ({} pages)",
+                decl.name,
+                self.memory_config.initial_pages
+            )));
+        }
+        // walrus can only place data segments at 32-bit addresses (which only
+        // limits 64-bit memories)
+        if self.data_layout.end_after(size as u64, align) > u32::MAX as u64 + 1 {
+            return Err(ModuleError::Backend(anyhow!(
+                "`{}` cannot be placed in the first 4 GiB of memory",
+                decl.name
+            )));
+        }
+
+        let address = self.data_layout.allocate(data, size as u64, align);
+        log::trace!("placed data object {:?} at address {}", data, address);
 
-        match &desc.init {
-            cranelift_module::Init::Uninitialized => todo!(),
-            cranelift_module::Init::Zeros { size } => {
-                data.value = vec![0; *size];
+        if let Some(mut contents) = contents {
+            // function pointers are indices into the function table
+            for (offset, func) in function_relocs {
+                let index = self.table.index_of(func);
+                write_pointer(&mut contents, offset, index as u64, pointer_bytes);
             }
-            cranelift_module::Init::Bytes { contents } => data.value = contents.to_vec(),
+
+            for (offset, target, addend) in data_relocs {
+                match self.data_layout.address_of(target) {
+                    Some(target_address) => {
+                        let pointer = (target_address as i64 + addend) as u64;
+                        write_pointer(&mut contents, offset, pointer, pointer_bytes);
+                    }
+                    None => self.pending_data_relocs.push(PendingDataReloc {
+                        source: data,
+                        offset,
+                        target,
+                        addend,
+                    }),
+                }
+            }
+
+            let walrus_id = self.module.data.add(
+                DataKind::Active(ActiveData {
+                    memory: self.memory_id,
+                    location: ActiveDataLocation::Absolute(address as u32),
+                }),
+                contents,
+            );
+            self.module
+                .memories
+                .get_mut(self.memory_id)
+                .data_segments
+                .insert(walrus_id);
+            self.data.insert(data, walrus_id);
+        }
+
+        // now that this data object has an address, we can write it into any data
+        // objects which were defined earlier and point to it
+        let (resolved, pending) = std::mem::take(&mut self.pending_data_relocs)
+            .into_iter()
+            .partition::<Vec<_>, _>(|reloc| reloc.target == data);
+        self.pending_data_relocs = pending;
+        for reloc in resolved {
+            let segment = self.module.data.get_mut(self.data[&reloc.source]);
+            let pointer = (address as i64 + reloc.addend) as u64;
+            write_pointer(&mut segment.value, reloc.offset, pointer, pointer_bytes);
+        }
+
+        // and do the same for functions which refer to it
+        let (resolved, pending) = std::mem::take(&mut self.pending_symbol_refs)
+            .into_iter()
+            .partition::<Vec<_>, _>(|symbol_ref| symbol_ref.target == data);
+        self.pending_symbol_refs = pending;
+        for symbol_ref in resolved {
+            let pointer = address as i64 + symbol_ref.addend;
+            let value = match symbol_ref.ty {
+                ValType::I64 => Value::I64(pointer),
+                _ => Value::I32(pointer as i32),
+            };
+            let func = self
+                .module
+                .funcs
+                .get_mut(symbol_ref.func)
+                .kind
+                .unwrap_local_mut();
+            func.block_mut(symbol_ref.seq).instrs[symbol_ref.position].0 =
+                Instr::Const(Const { value });
         }
 
         Ok(())
@@ -320,150 +902,460 @@ pub struct IndividualFunctionTranslator<'clif> {
     // todo: is this one even needed?
     #[allow(unused)]
     block_to_seq: &'clif mut FnvHashMap<Block, InstrSeqId>,
-    /// stores the `InstrSeqId`s that loops correspond to.
-    loop_to_block: &'clif mut FnvHashMap<u16, InstrSeqId>,
     #[allow(unused)]
     multi_to_block: &'clif mut FnvHashMap<u16, InstrSeqId>,
     operand_table: &'clif OperandTable,
-    locals: &'clif mut FnvHashMap<ir::Value, LocalId>,
+    locals: &'clif mut FnvHashMap<ir::Value, ValueLocals>,
+    /// Maps Cranelift functions to the Walrus functions which can be called.
+    functions: &'clif FnvHashMap<FuncId, walrus::FunctionId>,
+    /// The table through which functions are called indirectly.
+    table: &'clif mut FunctionTable,
+    /// The function types of the Walrus module (needed for indirect calls).
+    types: &'clif mut ModuleTypes,
+    /// The declarations of the functions and data objects in the module.
+    decls: &'clif ModuleDeclarations,
+    /// The addresses of the data objects which have been defined so far.
+    data_layout: &'clif DataLayout,
+    /// References to data objects which have not yet been defined.
+    pending_symbol_refs: &'clif mut Vec<PendingSymbolRef>,
+    /// The function which is being compiled.
+    func_id: walrus::FunctionId,
+    /// The linear memory of the module.
+    memory_id: MemoryId,
+    /// The type of a pointer into linear memory.
+    pointer_type: ir::Type,
+    /// The stack frame of the function (if it has any stack slots).
+    frame: Option<&'clif StackFrame>,
+    /// The global which stores the stack pointer.
+    stack_pointer: GlobalId,
+    /// Whether the non-trapping float-to-int conversions may be used.
+    nontrapping_fptoint: bool,
+    /// Whether the linear memory is shared (so that atomic instructions may be
+    /// used).
+    shared_memory: bool,
+    /// The blocks (and loops) enclosing the code being compiled, which can be
+    /// branched to.
+    branch_targets: Vec<BranchTarget>,
+    /// The local which holds the label (if it has been needed so far).
+    label: Option<LocalId>,
+    /// Where each block is placed, if the control flow is structured using the
+    /// dominator tree (rather than by the relooper).
+    structure: Option<&'clif DominatorStructure>,
+    /// The WebAssembly block (or loop) enclosing the code being compiled which
+    /// is branched to in order to reach each node (if the control flow is
+    /// structured using the dominator tree).
+    node_targets: FnvHashMap<Node, InstrSeqId>,
 }
 
 impl<'clif> IndividualFunctionTranslator<'clif> {
+    #[allow(clippy::too_many_arguments)]
     fn new(
         module: &'clif mut ModuleLocals,
         cursor: &'clif mut FuncCursor<'clif>,
         block_to_seq: &'clif mut FnvHashMap<Block, InstrSeqId>,
-        loop_to_block: &'clif mut FnvHashMap<u16, InstrSeqId>,
         multi_to_block: &'clif mut FnvHashMap<u16, InstrSeqId>,
         operand_table: &'clif OperandTable,
-        locals: &'clif mut FnvHashMap<ir::Value, LocalId>,
+        locals: &'clif mut FnvHashMap<ir::Value, ValueLocals>,
+        functions: &'clif FnvHashMap<FuncId, walrus::FunctionId>,
+        table: &'clif mut FunctionTable,
+        types: &'clif mut ModuleTypes,
+        decls: &'clif ModuleDeclarations,
+        data_layout: &'clif DataLayout,
+        pending_symbol_refs: &'clif mut Vec<PendingSymbolRef>,
+        func_id: walrus::FunctionId,
+        memory_id: MemoryId,
+        pointer_type: ir::Type,
+        frame: Option<&'clif StackFrame>,
+        stack_pointer: GlobalId,
+        nontrapping_fptoint: bool,
+        shared_memory: bool,
     ) -> Self {
         Self {
             module_locals: module,
             cursor,
             block_to_seq,
-            loop_to_block,
             multi_to_block,
             operand_table,
             locals,
+            functions,
+            table,
+            types,
+            decls,
+            data_layout,
+            pending_symbol_refs,
+            func_id,
+            memory_id,
+            pointer_type,
+            frame,
+            stack_pointer,
+            nontrapping_fptoint,
+            shared_memory,
+            branch_targets: Vec::new(),
+            label: None,
+            structure: None,
+            node_targets: Default::default(),
         }
     }
 
+    /// Compiles the control flow recovered by the relooper.
+    ///
+    /// Every branch leaves a WebAssembly block which is followed by the code of
+    /// its destination (or continues a loop which starts with it), found using
+    /// [`Self::branch_targets`]. If that code is a multiple block (which
+    /// handles several destinations), the label is set to the destination
+    /// before branching, so that the multiple block can dispatch on it.
     fn compile_structured(
         &mut self,
         builder: &mut InstrSeqBuilder,
         structured: &ShapedBlock<u32>,
-        label: Option<LocalId>,
-        next_exists: bool,
-    ) {
+    ) -> TranslationResult<()> {
         log::trace!("compiling structured: {:#?}", structured);
         match structured {
-            // a straight-line sequence of blocks
-            // we just translate each one in turn
+            // a block, followed by the blocks which it branches to (its
+            // `immediate`), and then by the blocks in which those branches merge
+            // back together (its `next`)
             ShapedBlock::Simple(simple) => {
                 log::trace!("structured was a simple block: {:#?}", simple);
-                if let Some(ref immediate) = simple.immediate {
-                    let local = self.module_locals.add(ValType::I32);
-
-                    let mut locally_computed = FnvHashMap::default();
-                    if let ShapedBlock::Multiple(block) = immediate.as_ref() {
-                        for each in &block.handled {
-                            for label in &each.labels {
-                                locally_computed.insert(*label, BranchInstr::SetLocal(local));
-                            }
+                self.compile_followed_by(builder, simple.next.as_deref(), |this, builder| {
+                    match simple.immediate.as_deref() {
+                        Some(ShapedBlock::Multiple(multiple))
+                            if this.is_switch(simple, multiple) =>
+                        {
+                            this.compile_switch(builder, simple, multiple)
+                        }
+                        immediate => {
+                            this.compile_followed_by(builder, immediate, |this, builder| {
+                                this.compile_simple(builder, simple)
+                            })
                         }
                     }
-
-                    log::trace!(
-                        "computed blocks that can be branched to with a label: {:#?}",
-                        locally_computed
-                    );
-
-                    build_wasm_block(
-                        Block::from_u32(simple.label),
-                        self,
-                        builder,
-                        &CanBranchTo {
-                            from_relooper: &simple.branches,
-                            locally_computed,
-                        },
-                    );
-
-                    self.compile_structured(builder, immediate, Some(local), has_next(&structured));
-                } else {
-                    build_wasm_block(
-                        Block::from_u32(simple.label),
-                        self,
-                        builder,
-                        &CanBranchTo {
-                            from_relooper: &simple.branches,
-                            locally_computed: Default::default(),
-                        },
-                    );
-                }
-
-                if let Some(ref next) = simple.next {
-                    self.compile_structured(builder, next, None, has_next(next));
-                }
+                })
             }
+            // breaking out of a loop leaves the block around it (which is
+            // followed by the `next` of the loop, if the destination is there)
             ShapedBlock::Loop(l) => {
                 log::trace!("structured was a loop: {:#?}", l);
-
-                builder.loop_(None, |builder: &mut InstrSeqBuilder| {
-                    self.loop_to_block.insert(l.loop_id, builder.id());
-                    log::trace!("added `{:?}={:?}`", l.loop_id, builder.id());
-                    self.compile_structured(builder, &l.inner, None, has_next(&l.inner));
-                });
-
-                if let Some(ref next) = l.next {
-                    self.compile_structured(builder, next, None, has_next(&next));
-                }
+                self.compile_followed_by(builder, l.next.as_deref(), |this, builder| {
+                    let seq = builder.dangling_instr_seq(None).id();
+                    this.branch_targets
+                        .push(BranchTarget::new(&l.inner, seq, Some(l.loop_id)));
+                    let inner = this.compile_structured(&mut builder.instr_seq(seq), &l.inner);
+                    this.branch_targets.pop();
+                    inner?;
+                    builder.instr(walrus::ir::Loop { seq });
+                    Ok(())
+                })
             }
             // `match`/`if` + `else if` chain
             ShapedBlock::Multiple(m) => {
                 log::trace!("structured was a multiple block: {:#?}", m);
 
                 // note: `HandledBlock::break_after` means "can this entry reach another entry"
+                // an entry does so by setting the label and branching out of its
+                // `if`, so that the following entries are considered
 
-                let label = label.unwrap();
-
-                // now we run the if-else sequence
-                // once for each node in the state machine
-                for each in &m.handled {
-                    // and then once for each possible label
-                    for val in &each.labels {
+                let label = self.label();
+                for (i, each) in m.handled.iter().enumerate() {
+                    // check if the `label` local matches any of the entry's ids
+                    for (j, val) in each.labels.iter().enumerate() {
                         builder
-                            // check if the `label` local matches the id in question
                             .local_get(label)
                             .i32_const(*val as i32)
-                            .binop(BinaryOp::I32Eq)
-                            .if_else(
-                                None,
-                                |builder| {
-                                    self.compile_structured(
-                                        builder,
-                                        &each.inner,
-                                        None,
-                                        has_next(&each.inner),
-                                    );
-                                },
-                                |_| {},
-                            );
+                            .binop(BinaryOp::I32Eq);
+                        if j > 0 {
+                            builder.binop(BinaryOp::I32Or);
+                        }
                     }
+                    let later = m.handled[i + 1..]
+                        .iter()
+                        .flat_map(|later| later.labels.iter().copied())
+                        .collect();
+
+                    let mut inner = Ok(());
+                    builder.if_else(
+                        None,
+                        |then| {
+                            self.branch_targets.push(BranchTarget {
+                                labels: later,
+                                seq: then.id(),
+                                dispatch: true,
+                                loop_id: None,
+                            });
+                            inner = self.compile_structured(then, &each.inner);
+                            self.branch_targets.pop();
+                        },
+                        |_| {},
+                    );
+                    inner?;
                 }
+                Ok(())
+            }
+        }
+    }
 
-                if !next_exists {
-                    builder.unreachable();
-                }
+    /// Compiles `body` into a WebAssembly block which is followed by
+    /// `following` (if anything follows it), so that branches to `following`
+    /// leave the block.
+    fn compile_followed_by(
+        &mut self,
+        builder: &mut InstrSeqBuilder,
+        following: Option<&ShapedBlock<u32>>,
+        body: impl FnOnce(&mut Self, &mut InstrSeqBuilder) -> TranslationResult<()>,
+    ) -> TranslationResult<()> {
+        let following = match following {
+            Some(following) => following,
+            None => return body(self, builder),
+        };
+
+        let seq = builder.dangling_instr_seq(None).id();
+        self.branch_targets
+            .push(BranchTarget::new(following, seq, None));
+        let inner = body(self, &mut builder.instr_seq(seq));
+        self.branch_targets.pop();
+        inner?;
+        builder.instr(walrus::ir::Block { seq });
+
+        self.compile_structured(builder, following)
+    }
+
+    /// Compiles the code of a simple block, once the way in which each of the
+    /// blocks which it branches to is reached has been worked out.
+    fn compile_simple(
+        &mut self,
+        builder: &mut InstrSeqBuilder,
+        simple: &SimpleBlock<u32>,
+    ) -> TranslationResult<()> {
+        let block = Block::from_u32(simple.label);
+
+        let mut destinations = FnvHashMap::default();
+        for (inst, destination) in branches(self.cursor, block) {
+            let mode = simple.branches.get(&destination.as_u32());
+            let method = self
+                .resolve_branch(destination.as_u32(), mode)
+                .ok_or_else(|| {
+                    let kind = match mode {
+                        Some(mode) => ErrorKind::UnsupportedBranchMode(format!("{:?}", mode)),
+                        None => {
+                            ErrorKind::Unsupported(format!("`{}` cannot be reached", destination))
+                        }
+                    };
+                    TranslationError::new(kind).at(inst, self.cursor.func)
+                })?;
+            destinations.insert(destination.as_u32(), Branch::Br(method));
+        }
+
+        log::trace!(
+            "computed how to branch to each destination: {:#?}",
+            destinations
+        );
+
+        build_wasm_block(block, self, builder, &CanBranchTo { destinations })
+    }
+
+    /// Works out how a branch reaches `destination`, to which the relooper
+    /// branches using `mode` (there is no mode if the destination directly
+    /// follows the block which branches to it).
+    fn resolve_branch(
+        &mut self,
+        destination: u32,
+        mode: Option<&BranchMode>,
+    ) -> Option<BranchInstr> {
+        let target = match mode {
+            // back to the start of a loop, which may be nested in several
+            // other loops
+            Some(BranchMode::LoopContinue(id)) | Some(BranchMode::LoopContinueIntoMulti(id)) => {
+                self.branch_targets
+                    .iter()
+                    .rev()
+                    .find(|target| target.loop_id == Some(*id))
             }
+            // out of (any number of) loops, or to the code in which branches
+            // merge back together, which is reached by leaving the innermost
+            // block which it follows (in the `IntoMulti` and `SetLabelAndBreak`
+            // cases, that code dispatches on the label)
+            Some(BranchMode::LoopBreak(_))
+            | Some(BranchMode::LoopBreakIntoMulti(_))
+            | Some(BranchMode::MergedBranch)
+            | Some(BranchMode::MergedBranchIntoMulti)
+            | Some(BranchMode::SetLabelAndBreak)
+            | None => self
+                .branch_targets
+                .iter()
+                .rev()
+                .find(|target| target.labels.contains(&destination)),
+        }?;
+
+        let (seq, dispatch) = (target.seq, target.dispatch);
+        Some(BranchInstr {
+            label: dispatch.then(|| self.label()),
+            seq,
+        })
+    }
+
+    /// The local which holds the label of the block which is being branched to
+    /// (on which multiple blocks dispatch).
+    fn label(&mut self) -> LocalId {
+        let module_locals = &mut self.module_locals;
+        *self
+            .label
+            .get_or_insert_with(|| module_locals.add(ValType::I32))
+    }
+
+    /// Whether `simple` ends with a `br_table` whose destinations are handled
+    /// by `multiple` (which immediately follows it) in a way which
+    /// [`Self::compile_switch`] can translate.
+    fn is_switch(&self, simple: &SimpleBlock<u32>, multiple: &MultipleBlock<u32>) -> bool {
+        let func = &self.cursor.func;
+        let terminator = func.layout.last_inst(Block::from_u32(simple.label));
+        let ends_with_br_table =
+            terminator.map(|inst| func.dfg[inst].opcode()) == Some(ir::Opcode::BrTable);
+        // an entry which dispatches on the label (a loop with several entries)
+        // has to be told which of them was branched to
+        ends_with_br_table
+            && !multiple.handled.is_empty()
+            && multiple
+                .handled
+                .iter()
+                .all(|handled| !dispatches(&handled.inner))
+    }
+
+    /// Compiles a block ending with a `br_table`, followed by the multiple block
+    /// which handles its destinations.
+    ///
+    /// Rather than setting a label which is then compared with each entry of
+    /// the multiple block, the block is nested in a WebAssembly block for each
+    /// entry, whose code follows the end of that block, so that the `br_table`
+    /// branches straight to it:
+    ///
+    /// ```text
+    /// block $entry_1
+    ///   block $entry_0
+    ///     ;; the block, ending with `br_table $entry_0 $entry_1 ...`
+    ///   end
+    ///   ;; the first entry
+    /// end
+    /// ;; the second entry
+    /// ```
+    fn compile_switch(
+        &mut self,
+        builder: &mut InstrSeqBuilder,
+        simple: &SimpleBlock<u32>,
+        multiple: &MultipleBlock<u32>,
+    ) -> TranslationResult<()> {
+        let seqs = multiple
+            .handled
+            .iter()
+            .map(|_| builder.dangling_instr_seq(None).id())
+            .collect::<Vec<_>>();
+
+        let depth = self.branch_targets.len();
+        for (handled, seq) in multiple.handled.iter().zip(&seqs) {
+            self.branch_targets
+                .push(BranchTarget::new(&handled.inner, *seq, None));
+        }
+        let inner = self.compile_simple(&mut builder.instr_seq(seqs[0]), simple);
+        self.branch_targets.truncate(depth);
+        inner?;
+
+        // each entry branches away at its end, so none of them continues into
+        // the next
+        for (i, handled) in multiple.handled.iter().enumerate() {
+            let outer = seqs.get(i + 1).copied().unwrap_or_else(|| builder.id());
+            let mut outer = builder.instr_seq(outer);
+            outer.instr(walrus::ir::Block { seq: seqs[i] });
+            self.compile_structured(&mut outer, &handled.inner)?;
+        }
+        Ok(())
+    }
+}
+
+/// A WebAssembly block which is followed by the code of the blocks with the
+/// given labels (or a loop which starts with it): branching to it reaches them.
+struct BranchTarget {
+    labels: Vec<u32>,
+    seq: InstrSeqId,
+    /// Whether the code dispatches on the label (i.e. it is a multiple block).
+    dispatch: bool,
+    /// The id which the relooper gave the loop (if `seq` is a loop).
+    loop_id: Option<u16>,
+}
+
+impl BranchTarget {
+    /// The target which `shaped` follows (or starts, if `seq` is a loop).
+    fn new(shaped: &ShapedBlock<u32>, seq: InstrSeqId, loop_id: Option<u16>) -> Self {
+        Self {
+            labels: entries(shaped),
+            seq,
+            dispatch: dispatches(shaped),
+            loop_id,
         }
     }
 }
 
-fn has_next(shaped: &ShapedBlock<u32>) -> bool {
+/// The labels of the blocks which can be branched to in order to enter
+/// `shaped`.
+fn entries(shaped: &ShapedBlock<u32>) -> Vec<u32> {
+    match shaped {
+        ShapedBlock::Simple(s) => vec![s.label],
+        ShapedBlock::Loop(l) => entries(&l.inner),
+        ShapedBlock::Multiple(m) => m
+            .handled
+            .iter()
+            .flat_map(|handled| handled.labels.iter().copied())
+            .collect(),
+    }
+}
+
+/// Whether `shaped` starts by dispatching on the label.
+fn dispatches(shaped: &ShapedBlock<u32>) -> bool {
     match shaped {
-        ShapedBlock::Simple(s) => s.next.is_some(),
-        ShapedBlock::Loop(l) => l.next.is_some(),
-        ShapedBlock::Multiple(_) => false,
+        ShapedBlock::Simple(_) => false,
+        ShapedBlock::Loop(l) => dispatches(&l.inner),
+        ShapedBlock::Multiple(_) => true,
+    }
+}
+
+/// The branches in `block`, together with the blocks which they branch to (a
+/// `br_table` branches to each entry of its jump table, and to its default).
+fn branches(cursor: &mut FuncCursor, block: Block) -> Vec<(ir::Inst, Block)> {
+    let mut branches = Vec::new();
+    cursor.goto_top(block);
+
+    while let Some(inst) = cursor.next_inst() {
+        match cursor.func.dfg.analyze_branch(inst) {
+            BranchInfo::NotABranch => (),
+            BranchInfo::SingleDest(destination, _) => branches.push((inst, destination)),
+            BranchInfo::Table(table, default) => {
+                let entries = cursor.func.jump_tables[table].iter().copied();
+                branches.extend(
+                    entries
+                        .chain(default)
+                        .map(|destination| (inst, destination)),
+                );
+            }
+        }
+    }
+
+    branches
+}
+
+/// Computes the input to the relooper: every block, together with the blocks
+/// which it branches to (each of which is only listed once).
+fn relooper_input(cursor: &mut FuncCursor, blocks: &[Block]) -> Vec<(u32, Vec<u32>)> {
+    let mut relooper_blocks = Vec::new();
+
+    for block in blocks {
+        let mut labels = Vec::new();
+        for (_, destination) in branches(cursor, *block) {
+            if !labels.contains(&destination.as_u32()) {
+                labels.push(destination.as_u32());
+            }
+        }
+        relooper_blocks.push((block.as_u32(), labels))
     }
+
+    // the relooper expects the blocks to be sorted by their number (rather
+    // than in layout order)
+    relooper_blocks.sort_unstable_by_key(|(label, _)| *label);
+    relooper_blocks
 }
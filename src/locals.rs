@@ -0,0 +1,42 @@
+//! The locals in which Cranelift values are stored.
+
+use cranelift_codegen::ir;
+use walrus::{InstrSeqBuilder, LocalId, ModuleLocals};
+
+use crate::{conversions::ty::wasm_values_of_cranelift, error::TranslationResult};
+
+/// The locals which store a Cranelift value: one for most values, but two for
+/// an `i128` (which is represented by its low and then its high half).
+#[derive(Debug, Clone)]
+pub(crate) struct ValueLocals(Vec<LocalId>);
+
+impl ValueLocals {
+    /// Adds locals to the module which can store a value of type `ty`.
+    pub(crate) fn new(module: &mut ModuleLocals, ty: ir::Type) -> TranslationResult<Self> {
+        Ok(Self(
+            wasm_values_of_cranelift(ty)?
+                .into_iter()
+                .map(|ty| module.add(ty))
+                .collect(),
+        ))
+    }
+
+    /// The locals, in the order in which the value is pushed onto the stack.
+    pub(crate) fn ids(&self) -> &[LocalId] {
+        &self.0
+    }
+
+    /// Pushes the value onto the stack.
+    pub(crate) fn get(&self, builder: &mut InstrSeqBuilder) {
+        for local in &self.0 {
+            builder.local_get(*local);
+        }
+    }
+
+    /// Pops the value from the top of the stack into the locals.
+    pub(crate) fn set(&self, builder: &mut InstrSeqBuilder) {
+        for local in self.0.iter().rev() {
+            builder.local_set(*local);
+        }
+    }
+}
@@ -0,0 +1,142 @@
+//! Configuration of the linear memory used by a module.
+
+use anyhow::anyhow;
+use cranelift_codegen::ir;
+use cranelift_module::{ModuleError, ModuleResult};
+use walrus::{MemoryId, Module as WalrusModule};
+
+use crate::{
+    stack::{StackConfig, StackPlacement, STACK_ALIGNMENT},
+    DATA_START,
+};
+
+/// The size of a WebAssembly page (in bytes).
+pub const PAGE_SIZE: u64 = 65536;
+
+/// Describes the linear memory which data objects (and anything else which
+/// Cranelift code stores in memory) are placed in.
+#[derive(Debug, Clone)]
+pub struct MemoryConfig {
+    /// The initial size of the memory (in pages).
+    pub initial_pages: u32,
+    /// The maximum size of the memory (in pages), if there is one.
+    pub maximum_pages: Option<u32>,
+    /// If set, the memory is imported from the host rather than being defined
+    /// in the module.
+    pub import: Option<MemoryImport>,
+    /// If set, the memory is exported under this name.
+    pub export: Option<String>,
+    /// Whether the memory is shared (which requires the threads proposal, and
+    /// a maximum size).
+    pub shared: bool,
+    /// Whether this is a 64-bit memory (which requires the memory64 proposal,
+    /// and a 64-bit pointer type).
+    pub memory64: bool,
+    /// The stack which is placed in this memory.
+    pub stack: StackConfig,
+}
+
+/// The location from which a memory is imported.
+#[derive(Debug, Clone)]
+pub struct MemoryImport {
+    /// The module from which the memory is imported.
+    pub module: String,
+    /// The name of the memory in that module.
+    pub name: String,
+}
+
+impl Default for MemoryConfig {
+    fn default() -> Self {
+        Self {
+            initial_pages: 1000,
+            maximum_pages: None,
+            import: None,
+            export: Some("memory".to_string()),
+            shared: false,
+            memory64: false,
+            stack: StackConfig::default(),
+        }
+    }
+}
+
+impl MemoryConfig {
+    /// The size of the memory (in bytes) when it is created.
+    pub(crate) fn initial_bytes(&self) -> u64 {
+        self.initial_pages as u64 * PAGE_SIZE
+    }
+
+    /// The address at which the first data object can be placed.
+    pub(crate) fn data_start(&self) -> u64 {
+        match self.stack.placement {
+            StackPlacement::BeforeData => DATA_START.max(self.stack.top()),
+            StackPlacement::Top(_) => DATA_START,
+        }
+    }
+
+    /// Checks that this memory can be used by code with the given pointer type.
+    #[allow(clippy::manual_is_multiple_of)] // (which needs Rust 1.87)
+    pub(crate) fn validate(&self, pointer_type: ir::Type) -> ModuleResult<()> {
+        let invalid =
+            |reason: &str| Err(ModuleError::Backend(anyhow!("invalid memory: {}", reason)));
+
+        if let Some(maximum) = self.maximum_pages {
+            if maximum < self.initial_pages {
+                return invalid("the maximum size is smaller than the initial size");
+            }
+        }
+        if !self.memory64 && self.maximum_pages.unwrap_or(self.initial_pages) > 65536 {
+            return invalid("32-bit memories cannot be larger than 65536 pages");
+        }
+        if self.shared && self.maximum_pages.is_none() {
+            return invalid("shared memories must have a maximum size");
+        }
+        if self.memory64 != (pointer_type == ir::types::I64) {
+            return invalid(&format!(
+                "the pointer type is {}, so the memory must be {}",
+                pointer_type,
+                if pointer_type == ir::types::I64 {
+                    "a 64-bit memory"
+                } else {
+                    "a 32-bit memory"
+                }
+            ));
+        }
+        if self.stack.top() % STACK_ALIGNMENT != 0 {
+            return invalid(&format!(
+                "the top of the stack must be aligned to {} bytes",
+                STACK_ALIGNMENT
+            ));
+        }
+        if self.stack.size > self.stack.top() || self.stack.top() > self.initial_bytes() {
+            return invalid("the stack does not fit into the initial memory");
+        }
+
+        Ok(())
+    }
+
+    /// Adds the memory to the module.
+    pub(crate) fn create(&self, module: &mut WalrusModule) -> MemoryId {
+        let memory_id = match &self.import {
+            Some(import) => {
+                module
+                    .add_import_memory(
+                        &import.module,
+                        &import.name,
+                        self.shared,
+                        self.initial_pages,
+                        self.maximum_pages,
+                    )
+                    .0
+            }
+            None => module
+                .memories
+                .add_local(self.shared, self.initial_pages, self.maximum_pages),
+        };
+
+        if let Some(name) = &self.export {
+            module.exports.add(name, memory_id);
+        }
+
+        memory_id
+    }
+}
@@ -0,0 +1,198 @@
+//! Support for 64-bit memories, which Walrus cannot encode.
+//!
+//! The module is built (and emitted by Walrus) with a 32-bit memory, and the
+//! emitted module is then rewritten so that its memory is a 64-bit one. Only
+//! the type of the memory and the offsets of the active data segments (which
+//! Walrus encodes as `i32.const`) need to change: the code already uses 64-bit
+//! addresses, and a `memarg` is encoded in the same way for both.
+
+use crate::precompiled::{leb128, section};
+
+const IMPORT_SECTION: u8 = 2;
+const MEMORY_SECTION: u8 = 5;
+const DATA_SECTION: u8 = 11;
+
+/// The flag in the limits of a memory type which makes it a 64-bit memory.
+const MEMORY64_FLAG: u8 = 0x04;
+
+/// Rewrites `wasm` (which was emitted by Walrus) so that its memory is a
+/// 64-bit memory.
+pub(crate) fn rewrite_memory64(wasm: &[u8]) -> Vec<u8> {
+    // the header (the magic number and the version) is left as it is
+    let mut output = wasm[..8].to_vec();
+    let mut reader = Reader::new(wasm);
+    reader.position = 8;
+    while reader.position < wasm.len() {
+        let id = reader.byte();
+        let size = reader.leb128() as usize;
+        let contents = &wasm[reader.position..reader.position + size];
+        reader.position += size;
+        let contents = match id {
+            IMPORT_SECTION => rewrite_imports(contents),
+            MEMORY_SECTION => rewrite_memories(contents),
+            DATA_SECTION => rewrite_data(contents),
+            _ => contents.to_vec(),
+        };
+        section(&mut output, id, &contents);
+    }
+    output
+}
+
+/// Marks every imported memory as a 64-bit memory.
+fn rewrite_imports(contents: &[u8]) -> Vec<u8> {
+    let mut reader = Reader::new(contents);
+    for _ in 0..reader.leb128() {
+        // the module and the name of the import
+        for _ in 0..2 {
+            let length = reader.leb128() as usize;
+            reader.position += length;
+        }
+        match reader.byte() {
+            // a function (with the index of its type)
+            0x00 => {
+                reader.leb128();
+            }
+            // a table (with the type of its elements)
+            0x01 => {
+                reader.byte();
+                reader.limits();
+            }
+            0x02 => reader.memory_limits(),
+            // a global (with its type and mutability)
+            0x03 => reader.position += 2,
+            kind => unreachable!("walrus emitted an import of kind {:#x}", kind),
+        }
+    }
+    reader.finish()
+}
+
+/// Marks every memory defined in the module as a 64-bit memory.
+fn rewrite_memories(contents: &[u8]) -> Vec<u8> {
+    let mut reader = Reader::new(contents);
+    for _ in 0..reader.leb128() {
+        reader.memory_limits();
+    }
+    reader.finish()
+}
+
+/// Replaces the offset of every active data segment with an `i64.const`.
+fn rewrite_data(contents: &[u8]) -> Vec<u8> {
+    let mut output = Vec::new();
+    let mut reader = Reader::new(contents);
+    let count = reader.leb128();
+    leb128(&mut output, count);
+    for _ in 0..count {
+        let start = reader.position;
+        let kind = reader.leb128();
+        if kind == 2 {
+            // the index of the memory
+            reader.leb128();
+        }
+        output.extend_from_slice(&contents[start..reader.position]);
+
+        // passive segments do not have an offset
+        if kind != 1 {
+            // walrus only places data segments at absolute addresses, which it
+            // encodes as signed 32-bit integers
+            assert_eq!(reader.byte(), 0x41, "expected `i32.const`");
+            let offset = reader.sleb128() as u32;
+            assert_eq!(reader.byte(), 0x0b, "expected `end`");
+            output.push(0x42);
+            sleb128(&mut output, offset as i64);
+            output.push(0x0b);
+        }
+
+        let start = reader.position;
+        let length = reader.leb128() as usize;
+        reader.position += length;
+        output.extend_from_slice(&contents[start..reader.position]);
+    }
+    output
+}
+
+/// Reads the contents of a section, noting the memory types in it.
+struct Reader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+    /// The positions of the flags of the limits of memory types.
+    memory_flags: Vec<usize>,
+}
+
+impl<'a> Reader<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        Self {
+            bytes,
+            position: 0,
+            memory_flags: Vec::new(),
+        }
+    }
+
+    fn byte(&mut self) -> u8 {
+        let byte = self.bytes[self.position];
+        self.position += 1;
+        byte
+    }
+
+    fn leb128(&mut self) -> u64 {
+        let mut value = 0;
+        let mut shift = 0;
+        loop {
+            let byte = self.byte();
+            value |= ((byte & 0x7f) as u64) << shift;
+            shift += 7;
+            if byte & 0x80 == 0 {
+                return value;
+            }
+        }
+    }
+
+    fn sleb128(&mut self) -> i64 {
+        let mut value = 0;
+        let mut shift = 0;
+        loop {
+            let byte = self.byte();
+            value |= ((byte & 0x7f) as i64) << shift;
+            shift += 7;
+            if byte & 0x80 == 0 {
+                if shift < 64 && byte & 0x40 != 0 {
+                    value |= -1 << shift;
+                }
+                return value;
+            }
+        }
+    }
+
+    fn limits(&mut self) {
+        let flags = self.byte();
+        self.leb128();
+        if flags & 0x01 != 0 {
+            self.leb128();
+        }
+    }
+
+    fn memory_limits(&mut self) {
+        self.memory_flags.push(self.position);
+        self.limits();
+    }
+
+    /// Returns the section, with every memory marked as a 64-bit memory.
+    fn finish(self) -> Vec<u8> {
+        let mut contents = self.bytes.to_vec();
+        for position in self.memory_flags {
+            contents[position] |= MEMORY64_FLAG;
+        }
+        contents
+    }
+}
+
+fn sleb128(bytes: &mut Vec<u8>, mut value: i64) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if (value == 0 && byte & 0x40 == 0) || (value == -1 && byte & 0x40 != 0) {
+            bytes.push(byte);
+            return;
+        }
+        bytes.push(byte | 0x80);
+    }
+}
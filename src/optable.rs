@@ -3,9 +3,9 @@ use cranelift_codegen::{
     ir::{self, Block, InstInserterBase},
 };
 use fnv::{FnvHashMap, FnvHashSet};
-use walrus::{LocalId, ModuleLocals};
+use walrus::ModuleLocals;
 
-use crate::conversions::ty::wasm_of_cranelift;
+use crate::{error::TranslationResult, locals::ValueLocals};
 
 /// Describes the nature of the operand in question.
 ///
@@ -28,7 +28,7 @@ pub(crate) enum Operand {
 
 impl Operand {
     /// Retrieves the type of the operand from the provided table.
-    pub(crate) fn from_table<'ctx>(value: ir::Value, table: &OperandTable) -> Self {
+    pub(crate) fn from_table(value: ir::Value, table: &OperandTable) -> Self {
         Operand::try_from_table(value, table).unwrap()
     }
 
@@ -58,13 +58,25 @@ pub struct OperandTable {
     /// Values which should always be rematerialised.
     pub(crate) rematerialize: FnvHashSet<ir::Value>,
     /// Values which are passed as parameters to a block.
-    pub(crate) block_params: FnvHashMap<Block, FnvHashMap<ir::Value, LocalId>>,
+    pub(crate) block_params: FnvHashMap<Block, FnvHashMap<ir::Value, ValueLocals>>,
 }
 
 impl OperandTable {
+    /// Whether `value` is used more than once (and so is kept in a local,
+    /// rather than being computed where it is used).
+    pub(crate) fn is_normal_use(&self, value: ir::Value) -> bool {
+        matches!(
+            Operand::try_from_table(value, self),
+            Some(Operand::NormalUse(_))
+        )
+    }
+
     /// Computes the role of every [cranelift_codegen::ir::Value] in the
     /// provided program, and adds it to this table.
-    pub(crate) fn fill(cursor: &mut FuncCursor, module: &mut ModuleLocals) -> OperandTable {
+    pub(crate) fn fill(
+        cursor: &mut FuncCursor,
+        module: &mut ModuleLocals,
+    ) -> TranslationResult<OperandTable> {
         let mut value_uses: FnvHashMap<_, _> = Default::default();
         let mut rematerialize: FnvHashSet<_> = Default::default();
         let mut block_params: FnvHashMap<_, _> = Default::default();
@@ -72,30 +84,22 @@ impl OperandTable {
         let params = cursor
             .layout()
             .blocks()
-            .map(|block| cursor.layout().block_insts(block))
-            .flatten()
+            .flat_map(|block| cursor.layout().block_insts(block))
             .map(|inst| (inst, cursor.data_flow_graph().inst_args(inst)))
-            .map(|(inst, values)| values.iter().zip(std::iter::repeat(inst)))
-            .flatten();
+            .flat_map(|(inst, values)| values.iter().zip(std::iter::repeat(inst)));
 
-        for (value, _) in params {
+        for (value, inst) in params {
             let def = match cursor.data_flow_graph().value_def(*value) {
                 ir::ValueDef::Result(inst, _) => inst,
                 ir::ValueDef::Param(block, _) => {
                     log::trace!("got an argument for {:#?}", block);
-                    let ty = cursor.data_flow_graph().value_type(*value);
-                    let ty = wasm_of_cranelift(ty);
-                    let local = module.add(ty);
-                    block_params
-                        .entry(block)
-                        .and_modify(|map: &mut FnvHashMap<_, _>| {
-                            map.insert(*value, local);
-                        })
-                        .or_insert({
-                            let mut map = FnvHashMap::default();
-                            map.insert(*value, local);
-                            map
-                        });
+                    let locals_of: &mut FnvHashMap<_, _> = block_params.entry(block).or_default();
+                    if !locals_of.contains_key(value) {
+                        let ty = cursor.data_flow_graph().value_type(*value);
+                        let locals =
+                            ValueLocals::new(module, ty).map_err(|e| e.at(inst, cursor.func))?;
+                        locals_of.insert(*value, locals);
+                    }
                     log::trace!(
                         "entries for block now looks like: {:#?}",
                         block_params.get(&block)
@@ -106,24 +110,32 @@ impl OperandTable {
 
             let def = &cursor.data_flow_graph()[def];
             match def {
-                ir::InstructionData::Unary { opcode, arg: _ }
-                | ir::InstructionData::UnaryImm { opcode, imm: _ } => match opcode {
-                    ir::Opcode::Iconst => {
-                        rematerialize.insert(*value);
-                        continue;
-                    }
-                    _ => (),
-                },
+                ir::InstructionData::Unary {
+                    opcode: ir::Opcode::Iconst,
+                    ..
+                }
+                | ir::InstructionData::UnaryImm {
+                    opcode: ir::Opcode::Iconst,
+                    ..
+                } => {
+                    rematerialize.insert(*value);
+                    continue;
+                }
+                ir::InstructionData::UnaryIeee32 { .. }
+                | ir::InstructionData::UnaryIeee64 { .. } => {
+                    rematerialize.insert(*value);
+                    continue;
+                }
                 _ => (),
             }
 
             *value_uses.entry(*value).or_insert(0) += 1;
         }
 
-        Self {
+        Ok(Self {
             value_uses,
             rematerialize,
             block_params,
-        }
+        })
     }
 }
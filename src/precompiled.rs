@@ -0,0 +1,538 @@
+//! Links precompiled WebAssembly function bodies into the module.
+//!
+//! A precompiled body is the encoding of a function body as it appears in the
+//! code section (its locals followed by its instructions), without the leading
+//! size. In the body,
+//! - memory 0 is the linear memory of the module,
+//! - table 0 is the function table (see [`crate::WasmModule::export_function_table`]),
+//! - global 0 is the stack pointer.
+//!
+//! Functions and data objects are referred to through relocations. The
+//! relocated immediate must be a padded LEB128 (5 bytes for [`Reloc::Abs4`] and
+//! 10 bytes for [`Reloc::Abs8`]), and its meaning depends on the instruction:
+//! - the immediate of a `call` is the function which is called,
+//! - the immediate of an `i32.const` or an `i64.const` is the address of the
+//!   data object (plus the addend) or the table index of the function (i.e. a
+//!   Cranelift function pointer).
+//!
+//! The body is validated by wrapping it in a small module which imports the
+//! functions it calls (as well as the memory, table and stack pointer), parsing
+//! that module with Walrus, and then copying the function into our module.
+
+use anyhow::anyhow;
+use cranelift_codegen::binemit::Reloc;
+use cranelift_module::{DataId, ModuleError, ModuleResult};
+use fnv::{FnvHashMap, FnvHashSet};
+use walrus::{
+    ir::{
+        self, dfs_in_order, dfs_pre_order_mut, Instr, InstrLocId, InstrSeq, InstrSeqId, Value,
+        Visitor, VisitorMut,
+    },
+    FunctionId, FunctionKind, GlobalId, LocalFunction, LocalId, MemoryId, Module as WalrusModule,
+    ModuleConfig, ModuleLocals, ModuleTypes, TableId, TypeId, ValType,
+};
+
+use crate::MemoryConfig;
+
+const OP_CALL: u8 = 0x10;
+const OP_I32_CONST: u8 = 0x41;
+const OP_I64_CONST: u8 = 0x42;
+
+/// A relocation in a precompiled function body, whose target has been
+/// resolved.
+#[derive(Debug)]
+pub(crate) struct BodyReloc {
+    /// The offset (in the body) of the relocated immediate.
+    pub(crate) offset: usize,
+    /// The kind of relocation.
+    pub(crate) kind: Reloc,
+    /// The function or data object referred to.
+    pub(crate) target: BodyRelocTarget,
+    /// The value to add to the address of a data object.
+    pub(crate) addend: i64,
+}
+
+#[derive(Debug)]
+pub(crate) enum BodyRelocTarget {
+    /// A function in our module, with its (WebAssembly) parameters and
+    /// results.
+    Function(FunctionId, Vec<ValType>, Vec<ValType>),
+    Data(DataId),
+}
+
+/// The value of a relocated constant.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum RelocValue {
+    /// The value is known.
+    Known(i64),
+    /// The data object has not yet been defined, so the constant has to be
+    /// patched later.
+    Pending(DataId, i64),
+}
+
+/// The items of our module which the body may use.
+pub(crate) struct BodyEnvironment<'a> {
+    pub(crate) memory_config: &'a MemoryConfig,
+    pub(crate) memory: MemoryId,
+    pub(crate) table: TableId,
+    pub(crate) stack_pointer: GlobalId,
+    pub(crate) pointer_type: ValType,
+}
+
+/// A precompiled function body which has been validated (in its wrapper
+/// module).
+pub(crate) struct PrecompiledBody {
+    wrapper: WalrusModule,
+    /// Where the body starts in the wrapper module.
+    body_offset: usize,
+    /// The wrapper's function, and the function it is copied into.
+    function: (FunctionId, FunctionId),
+    /// Maps the functions imported by the wrapper to our functions.
+    imports: FnvHashMap<FunctionId, FunctionId>,
+    /// The offsets of the relocations which are applied to calls (rather than
+    /// to constants).
+    calls: Vec<usize>,
+}
+
+impl PrecompiledBody {
+    /// Validates `bytes` as the body of `func`, which has the given parameters
+    /// and results.
+    pub(crate) fn parse(
+        func: FunctionId,
+        params: &[ValType],
+        results: &[ValType],
+        bytes: &[u8],
+        relocs: &[BodyReloc],
+        env: &BodyEnvironment,
+    ) -> ModuleResult<Self> {
+        let mut bytes = bytes.to_vec();
+
+        // every function which is called is imported by the wrapper module, so
+        // that calls can be validated
+        let mut callees: Vec<(FunctionId, &[ValType], &[ValType])> = Vec::new();
+        let mut calls = Vec::new();
+        for reloc in relocs {
+            let width = match reloc.kind {
+                Reloc::Abs4 => 5,
+                Reloc::Abs8 => 10,
+                kind => return invalid(format!("unsupported relocation kind `{}`", kind)),
+            };
+            if reloc.offset == 0 || reloc.offset + width > bytes.len() {
+                return invalid(format!("relocation at {} is out of bounds", reloc.offset));
+            }
+            let immediate = &bytes[reloc.offset..reloc.offset + width];
+            if immediate[..width - 1].iter().any(|byte| byte & 0x80 == 0)
+                || immediate[width - 1] & 0x80 != 0
+            {
+                return invalid(format!(
+                    "relocation at {} is not a {}-byte LEB128",
+                    reloc.offset, width
+                ));
+            }
+
+            match (bytes[reloc.offset - 1], &reloc.target, width) {
+                (OP_CALL, BodyRelocTarget::Function(id, params, results), 5) => {
+                    let index = match callees.iter().position(|(callee, ..)| callee == id) {
+                        Some(index) => index,
+                        None => {
+                            callees.push((*id, params.as_slice(), results.as_slice()));
+                            callees.len() - 1
+                        }
+                    };
+                    bytes[reloc.offset..reloc.offset + width]
+                        .copy_from_slice(&padded_leb128(index as u32));
+                    calls.push(reloc.offset);
+                }
+                (OP_I32_CONST, _, 5) | (OP_I64_CONST, _, 10) => (),
+                (opcode, _, _) => {
+                    return invalid(format!(
+                        "relocation at {} cannot be applied to opcode {:#04x}",
+                        reloc.offset, opcode
+                    ))
+                }
+            }
+        }
+
+        let (wasm, body_offset) = wrapper_module(params, results, &callees, &bytes, env);
+        let wrapper = ModuleConfig::new()
+            .parse(&wasm)
+            .map_err(|e| ModuleError::Backend(anyhow!("invalid function body: {:#}", e)))?;
+
+        let (local, _) = wrapper
+            .funcs
+            .iter_local()
+            .next()
+            .expect("the wrapper module contains the function body");
+        let mut imports: FnvHashMap<_, _> = wrapper
+            .imports
+            .iter()
+            .filter_map(|import| match import.kind {
+                walrus::ImportKind::Function(id) => {
+                    // `wrapper_module` names the import of each callee after its
+                    // index in `callees`
+                    let index: usize = import.name.parse().unwrap();
+                    Some((id, callees[index].0))
+                }
+                _ => None,
+            })
+            .collect();
+        imports.insert(local, func);
+
+        let body = Self {
+            function: (local, func),
+            wrapper,
+            body_offset,
+            imports,
+            calls,
+        };
+
+        // the opcode before a relocation has to be an instruction (rather than
+        // e.g. a part of the immediate of another instruction)
+        let function = body.local_function();
+        let mut relocatable = Relocatable(Vec::new());
+        dfs_in_order(&mut relocatable, function, function.entry_block());
+        for reloc in relocs {
+            if !relocatable
+                .0
+                .contains(&(body.body_offset + reloc.offset - 1))
+            {
+                return invalid(format!(
+                    "relocation at {} does not refer to a call or a constant",
+                    reloc.offset
+                ));
+            }
+        }
+
+        Ok(body)
+    }
+
+    /// Whether the relocation at `offset` is applied to a call (and so has
+    /// already been applied).
+    pub(crate) fn is_call(&self, offset: usize) -> bool {
+        self.calls.contains(&offset)
+    }
+
+    fn local_function(&self) -> &LocalFunction {
+        match self.wrapper.funcs.get(self.function.0).kind {
+            FunctionKind::Local(ref local) => local,
+            _ => unreachable!(),
+        }
+    }
+
+    /// Copies the body into `target` (which must be a local function),
+    /// replacing relocated constants with the given values.
+    ///
+    /// Returns the constants which have to be patched once a data object is
+    /// defined, as `(seq, position, data, addend)`.
+    pub(crate) fn copy_into(
+        mut self,
+        target: &mut LocalFunction,
+        locals: &mut ModuleLocals,
+        types: &mut ModuleTypes,
+        relocs: &[(usize, RelocValue)],
+        env: &BodyEnvironment,
+    ) -> Vec<(InstrSeqId, usize, DataId, i64)> {
+        let WalrusModule {
+            funcs,
+            locals: wrapper_locals,
+            types: wrapper_types,
+            ..
+        } = &mut self.wrapper;
+
+        let mut remap = Remap {
+            wrapper_locals,
+            wrapper_types,
+            locals,
+            types,
+            local_map: Default::default(),
+            mapped: Default::default(),
+            functions: &self.imports,
+            env,
+            seqs: Vec::new(),
+        };
+
+        let function = match funcs.get_mut(self.function.0).kind {
+            FunctionKind::Local(ref mut local) => local,
+            _ => unreachable!(),
+        };
+        target.args = function.args.iter().map(|arg| remap.local(*arg)).collect();
+        let entry = function.entry_block();
+        dfs_pre_order_mut(&mut remap, function, entry);
+
+        // the entry block of the body becomes the entry block of the function,
+        // every other block has to be created
+        let mut seq_map = FnvHashMap::default();
+        seq_map.insert(entry, target.entry_block());
+        for seq in &remap.seqs {
+            if *seq != entry {
+                let ty = function.block(*seq).ty;
+                let new = target.builder_mut().dangling_instr_seq(ty).id();
+                seq_map.insert(*seq, new);
+            }
+        }
+
+        let mut pending = Vec::new();
+        for seq in &remap.seqs {
+            let new_seq = seq_map[seq];
+            for (instr, loc) in &function.block(*seq).instrs {
+                let mut instr = instr.clone();
+                remap_seqs(&mut instr, &seq_map);
+
+                // relocated constants
+                let offset = loc.data() as usize - self.body_offset + 1;
+                if let (Instr::Const(ir::Const { value }), Some((_, reloc))) = (
+                    &mut instr,
+                    relocs.iter().find(|(reloc, _)| *reloc == offset),
+                ) {
+                    let address = match *reloc {
+                        RelocValue::Known(address) => address,
+                        RelocValue::Pending(data, addend) => {
+                            let position = target.block(new_seq).instrs.len();
+                            pending.push((new_seq, position, data, addend));
+                            0
+                        }
+                    };
+                    *value = match value {
+                        Value::I64(_) => Value::I64(address),
+                        _ => Value::I32(address as i32),
+                    };
+                }
+
+                target.builder_mut().instr_seq(new_seq).instr(instr);
+            }
+        }
+
+        pending
+    }
+}
+
+/// Finds the (offsets of the) calls and constants in a function.
+struct Relocatable(Vec<usize>);
+
+impl<'instr> Visitor<'instr> for Relocatable {
+    fn visit_instr(&mut self, instr: &'instr Instr, loc: &'instr InstrLocId) {
+        if let Instr::Call(_) | Instr::Const(_) = instr {
+            self.0.push(loc.data() as usize);
+        }
+    }
+}
+
+/// Maps the items of the wrapper module to the items of our module.
+///
+/// note: Walrus visits the ids in each instruction twice, so ids which have
+/// already been mapped have to be left alone.
+struct Remap<'a> {
+    wrapper_locals: &'a ModuleLocals,
+    wrapper_types: &'a ModuleTypes,
+    locals: &'a mut ModuleLocals,
+    types: &'a mut ModuleTypes,
+    local_map: FnvHashMap<LocalId, LocalId>,
+    /// The locals and types of our module which have been mapped to.
+    mapped: (FnvHashSet<LocalId>, FnvHashSet<TypeId>),
+    functions: &'a FnvHashMap<FunctionId, FunctionId>,
+    env: &'a BodyEnvironment<'a>,
+    /// The instruction sequences of the body (in pre-order).
+    seqs: Vec<InstrSeqId>,
+}
+
+impl Remap<'_> {
+    fn local(&mut self, local: LocalId) -> LocalId {
+        if self.mapped.0.contains(&local) {
+            return local;
+        }
+        let (wrapper_locals, locals, mapped) =
+            (&self.wrapper_locals, &mut self.locals, &mut self.mapped.0);
+        *self.local_map.entry(local).or_insert_with(|| {
+            let new = locals.add(wrapper_locals.get(local).ty());
+            mapped.insert(new);
+            new
+        })
+    }
+}
+
+impl VisitorMut for Remap<'_> {
+    fn start_instr_seq_mut(&mut self, seq: &mut InstrSeq) {
+        self.seqs.push(seq.id());
+    }
+
+    fn visit_local_id_mut(&mut self, local: &mut LocalId) {
+        *local = self.local(*local);
+    }
+
+    fn visit_function_id_mut(&mut self, function: &mut FunctionId) {
+        if let Some(new) = self.functions.get(function) {
+            *function = *new;
+        }
+    }
+
+    fn visit_memory_id_mut(&mut self, memory: &mut MemoryId) {
+        *memory = self.env.memory;
+    }
+
+    fn visit_table_id_mut(&mut self, table: &mut TableId) {
+        *table = self.env.table;
+    }
+
+    fn visit_global_id_mut(&mut self, global: &mut GlobalId) {
+        // the only global which `wrapper_module` imports is `stack_pointer`
+        *global = self.env.stack_pointer;
+    }
+
+    fn visit_type_id_mut(&mut self, ty: &mut TypeId) {
+        if self.mapped.1.contains(ty) {
+            return;
+        }
+        let wrapper_ty = self.wrapper_types.get(*ty);
+        *ty = self.types.add(wrapper_ty.params(), wrapper_ty.results());
+        self.mapped.1.insert(*ty);
+    }
+}
+
+/// Replaces the instruction sequences which `instr` refers to.
+fn remap_seqs(instr: &mut Instr, seq_map: &FnvHashMap<InstrSeqId, InstrSeqId>) {
+    match instr {
+        Instr::Block(ir::Block { seq }) | Instr::Loop(ir::Loop { seq }) => *seq = seq_map[seq],
+        Instr::IfElse(ir::IfElse {
+            consequent,
+            alternative,
+        }) => {
+            *consequent = seq_map[consequent];
+            *alternative = seq_map[alternative];
+        }
+        Instr::Br(ir::Br { block }) | Instr::BrIf(ir::BrIf { block }) => *block = seq_map[block],
+        Instr::BrTable(ir::BrTable { blocks, default }) => {
+            for block in blocks.iter_mut() {
+                *block = seq_map[block];
+            }
+            *default = seq_map[default];
+        }
+        _ => (),
+    }
+}
+
+fn invalid<T>(reason: String) -> ModuleResult<T> {
+    Err(ModuleError::Backend(anyhow!(
+        "invalid function body: {}",
+        reason
+    )))
+}
+
+/// Encodes the module in which the body is validated, returning the module and
+/// the offset of the body in it.
+fn wrapper_module(
+    params: &[ValType],
+    results: &[ValType],
+    callees: &[(FunctionId, &[ValType], &[ValType])],
+    body: &[u8],
+    env: &BodyEnvironment,
+) -> (Vec<u8>, usize) {
+    let mut wasm = b"\0asm\x01\0\0\0".to_vec();
+
+    // types: one for each callee, followed by the type of the function
+    let mut types = Vec::new();
+    leb128(&mut types, callees.len() as u64 + 1);
+    for (_, params, results) in callees {
+        func_type(&mut types, params, results);
+    }
+    func_type(&mut types, params, results);
+    section(&mut wasm, 1, &types);
+
+    let mut imports = Vec::new();
+    leb128(&mut imports, callees.len() as u64 + 3);
+    for index in 0..callees.len() {
+        import(&mut imports, &index.to_string(), 0x00);
+        leb128(&mut imports, index as u64);
+    }
+    import(&mut imports, "memory", 0x02);
+    let memory = env.memory_config;
+    match (memory.maximum_pages, memory.shared) {
+        (Some(maximum), shared) => {
+            imports.push(if shared { 0x03 } else { 0x01 });
+            leb128(&mut imports, memory.initial_pages as u64);
+            leb128(&mut imports, maximum as u64);
+        }
+        (None, _) => {
+            imports.push(0x00);
+            leb128(&mut imports, memory.initial_pages as u64);
+        }
+    }
+    import(&mut imports, "table", 0x01);
+    imports.extend_from_slice(&[0x70, 0x00, 0x00]);
+    import(&mut imports, "stack_pointer", 0x03);
+    imports.extend_from_slice(&[val_type(env.pointer_type), 0x01]);
+    section(&mut wasm, 2, &imports);
+
+    let mut functions = Vec::new();
+    leb128(&mut functions, 1);
+    leb128(&mut functions, callees.len() as u64);
+    section(&mut wasm, 3, &functions);
+
+    let mut code = Vec::new();
+    leb128(&mut code, 1);
+    leb128(&mut code, body.len() as u64);
+    let body_in_code = code.len();
+    code.extend_from_slice(body);
+    let mut header = Vec::new();
+    leb128(&mut header, code.len() as u64);
+    let body_offset = wasm.len() + 1 + header.len() + body_in_code;
+    section(&mut wasm, 10, &code);
+
+    (wasm, body_offset)
+}
+
+pub(crate) fn section(wasm: &mut Vec<u8>, id: u8, contents: &[u8]) {
+    wasm.push(id);
+    leb128(wasm, contents.len() as u64);
+    wasm.extend_from_slice(contents);
+}
+
+fn import(imports: &mut Vec<u8>, name: &str, kind: u8) {
+    // the module name is empty
+    imports.push(0);
+    leb128(imports, name.len() as u64);
+    imports.extend_from_slice(name.as_bytes());
+    imports.push(kind);
+}
+
+fn func_type(types: &mut Vec<u8>, params: &[ValType], results: &[ValType]) {
+    types.push(0x60);
+    for list in [params, results] {
+        leb128(types, list.len() as u64);
+        types.extend(list.iter().map(|ty| val_type(*ty)));
+    }
+}
+
+fn val_type(ty: ValType) -> u8 {
+    match ty {
+        ValType::I32 => 0x7f,
+        ValType::I64 => 0x7e,
+        ValType::F32 => 0x7d,
+        ValType::F64 => 0x7c,
+        ValType::V128 => 0x7b,
+        ValType::Funcref => 0x70,
+        ValType::Externref => 0x6f,
+    }
+}
+
+pub(crate) fn leb128(bytes: &mut Vec<u8>, mut value: u64) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            bytes.push(byte);
+            return;
+        }
+        bytes.push(byte | 0x80);
+    }
+}
+
+fn padded_leb128(value: u32) -> [u8; 5] {
+    let mut bytes = [0; 5];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = ((value >> (7 * i)) & 0x7f) as u8;
+        if i < 4 {
+            *byte |= 0x80;
+        }
+    }
+    bytes
+}
@@ -0,0 +1,142 @@
+//! The shadow stack, in linear memory, on which Cranelift stack slots live.
+
+use cranelift_codegen::ir;
+use fnv::FnvHashMap;
+use walrus::{ir::BinaryOp, GlobalId, InstrSeqBuilder, LocalId, ModuleLocals};
+
+use crate::conversions::ty::wasm_of_pointer;
+
+/// The alignment of the stack pointer (at function boundaries).
+pub(crate) const STACK_ALIGNMENT: u64 = 16;
+
+/// Describes the stack which functions use for their stack slots.
+///
+/// The stack grows downwards (from its top towards address zero), with the
+/// current stack pointer stored in a global.
+#[derive(Debug, Clone)]
+pub struct StackConfig {
+    /// The size of the stack (in bytes).
+    pub size: u64,
+    /// Where the stack is placed in linear memory.
+    pub placement: StackPlacement,
+}
+
+/// Where the stack is placed in linear memory.
+#[derive(Debug, Clone)]
+pub enum StackPlacement {
+    /// The stack occupies the beginning of linear memory, and data objects are
+    /// placed after it. This means that a stack overflow traps instead of
+    /// overwriting data.
+    BeforeData,
+    /// The top of the stack is placed at the given address.
+    ///
+    /// note: the stack must not overlap with any data objects
+    Top(u64),
+}
+
+impl Default for StackConfig {
+    fn default() -> Self {
+        Self {
+            size: 64 * 1024,
+            placement: StackPlacement::BeforeData,
+        }
+    }
+}
+
+impl StackConfig {
+    /// The initial value of the stack pointer.
+    pub(crate) fn top(&self) -> u64 {
+        match self.placement {
+            StackPlacement::BeforeData => self.size,
+            StackPlacement::Top(top) => top,
+        }
+    }
+}
+
+/// The stack frame of a function, which contains all of its stack slots.
+#[derive(Debug)]
+pub(crate) struct StackFrame {
+    /// The local which stores the address of the bottom of the frame.
+    pub(crate) frame_pointer: LocalId,
+    /// The size of the frame (in bytes).
+    size: u64,
+    /// The offset of each stack slot from the bottom of the frame.
+    offsets: FnvHashMap<ir::StackSlot, u64>,
+    /// The type of a pointer.
+    pointer_type: ir::Type,
+}
+
+impl StackFrame {
+    /// Assigns every stack slot in the function a location in its stack frame.
+    ///
+    /// Returns `None` if the function does not have any stack slots.
+    pub(crate) fn layout(
+        func: &ir::Function,
+        locals: &mut ModuleLocals,
+        pointer_type: ir::Type,
+    ) -> Option<Self> {
+        if func.stack_slots.is_empty() {
+            return None;
+        }
+
+        let mut size = 0;
+        let mut offsets = FnvHashMap::default();
+        for (slot, data) in func.stack_slots.iter() {
+            let align = (data.size as u64).next_power_of_two().min(STACK_ALIGNMENT);
+            let offset = (size + align - 1) & !(align - 1);
+            offsets.insert(slot, offset);
+            size = offset + data.size as u64;
+        }
+        let size = (size + STACK_ALIGNMENT - 1) & !(STACK_ALIGNMENT - 1);
+
+        log::trace!("computed stack frame of {} bytes: {:#?}", size, offsets);
+
+        Some(Self {
+            frame_pointer: locals.add(wasm_of_pointer(pointer_type)),
+            size,
+            offsets,
+            pointer_type,
+        })
+    }
+
+    /// The offset of the stack slot from the bottom of the frame.
+    pub(crate) fn offset_of(&self, slot: ir::StackSlot) -> u64 {
+        self.offsets[&slot]
+    }
+
+    /// Reserves space for the frame on the stack, and sets the frame pointer.
+    pub(crate) fn build_prologue(&self, stack_pointer: GlobalId, builder: &mut InstrSeqBuilder) {
+        builder.global_get(stack_pointer);
+        self.build_size(builder);
+        builder
+            .binop(self.op(BinaryOp::I32Sub, BinaryOp::I64Sub))
+            .local_tee(self.frame_pointer)
+            .global_set(stack_pointer);
+    }
+
+    /// Releases the frame (this must be done before returning).
+    pub(crate) fn build_epilogue(&self, stack_pointer: GlobalId, builder: &mut InstrSeqBuilder) {
+        builder.local_get(self.frame_pointer);
+        self.build_size(builder);
+        builder
+            .binop(self.op(BinaryOp::I32Add, BinaryOp::I64Add))
+            .global_set(stack_pointer);
+    }
+
+    fn build_size(&self, builder: &mut InstrSeqBuilder) {
+        if self.pointer_type == ir::types::I64 {
+            builder.i64_const(self.size as i64);
+        } else {
+            builder.i32_const(self.size as i32);
+        }
+    }
+
+    /// Selects the operation which corresponds to the pointer type.
+    fn op(&self, op_32: BinaryOp, op_64: BinaryOp) -> BinaryOp {
+        if self.pointer_type == ir::types::I64 {
+            op_64
+        } else {
+            op_32
+        }
+    }
+}
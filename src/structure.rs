@@ -0,0 +1,722 @@
+//! Structures control flow using the dominator tree of the function, following
+//! "Beyond Relooper" (Norman Ramsey, ICFP 2022):
+//! https://dl.acm.org/doi/10.1145/3547621
+//!
+//! The code of each block is followed by the code of the blocks which it
+//! immediately dominates. A block which is only branched to from one place is
+//! placed at that branch. Every other block (where branches merge together) is
+//! placed after a WebAssembly block, which the branches to it leave:
+//!
+//! ```text
+//! block $merge_1
+//!   block $merge_0
+//!     ;; the block, and the blocks which are only branched to from it
+//!   end
+//!   ;; the first block where branches merge (the earlier in reverse postorder)
+//! end
+//! ;; the second block where branches merge
+//! ```
+//!
+//! A block which is branched back to (from the blocks which it dominates) is
+//! wrapped in a WebAssembly loop, which those branches continue.
+//!
+//! This only works if the control flow is reducible (every loop is entered
+//! through the block at its start), so loops which can be entered at several
+//! of their blocks are first given a single entry (see
+//! [`IrreducibleControlFlow`]). The blocks are therefore structured as the
+//! nodes of a graph, in which a block may appear more than once and which may
+//! contain nodes dispatching on the label.
+
+use cranelift_codegen::{cursor::FuncCursor, ir::Block};
+use fnv::{FnvHashMap, FnvHashSet};
+use walrus::InstrSeqBuilder;
+
+use crate::{
+    branches,
+    conversions::block::{build_wasm_block, Branch, BranchInstr, CanBranchTo},
+    error::{ErrorKind, TranslationError, TranslationResult},
+    IndividualFunctionTranslator,
+};
+
+/// The algorithm used to turn the control flow of a function into WebAssembly
+/// blocks and loops.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Structurer {
+    /// The relooper (as used by Emscripten), which can structure any control
+    /// flow, but dispatches on a label local wherever several blocks can be
+    /// reached from the same place.
+    Relooper,
+    /// The dominator tree of the function is used to place blocks (see
+    /// [`crate::structure`]), so that every branch is a WebAssembly `br` (other
+    /// than those into loops with several entries, see
+    /// [`IrreducibleControlFlow`]).
+    Dominators,
+}
+
+/// How [`Structurer::Dominators`] handles a loop which can be entered at more
+/// than one of its blocks (irreducible control flow).
+///
+/// note: the relooper always dispatches on the label.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrreducibleControlFlow {
+    /// The blocks of the loop which are reached from each of its other entries
+    /// (before reaching the first entry) are duplicated, and the branches into
+    /// the loop at that entry go to the copies instead, so that the loop (and
+    /// each copy) only has one entry.
+    ///
+    /// At most `budget` instructions are duplicated in each function. Loops
+    /// which would need more are dispatched on the label instead.
+    Split { budget: usize },
+    /// Branches into the loop set the label to the block which they branch to,
+    /// and go to a new block at the start of the loop, which dispatches on the
+    /// label to that block.
+    Dispatch,
+}
+
+/// A node of the graph which is structured: the code of a block (of which
+/// there may be several copies) or a dispatch on the label.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) struct Node(usize);
+
+#[derive(Debug, Clone)]
+struct NodeData {
+    /// The block which the node is a copy of (or `None` if the node dispatches
+    /// on the label).
+    block: Option<Block>,
+    /// The node reached by each branch, together with the block which it
+    /// branches to (a dispatch on the label has a branch for each value of the
+    /// label).
+    ///
+    /// A block with several branches to the same destination (other than the
+    /// entries of a `br_table`) lists it more than once, but always with the
+    /// same node.
+    successors: Vec<(Block, Node)>,
+}
+
+/// Where the code of each node of the function is placed.
+#[derive(Debug)]
+pub(crate) struct DominatorStructure {
+    nodes: Vec<NodeData>,
+    /// The node at which the function starts.
+    entry: Node,
+    /// The children of each node in the dominator tree which are branched to
+    /// from more than one place, latest in reverse postorder first.
+    merges: FnvHashMap<Node, Vec<Node>>,
+    /// The nodes which are only branched to from one place (other than the
+    /// nodes which they dominate).
+    inlined: FnvHashSet<Node>,
+    /// The nodes which are branched back to, from the nodes which they
+    /// dominate.
+    loop_headers: FnvHashSet<Node>,
+}
+
+impl DominatorStructure {
+    /// Computes the structure of the function, handling irreducible control
+    /// flow as described by `irreducible`.
+    pub(crate) fn compute(cursor: &mut FuncCursor, irreducible: IrreducibleControlFlow) -> Self {
+        let mut graph = Graph::new(cursor);
+        graph.make_reducible(cursor, irreducible);
+
+        let postorder = graph.postorder();
+        let order: FnvHashMap<Node, usize> = postorder
+            .iter()
+            .rev()
+            .enumerate()
+            .map(|(i, node)| (*node, i))
+            .collect();
+        let idoms = dominators(&graph, &postorder, &order);
+
+        // a branch goes forwards if its destination is later in reverse
+        // postorder; otherwise it goes back to the start of a loop
+        let mut forward_branches: FnvHashMap<Node, usize> = Default::default();
+        let mut loop_headers = FnvHashSet::default();
+        for node in &postorder {
+            for (_, successor) in &graph.nodes[node.0].successors {
+                if order[successor] > order[node] {
+                    *forward_branches.entry(*successor).or_default() += 1;
+                } else {
+                    debug_assert!(dominates(&idoms, *successor, *node));
+                    loop_headers.insert(*successor);
+                }
+            }
+        }
+
+        // the branches to a dispatch on the label set the label, so they
+        // cannot be followed by its code
+        let mut merges: FnvHashMap<Node, Vec<Node>> = Default::default();
+        let mut inlined = FnvHashSet::default();
+        for node in postorder.iter().rev().skip(1) {
+            if forward_branches[node] == 1 && graph.nodes[node.0].block.is_some() {
+                inlined.insert(*node);
+            } else {
+                merges.entry(idoms[node]).or_default().push(*node);
+            }
+        }
+        for children in merges.values_mut() {
+            children.reverse();
+        }
+
+        Self {
+            nodes: graph.nodes,
+            entry: graph.entry,
+            merges,
+            inlined,
+            loop_headers,
+        }
+    }
+}
+
+impl<'clif> IndividualFunctionTranslator<'clif> {
+    /// Compiles the function, whose control flow has been structured using
+    /// its dominator tree.
+    pub(crate) fn compile_dominator_tree(
+        &mut self,
+        builder: &mut InstrSeqBuilder,
+    ) -> TranslationResult<()> {
+        let structure = self.structure.expect("the control flow is not structured");
+        // if the function starts with a dispatch on the label (because the
+        // entry block is in a loop with several entries), the label selects the
+        // entry block
+        if structure.nodes[structure.entry.0].block.is_none() {
+            let entry = self.cursor.func.layout.entry_block().unwrap();
+            let label = self.label();
+            builder.i32_const(entry.as_u32() as i32).local_set(label);
+        }
+        self.compile_dominated(builder, structure.entry)
+    }
+
+    /// Compiles `node`, followed by the nodes which it dominates.
+    pub(crate) fn compile_dominated(
+        &mut self,
+        builder: &mut InstrSeqBuilder,
+        node: Node,
+    ) -> TranslationResult<()> {
+        let structure = self.structure.expect("the control flow is not structured");
+        let merges = structure
+            .merges
+            .get(&node)
+            .map(Vec::as_slice)
+            .unwrap_or_default();
+
+        if !structure.loop_headers.contains(&node) {
+            return self.compile_merges(builder, node, merges);
+        }
+        let seq = builder.dangling_instr_seq(None).id();
+        self.node_targets.insert(node, seq);
+        let inner = self.compile_merges(&mut builder.instr_seq(seq), node, merges);
+        self.node_targets.remove(&node);
+        inner?;
+        builder.instr(walrus::ir::Loop { seq });
+        Ok(())
+    }
+
+    /// Compiles `node` (and the nodes which are only branched to from it)
+    /// nested in a WebAssembly block for each of `merges`, whose code follows
+    /// the end of that block.
+    fn compile_merges(
+        &mut self,
+        builder: &mut InstrSeqBuilder,
+        node: Node,
+        merges: &[Node],
+    ) -> TranslationResult<()> {
+        let (merge, inner_merges) = match merges.split_first() {
+            Some(merges) => merges,
+            None => return self.compile_dominating(builder, node),
+        };
+
+        let seq = builder.dangling_instr_seq(None).id();
+        self.node_targets.insert(*merge, seq);
+        let inner = self.compile_merges(&mut builder.instr_seq(seq), node, inner_merges);
+        self.node_targets.remove(merge);
+        inner?;
+        builder.instr(walrus::ir::Block { seq });
+
+        self.compile_dominated(builder, *merge)
+    }
+
+    /// Compiles the code of `node`, whose branches either leave the
+    /// WebAssembly blocks which are followed by their destinations (or continue
+    /// the loops which start with them), or are followed by the code of their
+    /// destinations.
+    fn compile_dominating(
+        &mut self,
+        builder: &mut InstrSeqBuilder,
+        node: Node,
+    ) -> TranslationResult<()> {
+        let structure = self.structure.expect("the control flow is not structured");
+        let data = &structure.nodes[node.0];
+
+        let block = match data.block {
+            Some(block) => block,
+            None => return self.compile_dispatch(builder, &data.successors),
+        };
+
+        let mut destinations = FnvHashMap::default();
+        for (destination, successor) in &data.successors {
+            let dispatch = structure.nodes[successor.0].block.is_none();
+            let method = match self.node_target(*successor) {
+                Some(seq) => Branch::Br(BranchInstr {
+                    label: dispatch.then(|| self.label()),
+                    seq,
+                }),
+                None if structure.inlined.contains(successor) => Branch::Inline(*successor),
+                None => {
+                    let reason = format!("`{}` cannot be reached", destination);
+                    let inst = self.cursor.func.layout.last_inst(block).unwrap();
+                    return Err(TranslationError::new(ErrorKind::Unsupported(reason))
+                        .at(inst, self.cursor.func));
+                }
+            };
+            destinations.insert(destination.as_u32(), method);
+        }
+
+        build_wasm_block(block, self, builder, &CanBranchTo { destinations })
+    }
+
+    /// Compiles a dispatch on the label, to the node of each block which the
+    /// label can be set to (the last of which is reached if none of the others
+    /// are selected).
+    fn compile_dispatch(
+        &mut self,
+        builder: &mut InstrSeqBuilder,
+        successors: &[(Block, Node)],
+    ) -> TranslationResult<()> {
+        // the blocks which select each node
+        let mut cases: Vec<(Node, Vec<Block>)> = Vec::new();
+        let mut case_of: FnvHashMap<Node, usize> = Default::default();
+        for (block, successor) in successors {
+            let case = *case_of.entry(*successor).or_insert_with(|| {
+                cases.push((*successor, Vec::new()));
+                cases.len() - 1
+            });
+            cases[case].1.push(*block);
+        }
+
+        let label = self.label();
+        let (last, cases) = cases.split_last().expect("internal error");
+        for (node, blocks) in cases {
+            for (i, block) in blocks.iter().enumerate() {
+                builder
+                    .local_get(label)
+                    .i32_const(block.as_u32() as i32)
+                    .binop(walrus::ir::BinaryOp::I32Eq);
+                if i > 0 {
+                    builder.binop(walrus::ir::BinaryOp::I32Or);
+                }
+            }
+            let mut case = Ok(());
+            builder.if_else(
+                None,
+                |then| case = self.compile_dispatch_case(then, *node),
+                |_| {},
+            );
+            case?;
+        }
+        self.compile_dispatch_case(builder, last.0)
+    }
+
+    /// Goes to `node` from a dispatch on the label.
+    fn compile_dispatch_case(
+        &mut self,
+        builder: &mut InstrSeqBuilder,
+        node: Node,
+    ) -> TranslationResult<()> {
+        match self.node_target(node) {
+            Some(seq) => {
+                builder.br(seq);
+                Ok(())
+            }
+            None => self.compile_dominated(builder, node),
+        }
+    }
+
+    /// The WebAssembly block (or loop) which is branched to in order to reach
+    /// `node`, if the node is compiled after the end of a block (or at the
+    /// start of a loop) which is being compiled.
+    fn node_target(&self, node: Node) -> Option<walrus::ir::InstrSeqId> {
+        self.node_targets.get(&node).copied()
+    }
+}
+
+/// The control flow graph of a function, which is made reducible before it is
+/// structured.
+struct Graph {
+    nodes: Vec<NodeData>,
+    entry: Node,
+}
+
+impl Graph {
+    /// Constructs the graph of the blocks which can be reached from the entry
+    /// block (each of which is a node).
+    fn new(cursor: &mut FuncCursor) -> Self {
+        let entry = cursor.func.layout.entry_block().unwrap();
+
+        let mut blocks = vec![entry];
+        let mut node_of: FnvHashMap<Block, Node> = Default::default();
+        node_of.insert(entry, Node(0));
+        let mut branches_of = Vec::new();
+        while let Some(block) = blocks.get(branches_of.len()).copied() {
+            // a `br_table` only branches to each of its destinations once
+            let mut seen = FnvHashSet::default();
+            let block_branches: Vec<_> = branches(cursor, block)
+                .into_iter()
+                .filter(|branch| seen.insert(*branch))
+                .collect();
+            for (_, destination) in &block_branches {
+                if !node_of.contains_key(destination) {
+                    node_of.insert(*destination, Node(blocks.len()));
+                    blocks.push(*destination);
+                }
+            }
+            branches_of.push(block_branches);
+        }
+
+        let nodes = blocks
+            .into_iter()
+            .zip(branches_of)
+            .map(|(block, branches)| NodeData {
+                block: Some(block),
+                successors: branches
+                    .into_iter()
+                    .map(|(_, destination)| (destination, node_of[&destination]))
+                    .collect(),
+            })
+            .collect();
+        Self {
+            nodes,
+            entry: Node(0),
+        }
+    }
+
+    /// The nodes which can be reached from the entry node, in the postorder
+    /// of a depth-first search.
+    fn postorder(&self) -> Vec<Node> {
+        let mut postorder = Vec::new();
+        let mut visited = FnvHashSet::default();
+        visited.insert(self.entry);
+        let mut stack = vec![(self.entry, 0)];
+        while let Some((node, next)) = stack.last_mut() {
+            match self.nodes[node.0].successors.get(*next) {
+                Some((_, successor)) => {
+                    *next += 1;
+                    if visited.insert(*successor) {
+                        stack.push((*successor, 0));
+                    }
+                }
+                None => {
+                    postorder.push(*node);
+                    stack.pop();
+                }
+            }
+        }
+        postorder
+    }
+
+    /// Gives every loop a single entry.
+    fn make_reducible(&mut self, cursor: &FuncCursor, irreducible: IrreducibleControlFlow) {
+        let mut budget = match irreducible {
+            IrreducibleControlFlow::Split { budget } => Some(budget),
+            IrreducibleControlFlow::Dispatch => None,
+        };
+        while let Some((region, entries)) = self.irreducible_region() {
+            log::trace!("found a loop with several entries: {:?}", entries);
+            let split = match budget {
+                Some(ref mut budget) => self.split(cursor, &region, &entries, budget),
+                None => false,
+            };
+            if !split {
+                self.dispatch(&entries);
+            }
+        }
+    }
+
+    /// Finds a loop (i.e. a strongly connected region of the graph, possibly
+    /// nested in other loops) with more than one entry, returning its nodes
+    /// and its entries (in the order in which they are found).
+    fn irreducible_region(&self) -> Option<(Vec<Node>, Vec<Node>)> {
+        let mut postorder = self.postorder();
+        let predecessors = self.predecessors(&postorder);
+
+        // the loops nested in a loop are found by ignoring the branches back
+        // to its entry
+        postorder.reverse();
+        let mut regions = vec![(postorder, None)];
+        while let Some((region, header)) = regions.pop() {
+            for component in self.components(&region, header, &predecessors) {
+                let nodes: FnvHashSet<Node> = component.iter().copied().collect();
+                let entries: Vec<Node> = component
+                    .iter()
+                    .copied()
+                    .filter(|node| {
+                        *node == self.entry
+                            || predecessors[node]
+                                .iter()
+                                .any(|predecessor| !nodes.contains(predecessor))
+                    })
+                    .collect();
+                if entries.len() > 1 {
+                    return Some((component, entries));
+                }
+                // a single node is a loop if it branches to itself, unless it
+                // is the header of the region (whose branches are ignored)
+                let node = component[0];
+                if component.len() > 1
+                    || (Some(node) != header && predecessors[&node].contains(&node))
+                {
+                    regions.push((component, entries.first().copied()));
+                }
+            }
+        }
+        None
+    }
+
+    /// The nodes which branch to each node in `nodes`.
+    fn predecessors(&self, nodes: &[Node]) -> FnvHashMap<Node, Vec<Node>> {
+        let mut predecessors: FnvHashMap<Node, Vec<Node>> =
+            nodes.iter().map(|node| (*node, Vec::new())).collect();
+        let mut edges = FnvHashSet::default();
+        for node in nodes {
+            for (_, successor) in &self.nodes[node.0].successors {
+                if edges.insert((*node, *successor)) {
+                    predecessors.get_mut(successor).unwrap().push(*node);
+                }
+            }
+        }
+        predecessors
+    }
+
+    /// The strongly connected components of the subgraph made up of `region`
+    /// (ignoring the branches to `header`), using Kosaraju's algorithm. Each
+    /// component lists its nodes in the order of `region`.
+    fn components(
+        &self,
+        region: &[Node],
+        header: Option<Node>,
+        predecessors: &FnvHashMap<Node, Vec<Node>>,
+    ) -> Vec<Vec<Node>> {
+        let in_region: FnvHashSet<Node> = region.iter().copied().collect();
+        let followed = |node: &Node| in_region.contains(node) && Some(*node) != header;
+
+        // the postorder of a depth-first search of the subgraph
+        let mut postorder = Vec::new();
+        let mut visited = FnvHashSet::default();
+        for start in region {
+            if !visited.insert(*start) {
+                continue;
+            }
+            let mut stack = vec![(*start, 0)];
+            while let Some((node, next)) = stack.last_mut() {
+                match self.nodes[node.0].successors.get(*next) {
+                    Some((_, successor)) => {
+                        *next += 1;
+                        if followed(successor) && visited.insert(*successor) {
+                            stack.push((*successor, 0));
+                        }
+                    }
+                    None => {
+                        postorder.push(*node);
+                        stack.pop();
+                    }
+                }
+            }
+        }
+
+        // each search of the reversed subgraph (in reverse postorder) finds a
+        // component
+        let mut component_of: FnvHashMap<Node, usize> = Default::default();
+        let mut count = 0;
+        for start in postorder.iter().rev() {
+            if component_of.contains_key(start) {
+                continue;
+            }
+            component_of.insert(*start, count);
+            let mut stack = vec![*start];
+            while let Some(node) = stack.pop() {
+                if Some(node) == header {
+                    continue;
+                }
+                for predecessor in &predecessors[&node] {
+                    if in_region.contains(predecessor) && !component_of.contains_key(predecessor) {
+                        component_of.insert(*predecessor, count);
+                        stack.push(*predecessor);
+                    }
+                }
+            }
+            count += 1;
+        }
+
+        let mut components = vec![Vec::new(); count];
+        for node in region {
+            components[component_of[node]].push(*node);
+        }
+        components
+    }
+
+    /// Duplicates the nodes of `region` which are reached from each of its
+    /// entries other than the first (before reaching the first entry), so that
+    /// the branches into the region at that entry can go to the copy, if this
+    /// does not duplicate more than `budget` instructions (in which case
+    /// `budget` is reduced by the number of instructions which were
+    /// duplicated).
+    fn split(
+        &mut self,
+        cursor: &FuncCursor,
+        region: &[Node],
+        entries: &[Node],
+        budget: &mut usize,
+    ) -> bool {
+        let header = entries[0];
+        let in_region: FnvHashSet<Node> = region.iter().copied().collect();
+
+        let mut copies = Vec::new();
+        let mut size = 0;
+        for entry in &entries[1..] {
+            let mut reached = vec![*entry];
+            let mut visited: FnvHashSet<Node> = reached.iter().copied().collect();
+            let mut next = 0;
+            while let Some(node) = reached.get(next).copied() {
+                next += 1;
+                for (_, successor) in &self.nodes[node.0].successors {
+                    if *successor != header
+                        && in_region.contains(successor)
+                        && visited.insert(*successor)
+                    {
+                        reached.push(*successor);
+                    }
+                }
+            }
+            size += reached
+                .iter()
+                .map(|node| match self.nodes[node.0].block {
+                    Some(block) => cursor.func.layout.block_insts(block).count(),
+                    None => self.nodes[node.0].successors.len(),
+                })
+                .sum::<usize>();
+            copies.push((*entry, reached));
+        }
+        if size > *budget {
+            log::trace!("splitting the loop would duplicate {} instructions", size);
+            return false;
+        }
+        *budget -= size;
+
+        let outside = (0..self.nodes.len())
+            .map(Node)
+            .filter(|node| !in_region.contains(node))
+            .collect::<Vec<_>>();
+        for (entry, reached) in copies {
+            let copy_of: FnvHashMap<Node, Node> = reached
+                .iter()
+                .enumerate()
+                .map(|(i, node)| (*node, Node(self.nodes.len() + i)))
+                .collect();
+            for node in &reached {
+                let mut copy = self.nodes[node.0].clone();
+                for (_, successor) in &mut copy.successors {
+                    *successor = copy_of.get(successor).copied().unwrap_or(*successor);
+                }
+                self.nodes.push(copy);
+            }
+            for node in &outside {
+                for (_, successor) in &mut self.nodes[node.0].successors {
+                    if *successor == entry {
+                        *successor = copy_of[&entry];
+                    }
+                }
+            }
+        }
+        true
+    }
+
+    /// Adds a node which dispatches on the label to `entries`, which every
+    /// branch to them goes through instead.
+    fn dispatch(&mut self, entries: &[Node]) {
+        let dispatch = Node(self.nodes.len());
+        let entries: FnvHashSet<Node> = entries.iter().copied().collect();
+        let mut successors = Vec::new();
+        let mut seen = FnvHashSet::default();
+        for node in &mut self.nodes {
+            for (block, successor) in &mut node.successors {
+                if entries.contains(successor) {
+                    if seen.insert((*block, *successor)) {
+                        successors.push((*block, *successor));
+                    }
+                    *successor = dispatch;
+                }
+            }
+        }
+        // the function starts at its entry block (which is the first node)
+        if entries.contains(&self.entry) {
+            let entry = self.nodes[0].block.unwrap();
+            if seen.insert((entry, self.entry)) {
+                successors.push((entry, self.entry));
+            }
+            self.entry = dispatch;
+        }
+        self.nodes.push(NodeData {
+            block: None,
+            successors,
+        });
+    }
+}
+
+/// Computes the immediate dominator of each node (other than the entry node)
+/// which can be reached, using "A Simple, Fast Dominance Algorithm" (Cooper,
+/// Harvey and Kennedy).
+///
+/// note: [`cranelift_codegen::dominator_tree::DominatorTree`] is not used, as
+/// it ignores branches which are not at the end of their block (and does not
+/// know about copies of blocks)
+fn dominators(
+    graph: &Graph,
+    postorder: &[Node],
+    order: &FnvHashMap<Node, usize>,
+) -> FnvHashMap<Node, Node> {
+    let predecessors = graph.predecessors(postorder);
+    let mut idoms = FnvHashMap::default();
+    idoms.insert(graph.entry, graph.entry);
+
+    let intersect = |idoms: &FnvHashMap<Node, Node>, mut a: Node, mut b: Node| {
+        while a != b {
+            while order[&a] > order[&b] {
+                a = idoms[&a];
+            }
+            while order[&b] > order[&a] {
+                b = idoms[&b];
+            }
+        }
+        a
+    };
+
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for node in postorder.iter().rev().skip(1) {
+            let idom = predecessors[node]
+                .iter()
+                .copied()
+                .filter(|predecessor| idoms.contains_key(predecessor))
+                .reduce(|a, b| intersect(&idoms, a, b))
+                .expect("internal error");
+            if idoms.insert(*node, idom) != Some(idom) {
+                changed = true;
+            }
+        }
+    }
+
+    idoms.remove(&graph.entry);
+    idoms
+}
+
+/// Whether every path from the entry node to `node` goes through `dominator`.
+fn dominates(idoms: &FnvHashMap<Node, Node>, dominator: Node, mut node: Node) -> bool {
+    loop {
+        if node == dominator {
+            return true;
+        }
+        match idoms.get(&node) {
+            Some(idom) => node = *idom,
+            None => return false,
+        }
+    }
+}
@@ -0,0 +1,79 @@
+//! The table through which functions are called indirectly.
+
+use fnv::FnvHashMap;
+use walrus::{
+    ir::Value, ElementId, ElementKind, FunctionId, InitExpr, Module as WalrusModule, TableId,
+    ValType,
+};
+
+/// A `funcref` table containing every function whose address is taken.
+///
+/// Cranelift function pointers are represented as indices into this table.
+/// Index zero is never assigned to a function, so that calling a null function
+/// pointer traps.
+#[derive(Debug)]
+pub(crate) struct FunctionTable {
+    /// The id of the table in the Walrus module.
+    pub(crate) id: TableId,
+    /// The (active) element segment used to initialise the table.
+    element: ElementId,
+    /// The functions in the table, in order (starting at index one).
+    entries: Vec<FunctionId>,
+    /// Maps functions to their index in the table.
+    indices: FnvHashMap<FunctionId, u32>,
+}
+
+impl FunctionTable {
+    /// Adds a new (empty) function table to the module.
+    pub(crate) fn new(module: &mut WalrusModule) -> Self {
+        let id = module.tables.add_local(1, None, ValType::Funcref);
+        let element = module.elements.add(
+            ElementKind::Active {
+                table: id,
+                offset: InitExpr::Value(Value::I32(1)),
+            },
+            ValType::Funcref,
+            Vec::new(),
+        );
+        module.tables.get_mut(id).elem_segments.insert(element);
+
+        Self {
+            id,
+            element,
+            entries: Vec::new(),
+            indices: Default::default(),
+        }
+    }
+
+    /// Returns the index of `func` in the table, adding it to the table if it
+    /// is not already present.
+    pub(crate) fn index_of(&mut self, func: FunctionId) -> u32 {
+        let entries = &mut self.entries;
+        *self.indices.entry(func).or_insert_with(|| {
+            entries.push(func);
+            entries.len() as u32
+        })
+    }
+
+    /// The number of functions in the table.
+    pub(crate) fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Removes the functions which were added after the table contained `len`
+    /// functions (used to undo the changes made by a function which failed to
+    /// compile).
+    pub(crate) fn truncate(&mut self, len: usize) {
+        for func in self.entries.drain(len..) {
+            self.indices.remove(&func);
+        }
+    }
+
+    /// Writes the current contents of the table into the Walrus module. This
+    /// must be called before the module is emitted.
+    pub(crate) fn finish(&self, module: &mut WalrusModule) {
+        module.elements.get_mut(self.element).members =
+            self.entries.iter().copied().map(Some).collect();
+        module.tables.get_mut(self.id).initial = self.entries.len() as u32 + 1;
+    }
+}
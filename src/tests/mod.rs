@@ -93,7 +93,7 @@ fn test_simple_control_flow() {
             builder.switch_to_block(header_block);
             let iteration = builder.use_var(Variable::with_u32(0));
             let condition = builder.ins().icmp(IntCC::Equal, iteration, zero);
-            builder.ins().brz(condition, exit_block, &[]);
+            builder.ins().brnz(condition, exit_block, &[]);
             builder.ins().jump(body_block, &[]);
             builder.switch_to_block(body_block);
             builder.seal_block(body_block);
@@ -129,7 +129,7 @@ rusty_fork_test! {
 
         fn fib(n: i32) -> i32 {
             match n {
-                0 | 1 | 2 => 1,
+                0..=2 => 1,
                 n => fib(n - 1) + fib(n - 2),
             }
         }
@@ -212,82 +212,2576 @@ mod ops {
                 |res: i32| -> bool { res == 0 },
             );
         }
+
+        #[test]
+        fn test_icmp_i32() {
+            test_from_file(
+                (-1, 1),
+                "src/filetests/cond/icmp_i32.clif",
+                |res: (i32, i32, i32, i32, i32, i32, i32, i32)| -> bool {
+                    res == (1, 1, 0, 0, 0, 0, 1, 1)
+                },
+            );
+            test_from_file(
+                (3, 3),
+                "src/filetests/cond/icmp_i32.clif",
+                |res: (i32, i32, i32, i32, i32, i32, i32, i32)| -> bool {
+                    res == (0, 1, 0, 1, 0, 1, 0, 1)
+                },
+            );
+        }
+
+        #[test]
+        fn test_icmp_imm_i16() {
+            test_from_file(
+                0x1_8000,
+                "src/filetests/cond/icmp_imm_i16.clif",
+                |res: (i32, i32, i32, i32)| -> bool { res == (1, 0, 1, 1) },
+            );
+            test_from_file(
+                0xffff,
+                "src/filetests/cond/icmp_imm_i16.clif",
+                |res: (i32, i32, i32, i32)| -> bool { res == (1, 1, 1, 0) },
+            );
+        }
+
+        #[test]
+        fn test_overflow() {
+            test_from_file(
+                (i64::MIN, 1i64, 0x80, 1),
+                "src/filetests/cond/overflow.clif",
+                |res: (i32, i32, i32, i32)| -> bool { res == (1, 0, 1, 0) },
+            );
+            test_from_file(
+                (i64::MAX, -1i64, 100, -100),
+                "src/filetests/cond/overflow.clif",
+                |res: (i32, i32, i32, i32)| -> bool { res == (1, 0, 1, 1) },
+            );
+            test_from_file(
+                (5i64, 7i64, 0x1ff, 0x7f),
+                "src/filetests/cond/overflow.clif",
+                |res: (i32, i32, i32, i32)| -> bool { res == (0, 1, 0, 1) },
+            );
+        }
+
+        #[test]
+        fn test_br_icmp_i16() {
+            test_from_file(
+                (0x2_0002, 0x8005),
+                "src/filetests/cond/br_icmp.clif",
+                |res: i32| -> bool { res == -32763 },
+            );
+            test_from_file(
+                (0xffff, 3),
+                "src/filetests/cond/br_icmp.clif",
+                |res: i32| -> bool { res == -1 },
+            );
+        }
+    }
+
+    /// `i8` and `i16` values (whose upper bits may contain anything, so the
+    /// arguments are chosen to have non-zero upper bits where possible).
+    mod narrow {
+        use crate::tests::test_from_file;
+
+        #[test]
+        fn test_iadd_i8() {
+            test_from_file(
+                (200, 100),
+                "src/filetests/narrow/iadd_i8.clif",
+                |res: i32| -> bool { res == 44 },
+            );
+        }
+
+        #[test]
+        fn test_icmp_i8() {
+            test_from_file(
+                (200, 56),
+                "src/filetests/narrow/icmp_i8.clif",
+                |res: i32| -> bool { res == 1 },
+            );
+            test_from_file(
+                (0x100, 0),
+                "src/filetests/narrow/icmp_i8.clif",
+                |res: i32| -> bool { res == 1 },
+            );
+            test_from_file(
+                (200, 55),
+                "src/filetests/narrow/icmp_i8.clif",
+                |res: i32| -> bool { res == 0 },
+            );
+        }
+
+        #[test]
+        fn test_extend_i8() {
+            test_from_file(
+                0x1ff,
+                "src/filetests/narrow/extend_i8.clif",
+                |res: (i32, i32)| -> bool { res == (-1, 0xff) },
+            );
+            test_from_file(
+                0x17f,
+                "src/filetests/narrow/extend_i8.clif",
+                |res: (i32, i32)| -> bool { res == (0x7f, 0x7f) },
+            );
+        }
+
+        #[test]
+        fn test_extend_i16_i64() {
+            test_from_file(
+                0x1_7fffi64,
+                "src/filetests/narrow/extend_i16_i64.clif",
+                |res: (i64, i64)| -> bool { res == (-0x8000, 0x8000) },
+            );
+            test_from_file(
+                0xffffi64,
+                "src/filetests/narrow/extend_i16_i64.clif",
+                |res: (i64, i64)| -> bool { res == (0, 0) },
+            );
+        }
+
+        #[test]
+        fn test_brz_i16() {
+            test_from_file(0xffff, "src/filetests/narrow/brz_i16.clif", |res: i32| {
+                res == 1
+            });
+            test_from_file(0x1fffe, "src/filetests/narrow/brz_i16.clif", |res: i32| {
+                res == 0
+            });
+        }
+
+        #[test]
+        fn test_stack_i8() {
+            test_from_file(0x1234, "src/filetests/narrow/stack_i8.clif", |res: i32| {
+                res == 0xffff34ffu32 as i32
+            });
+        }
+    }
+
+    mod float {
+        use crate::tests::test_from_file;
+
+        #[test]
+        fn test_arith_f64() {
+            test_from_file(
+                (6.0f64, 1.5f64),
+                "src/filetests/float/arith.clif",
+                |res: (f64, f64, f64, f64)| -> bool { res == (7.5, 4.5, 9.0, 4.0) },
+            );
+        }
+
+        #[test]
+        fn test_minmax_f32() {
+            test_from_file(
+                (1.5f32, -2.0f32),
+                "src/filetests/float/minmax.clif",
+                |res: (f32, f32, f32)| -> bool { res == (-2.0, 1.5, -1.5) },
+            );
+        }
+
+        #[test]
+        fn test_unary_f64() {
+            test_from_file(
+                2.25f64,
+                "src/filetests/float/unary.clif",
+                |res: (f64, f64, f64, f64, f64, f64, f64)| -> bool {
+                    res == (1.5, 2.25, -2.25, 3.0, 2.0, 2.0, 2.0)
+                },
+            );
+            // `nearest` rounds halfway cases to even
+            test_from_file(
+                -2.5f64,
+                "src/filetests/float/unary.clif",
+                |(sqrt, abs, neg, ceil, floor, trunc, nearest): (
+                    f64,
+                    f64,
+                    f64,
+                    f64,
+                    f64,
+                    f64,
+                    f64,
+                )|
+                 -> bool {
+                    sqrt.is_nan()
+                        && (abs, neg, ceil, floor, trunc, nearest)
+                            == (2.5, 2.5, -2.0, -3.0, -2.0, -2.0)
+                },
+            );
+        }
+
+        #[test]
+        fn test_consts() {
+            test_from_file(
+                (2.0f32, 3.0f64),
+                "src/filetests/float/consts.clif",
+                |res: (f32, f64)| -> bool { res == (4.5, -4.0) },
+            );
+        }
+
+        #[test]
+        fn test_fcmp_f64() {
+            test_from_file(
+                (1.0f64, f64::NAN),
+                "src/filetests/float/fcmp.clif",
+                |res: (i32, i32, i32, i32, i32, i32)| -> bool { res == (0, 1, 0, 1, 0, 1) },
+            );
+            test_from_file(
+                (1.0f64, 2.0f64),
+                "src/filetests/float/fcmp.clif",
+                |res: (i32, i32, i32, i32, i32, i32)| -> bool { res == (1, 0, 1, 0, 1, 1) },
+            );
+            test_from_file(
+                (2.0f64, 2.0f64),
+                "src/filetests/float/fcmp.clif",
+                |res: (i32, i32, i32, i32, i32, i32)| -> bool { res == (1, 0, 0, 1, 0, 0) },
+            );
+        }
+    }
+
+    mod muldiv {
+        use wasmtime::TrapCode;
+
+        use crate::tests::{test_from_file, utils::call_from_file};
+
+        #[test]
+        fn test_div_i32() {
+            test_from_file(
+                (-7, 2),
+                "src/filetests/arith/div_i32.clif",
+                |res: (i32, i32, i32, i32)| -> bool { res == (0x7fff_fffc, -3, 1, -1) },
+            );
+            let trap = call_from_file::<_, (i32, i32, i32, i32)>(
+                (1, 0),
+                "src/filetests/arith/div_i32.clif",
+            )
+            .unwrap_err();
+            assert_eq!(trap.trap_code(), Some(TrapCode::IntegerDivisionByZero));
+            let trap = call_from_file::<_, (i32, i32, i32, i32)>(
+                (i32::MIN, -1),
+                "src/filetests/arith/div_i32.clif",
+            )
+            .unwrap_err();
+            assert_eq!(trap.trap_code(), Some(TrapCode::IntegerOverflow));
+        }
+
+        #[test]
+        fn test_sdiv_i8() {
+            test_from_file(
+                (0x1ff80, 0x302),
+                "src/filetests/arith/sdiv_i8.clif",
+                |res: i32| -> bool { res == -64 },
+            );
+            // -128 / -1 does not overflow an `i32`, but does overflow an `i8`
+            assert!(
+                call_from_file::<_, i32>((-128, -1), "src/filetests/arith/sdiv_i8.clif").is_err()
+            );
+        }
+
+        #[test]
+        fn test_div_imm_i64() {
+            test_from_file(
+                -100i64,
+                "src/filetests/arith/div_imm.clif",
+                |res: (i64, i64, i64)| -> bool { res == (33, 6, -700) },
+            );
+        }
+
+        #[test]
+        fn test_mulhi_i32() {
+            test_from_file(
+                (-5, 3),
+                "src/filetests/arith/mulhi_i32.clif",
+                |res: (i32, i32, i32)| -> bool { res == (-15, 2, -1) },
+            );
+        }
+
+        #[test]
+        fn test_mulhi_i64() {
+            test_from_file(
+                (0x1234_5678_9abc_def0i64, -2i64),
+                "src/filetests/arith/mulhi_i64.clif",
+                |res: (i64, i64, i64)| -> bool {
+                    res == (-0x2468_acf1_3579_bde0, 0x1234_5678_9abc_deef, -1)
+                },
+            );
+        }
+
+        #[test]
+        /// Divisions trap even if their results are never used.
+        fn test_unused_div() {
+            test_from_file((6, 3), "src/filetests/arith/unused_div.clif", |res: i32| {
+                res == 6
+            });
+            assert!(
+                call_from_file::<_, i32>((6, 0), "src/filetests/arith/unused_div.clif").is_err()
+            );
+        }
+
+        #[test]
+        /// As do divisions by an immediate.
+        fn test_unused_div_imm() {
+            test_from_file(6, "src/filetests/arith/unused_div_imm.clif", |res: i32| {
+                res == 6
+            });
+            assert!(
+                call_from_file::<_, i32>(i32::MIN, "src/filetests/arith/unused_div_imm.clif")
+                    .is_err()
+            );
+        }
+    }
+
+    mod bits {
+        use crate::tests::test_from_file;
+
+        #[test]
+        fn test_bitwise_i32() {
+            test_from_file(
+                (0b1100, 0b1010),
+                "src/filetests/bits/bitwise.clif",
+                |res: (i32, i32, i32, i32, i32, i32)| -> bool { res == (8, 14, 6, 4, -3, -13) },
+            );
+        }
+
+        #[test]
+        fn test_shifts_i8() {
+            // the shift amount is taken modulo 8
+            test_from_file(
+                (0x1f1, 12i64),
+                "src/filetests/bits/shifts_i8.clif",
+                |res: (i32, i32, i32, i32, i32)| -> bool { res == (0x10, 0x0f, -1, 0x1f, 0x1f) },
+            );
+            test_from_file(
+                (0x1f1, 1i64),
+                "src/filetests/bits/shifts_i8.clif",
+                |res: (i32, i32, i32, i32, i32)| -> bool { res == (0xe2, 0x78, -8, 0xe3, 0xf8) },
+            );
+        }
+
+        #[test]
+        fn test_shifts_imm_i64() {
+            test_from_file(
+                i64::MIN + 0xff,
+                "src/filetests/bits/shifts_imm.clif",
+                |res: (i64, i64, i64, i64)| -> bool {
+                    res == (0xff0, -8, 0xff80, 0x7fff_ffff_ffff_ff00)
+                },
+            );
+        }
+
+        #[test]
+        fn test_count_i16() {
+            test_from_file(
+                0x1_0f00,
+                "src/filetests/bits/count.clif",
+                |res: (i32, i32, i32, i32, i32)| -> bool { res == (4, 8, 4, 3, 0x00f0) },
+            );
+            test_from_file(
+                -1,
+                "src/filetests/bits/count.clif",
+                |res: (i32, i32, i32, i32, i32)| -> bool { res == (0, 0, 16, 15, 0xffff) },
+            );
+            test_from_file(
+                0,
+                "src/filetests/bits/count.clif",
+                |res: (i32, i32, i32, i32, i32)| -> bool { res == (16, 16, 0, 15, 0) },
+            );
+        }
+    }
+
+    mod conversions {
+        use cranelift_codegen::ir::{self, InstBuilder};
+        use cranelift_module::{Linkage, Module};
+        use walrus::ModuleConfig;
+
+        use crate::{
+            tests::{
+                test_from_file,
+                utils::{call_export, define_function, flags, signature, wasm32},
+            },
+            MemoryConfig, WasmModule,
+        };
+
+        #[test]
+        fn test_fcvt_sat_f64() {
+            test_from_file(
+                3e9f64,
+                "src/filetests/conversions/fcvt_sat.clif",
+                |res: (i32, i32, i64, i64)| -> bool {
+                    res == (
+                        i32::MAX,
+                        3_000_000_000u32 as i32,
+                        3_000_000_000,
+                        3_000_000_000,
+                    )
+                },
+            );
+            test_from_file(
+                -1e10f64,
+                "src/filetests/conversions/fcvt_sat.clif",
+                |res: (i32, i32, i64, i64)| -> bool { res == (i32::MIN, 0, -10_000_000_000, 0) },
+            );
+            test_from_file(
+                f64::NAN,
+                "src/filetests/conversions/fcvt_sat.clif",
+                |res: (i32, i32, i64, i64)| -> bool { res == (0, 0, 0, 0) },
+            );
+        }
+
+        #[test]
+        /// Without the nontrapping float-to-int proposal, out-of-range values
+        /// are clamped before being truncated.
+        fn test_fcvt_sat_clamped() {
+            let mut module = WasmModule::new(
+                wasm32(),
+                flags(),
+                ModuleConfig::new(),
+                MemoryConfig::default(),
+            )
+            .unwrap();
+            module.set_nontrapping_fptoint(false);
+
+            let sig = signature(&[ir::types::F64], &[ir::types::I32, ir::types::I32]);
+            let func = module
+                .declare_function("func_name", Linkage::Export, &sig)
+                .unwrap();
+            define_function(&mut module, func, sig, |builder, _| {
+                let entry = builder.create_block();
+                builder.append_block_params_for_function_params(entry);
+                builder.switch_to_block(entry);
+                let x = builder.block_params(entry)[0];
+                let signed = builder.ins().fcvt_to_sint_sat(ir::types::I32, x);
+                let unsigned = builder.ins().fcvt_to_uint_sat(ir::types::I32, x);
+                builder.ins().return_(&[signed, unsigned]);
+                builder.seal_block(entry);
+            });
+
+            for (x, expected) in [
+                (-1.5, (-1, 0)),
+                (3e9, (i32::MAX, 3_000_000_000u32 as i32)),
+                (5e9, (i32::MAX, -1)),
+                (-1e10, (i32::MIN, 0)),
+                (f64::NAN, (0, 0)),
+            ] {
+                let res: (i32, i32) = call_export(&mut module, "func_name", x, |_| vec![]);
+                assert_eq!(res, expected, "converting {}", x);
+            }
+        }
+
+        #[test]
+        fn test_fcvt_f32() {
+            test_from_file(
+                7.75f32,
+                "src/filetests/conversions/fcvt.clif",
+                |res: (i32, i64)| -> bool { res == (7, 7) },
+            );
+        }
+
+        #[test]
+        fn test_fcvt_from_i8() {
+            test_from_file(
+                0x1ff,
+                "src/filetests/conversions/fcvt_from.clif",
+                |res: (f64, f64, f32)| -> bool { res == (-1.0, 255.0, 511.0) },
+            );
+            test_from_file(
+                -1,
+                "src/filetests/conversions/fcvt_from.clif",
+                |res: (f64, f64, f32)| -> bool { res == (-1.0, 255.0, 4294967296.0) },
+            );
+        }
+
+        #[test]
+        fn test_promote_demote() {
+            test_from_file(
+                (1.5f32, 0.1f64),
+                "src/filetests/conversions/promote.clif",
+                |res: (f64, f32)| -> bool { res == (1.5, 0.1) },
+            );
+        }
+
+        #[test]
+        fn test_bitcast() {
+            test_from_file(
+                (1.0f64, 0x3fc0_0000),
+                "src/filetests/conversions/bitcast.clif",
+                |res: (i64, f32)| -> bool { res == (0x3ff0_0000_0000_0000, 1.5) },
+            );
+        }
+    }
+
+    /// `i128` values, which are passed (and returned) as their low and high
+    /// halves.
+    /// Loads and stores at an address in linear memory which is not used by
+    /// anything else.
+    mod memory {
+        use crate::tests::test_from_file;
+
+        const ADDRESS: i32 = 0x100_0000;
+
+        #[test]
+        fn test_load_store() {
+            test_from_file(
+                (ADDRESS, 0x0123_4567_89ab_cdefi64),
+                "src/filetests/memory/load_store.clif",
+                |res: (i64, i32, i64, i32)| -> bool {
+                    res == (0x0123_4567_89ab_cdef, -0x3211, 0x0123_4567, 0xcd)
+                },
+            );
+        }
+
+        #[test]
+        fn test_big_endian() {
+            test_from_file(
+                (ADDRESS, 0x0123_4567_89ab_cdefi64, 1.5f32),
+                "src/filetests/memory/big_endian.clif",
+                |res: (i64, i32, i32, f32)| -> bool {
+                    res == (
+                        0xefcd_ab89_6745_2301u64 as i64,
+                        0x4567,
+                        0xefcd_ab89u32 as i32,
+                        1.5,
+                    )
+                },
+            );
+        }
+
+        #[test]
+        fn test_complex_i16() {
+            test_from_file(
+                (ADDRESS - 100, 104, 0x1_80ff),
+                "src/filetests/memory/complex.clif",
+                |res: (i32, i64)| -> bool { res == (0x80ff, -0x80) },
+            );
+        }
+    }
+
+    mod i128 {
+        use crate::tests::test_from_file;
+
+        #[test]
+        fn test_iadd_i128() {
+            test_from_file(
+                (-1i64, 0i64, 1i64, 0i64),
+                "src/filetests/i128/iadd.clif",
+                |res: (i64, i64)| -> bool { res == (0, 1) },
+            );
+            test_from_file(
+                (-1i64, -1i64, 1i64, 0i64),
+                "src/filetests/i128/iadd.clif",
+                |res: (i64, i64)| -> bool { res == (0, 0) },
+            );
+        }
+
+        #[test]
+        fn test_isub_i128() {
+            test_from_file(
+                (0i64, 1i64, 1i64, 0i64),
+                "src/filetests/i128/isub.clif",
+                |res: (i64, i64)| -> bool { res == (-1, 0) },
+            );
+        }
+
+        #[test]
+        fn test_imul_i128() {
+            // (2^64 + 3) * (2^64 - 1) = 2^128 + 2^65 - 3
+            test_from_file(
+                (3i64, 1i64, -1i64, 0i64),
+                "src/filetests/i128/imul.clif",
+                |res: (i64, i64)| -> bool { res == (-3, 1) },
+            );
+            test_from_file(
+                (-1i64, -1i64, -1i64, -1i64),
+                "src/filetests/i128/imul.clif",
+                |res: (i64, i64)| -> bool { res == (1, 0) },
+            );
+        }
+
+        #[test]
+        fn test_icmp_i128() {
+            test_from_file(
+                (0i64, -1i64, 5i64, 0i64),
+                "src/filetests/i128/icmp.clif",
+                |res: (i32, i32, i32)| -> bool { res == (0, 1, 0) },
+            );
+            test_from_file(
+                (5i64, 0i64, 0i64, 1i64),
+                "src/filetests/i128/icmp.clif",
+                |res: (i32, i32, i32)| -> bool { res == (0, 1, 1) },
+            );
+            test_from_file(
+                (5i64, 7i64, 5i64, 7i64),
+                "src/filetests/i128/icmp.clif",
+                |res: (i32, i32, i32)| -> bool { res == (1, 0, 0) },
+            );
+        }
+
+        #[test]
+        fn test_overflow_i128() {
+            test_from_file(
+                (0i64, i64::MIN, 1i64, 0i64),
+                "src/filetests/i128/overflow.clif",
+                |res: (i32, i32)| -> bool { res == (1, 0) },
+            );
+            test_from_file(
+                (-1i64, i64::MAX, -1i64, -1i64),
+                "src/filetests/i128/overflow.clif",
+                |res: (i32, i32)| -> bool { res == (1, 0) },
+            );
+            test_from_file(
+                (5i64, 0i64, 7i64, 0i64),
+                "src/filetests/i128/overflow.clif",
+                |res: (i32, i32)| -> bool { res == (0, 1) },
+            );
+        }
+
+        #[test]
+        fn test_shifts_i128() {
+            let lo = 0x8000_0000_0000_0001u64 as i64;
+            test_from_file(
+                (lo, i64::MIN, 1),
+                "src/filetests/i128/shifts.clif",
+                |res: (i64, i64, i64, i64, i64, i64)| -> bool {
+                    res == (
+                        2,
+                        1,
+                        0x4000_0000_0000_0000,
+                        0x4000_0000_0000_0000,
+                        0x4000_0000_0000_0000,
+                        0xc000_0000_0000_0000u64 as i64,
+                    )
+                },
+            );
+            test_from_file(
+                (lo, i64::MIN, 64),
+                "src/filetests/i128/shifts.clif",
+                |res: (i64, i64, i64, i64, i64, i64)| -> bool {
+                    res == (0, lo, i64::MIN, 0, i64::MIN, -1)
+                },
+            );
+        }
+
+        #[test]
+        fn test_split_i128() {
+            test_from_file(
+                (-1i64, 5i64),
+                "src/filetests/i128/split.clif",
+                |res: (i64, i64)| -> bool { res == (6, 0) },
+            );
+        }
+
+        #[test]
+        fn test_extend_i128() {
+            test_from_file(
+                (-3, -1i64),
+                "src/filetests/i128/extend.clif",
+                |res: (i64, i64, i64, i64, i32)| -> bool { res == (-3, -1, -1, 0, -4) },
+            );
+        }
+
+        #[test]
+        fn test_stack_i128() {
+            test_from_file(
+                (7i64, -9i64),
+                "src/filetests/i128/stack.clif",
+                |res: (i64, i64, i64)| -> bool { res == (7, -9, -9) },
+            );
+        }
+
+        #[test]
+        fn test_load_store_i128() {
+            test_from_file(
+                (0x100_0000, 0x0102_0304_0506_0708i64, -9i64),
+                "src/filetests/i128/load_store.clif",
+                |res: (i64, i64, i64, i64, i64, i64, i64)| -> bool {
+                    res == (
+                        0x0102_0304_0506_0708,
+                        -9,
+                        -9,
+                        0xf7ff_ffff_ffff_ffffu64 as i64,
+                        0x0807_0605_0403_0201,
+                        0x0102_0304_0506_0708,
+                        -9,
+                    )
+                },
+            );
+        }
+
+        #[test]
+        fn test_bits_i128() {
+            test_from_file(
+                (1i64, i64::MIN, 4),
+                "src/filetests/i128/bits.clif",
+                |res: (i64, i64, i64, i64, i64, i64)| -> bool {
+                    res == (0x18, 0, -26, i64::MAX, 0, 2)
+                },
+            );
+            // the rotation amount is taken modulo 128
+            test_from_file(
+                (0i64, 0x10i64, 130),
+                "src/filetests/i128/bits.clif",
+                |res: (i64, i64, i64, i64, i64, i64)| -> bool { res == (0, 0x40, -1, -81, 127, 1) },
+            );
+        }
+
+        #[test]
+        fn test_fib_i128() {
+            // fib(100) does not fit in 64 bits
+            test_from_file(100, "src/filetests/i128/fib.clif", |res: (i64, i64)| {
+                res == (3736710778780434371, 19)
+            });
+        }
+    }
+}
+
+mod control_flow {
+    use rusty_fork::rusty_fork_test;
+
+    use super::{
+        enable_log, test_from_file,
+        utils::{call_func_name, fuel_of_func_name, module_from_file},
+    };
+    use crate::{IrreducibleControlFlow, Structurer, WasmModule};
+
+    #[test]
+    fn test_brnz() {
+        test_from_file(1, "src/filetests/wasmtime/brnz.clif", |res: i32| -> bool {
+            res == 1
+        });
+        test_from_file(0, "src/filetests/wasmtime/brnz.clif", |res: i32| -> bool {
+            res == 2
+        });
+    }
+
+    #[test]
+    fn test_brz() {
+        test_from_file(0, "src/filetests/wasmtime/brz.clif", |res: i32| -> bool {
+            res == 1
+        });
+        test_from_file(1, "src/filetests/wasmtime/brz.clif", |res: i32| -> bool {
+            res == 0
+        });
+    }
+
+    #[test]
+    fn test_fallthrough() {
+        test_from_file(
+            42,
+            "src/filetests/wasmtime/control.clif",
+            |res: i32| -> bool { res == 42 },
+        );
+    }
+
+    rusty_fork_test! {
+        #[test]
+        fn test_loop_2() {
+            enable_log();
+            test_from_file(1, "src/filetests/loop2.clif", |res: i32| -> bool {
+                res == 100
+            });
+            test_from_file(15, "src/filetests/loop2.clif", |res: i32| -> bool {
+                res == 1
+            });
+        }
+    }
+
+    #[test]
+    fn test_cond_br() {
+        enable_log();
+        test_from_file(
+            (1, 1),
+            "src/filetests/wasmtime/condbr-i32.clif",
+            |res: i32| -> bool { res == 1 },
+        );
+        test_from_file(
+            (1, 2),
+            "src/filetests/wasmtime/condbr-i32.clif",
+            |res: i32| -> bool { res == 2 },
+        );
+    }
+
+    rusty_fork_test! {
+        #[test]
+        fn test_control_flow() {
+            enable_log();
+            test_from_file(
+                14,
+                "src/filetests/control-flow.clif",
+                |res: i32| {
+                    res == 0
+                }
+            );
+        }
+    }
+
+    #[test]
+    /// Breaks out of two loops at once.
+    fn test_nested_break() {
+        for (limit, expected) in [(1, 1), (5, 105), (100, 412)] {
+            test_from_file(
+                limit,
+                "src/filetests/loops/nested_break.clif",
+                |res: i32| -> bool { res == expected },
+            );
+        }
+    }
+
+    #[test]
+    /// Continues the outer loop from inside the inner loop.
+    fn test_nested_continue() {
+        for (limit, expected) in [(0, 0), (4, 4), (5, 10)] {
+            test_from_file(
+                limit,
+                "src/filetests/loops/nested_continue.clif",
+                |res: i32| -> bool { res == expected },
+            );
+        }
+    }
+
+    #[test]
+    /// Leaves a loop through either of two exits, which then merge.
+    fn test_loop_exits() {
+        for (limit, expected) in [(0, 100), (3, 103), (50, 210)] {
+            test_from_file(
+                limit,
+                "src/filetests/loops/exits.clif",
+                |res: i32| -> bool { res == expected },
+            );
+        }
+    }
+
+    #[test]
+    fn test_br_table() {
+        for (index, expected) in [(0, 10), (1, 20), (2, 30), (3, 10), (4, -1), (-1, -1)] {
+            test_from_file(
+                index,
+                "src/filetests/switch/br_table.clif",
+                |res: i32| -> bool { res == expected },
+            );
+        }
+    }
+
+    #[test]
+    /// An `i64` index which is out of bounds selects the default destination,
+    /// even if its low 32 bits are in bounds.
+    fn test_br_table_i64() {
+        for (index, expected) in [(0_i64, 1), (1, 2), (2, 0), (1 << 32, 0), (-1, 0)] {
+            test_from_file(
+                index,
+                "src/filetests/switch/br_table_i64.clif",
+                |res: i32| -> bool { res == expected },
+            );
+        }
+    }
+
+    #[test]
+    fn test_br_table_i8() {
+        for (index, expected) in [(255, 1), (0, 2), (2, 0)] {
+            test_from_file(
+                index,
+                "src/filetests/switch/br_table_i8.clif",
+                |res: i32| -> bool { res == expected },
+            );
+        }
+    }
+
+    /// Files whose function takes an `i32`, and the results which it returns
+    /// for some arguments.
+    const CONTROL_FLOW_FILES: [(&str, &[(i32, i32)]); 12] = [
+        ("src/filetests/wasmtime/brnz.clif", &[(1, 1), (0, 2)]),
+        ("src/filetests/wasmtime/brz.clif", &[(0, 1), (1, 0)]),
+        ("src/filetests/wasmtime/control.clif", &[(42, 42), (0, 1)]),
+        (
+            "src/filetests/wasmtime/fib.clif",
+            &[(0, 1), (3, 2), (10, 55)],
+        ),
+        ("src/filetests/loop2.clif", &[(1, 100), (15, 1)]),
+        ("src/filetests/control-flow.clif", &[(14, 0)]),
+        (
+            "src/filetests/narrow/brz_i16.clif",
+            &[(0xffff, 1), (0x1fffe, 0)],
+        ),
+        (
+            "src/filetests/loops/nested_break.clif",
+            &[(1, 1), (5, 105), (100, 412)],
+        ),
+        (
+            "src/filetests/loops/nested_continue.clif",
+            &[(0, 0), (4, 4), (5, 10)],
+        ),
+        (
+            "src/filetests/loops/exits.clif",
+            &[(0, 100), (3, 103), (50, 210)],
+        ),
+        (
+            "src/filetests/switch/br_table.clif",
+            &[(0, 10), (1, 20), (2, 30), (3, 10), (4, -1), (-1, -1)],
+        ),
+        (
+            "src/filetests/switch/br_table_i8.clif",
+            &[(255, 1), (0, 2), (2, 0)],
+        ),
+    ];
+
+    #[test]
+    fn test_dominators() {
+        for (file, cases) in CONTROL_FLOW_FILES {
+            for (arg, expected) in cases {
+                let module =
+                    module_from_file(file, |module| module.set_structurer(Structurer::Dominators));
+                let res: i32 = call_func_name(module, *arg).unwrap();
+                assert_eq!(res, *expected, "{}({})", file, arg);
+            }
+        }
+    }
+
+    #[test]
+    /// Structuring control flow using the dominator tree never needs a label,
+    /// so it never produces more code than the relooper.
+    fn test_dominators_smaller() {
+        for (file, _) in CONTROL_FLOW_FILES {
+            let relooper = module_from_file(file, |_| ()).emit().unwrap().len();
+            let dominators =
+                module_from_file(file, |module| module.set_structurer(Structurer::Dominators))
+                    .emit()
+                    .unwrap()
+                    .len();
+            assert!(
+                dominators <= relooper,
+                "{}: {} > {}",
+                file,
+                dominators,
+                relooper
+            );
+        }
+    }
+
+    #[test]
+    /// Nor does it execute more instructions.
+    fn test_dominators_faster() {
+        for (file, cases) in CONTROL_FLOW_FILES {
+            for (arg, _) in cases {
+                let relooper = fuel_of_func_name(module_from_file(file, |_| ()), *arg);
+                let dominators = fuel_of_func_name(
+                    module_from_file(file, |module| module.set_structurer(Structurer::Dominators)),
+                    *arg,
+                );
+                assert!(
+                    dominators <= relooper,
+                    "{}({}): {} > {}",
+                    file,
+                    arg,
+                    dominators,
+                    relooper
+                );
+            }
+        }
+    }
+
+    /// Configures a module to handle irreducible control flow in each of the
+    /// ways which are supported.
+    fn irreducible_configs() -> [fn(&mut WasmModule); 4] {
+        [
+            |_| (),
+            |module| {
+                module.set_structurer(Structurer::Dominators);
+                module.set_irreducible_control_flow(IrreducibleControlFlow::Dispatch);
+            },
+            |module| {
+                module.set_structurer(Structurer::Dominators);
+                module.set_irreducible_control_flow(IrreducibleControlFlow::Split { budget: 100 });
+            },
+            // too small for any blocks to be duplicated
+            |module| {
+                module.set_structurer(Structurer::Dominators);
+                module.set_irreducible_control_flow(IrreducibleControlFlow::Split { budget: 1 });
+            },
+        ]
+    }
+
+    #[test]
+    /// A loop which is entered at either of its two blocks.
+    fn test_two_entries() {
+        for configure in irreducible_configs() {
+            for (arg, expected) in [(1, 10), (0, 11)] {
+                let module =
+                    module_from_file("src/filetests/irreducible/two_entries.clif", configure);
+                let res: i32 = call_func_name(module, arg).unwrap();
+                assert_eq!(res, expected);
+            }
+        }
+    }
+
+    #[test]
+    /// A loop with two entries, nested in another loop.
+    fn test_nested_irreducible() {
+        for configure in irreducible_configs() {
+            for (arg, expected) in [(0, 0), (1, 8), (2, 12), (5, 32)] {
+                let module = module_from_file("src/filetests/irreducible/nested.clif", configure);
+                let res: i32 = call_func_name(module, arg).unwrap();
+                assert_eq!(res, expected);
+            }
+        }
+    }
+
+    #[test]
+    /// Splitting a loop with two entries duplicates one of its blocks, rather
+    /// than dispatching on the label.
+    fn test_split_within_budget() {
+        let file = "src/filetests/irreducible/two_entries.clif";
+        let [_, dispatch, split, over_budget] = irreducible_configs();
+        let dispatch = module_from_file(file, dispatch).emit().unwrap();
+        assert_ne!(module_from_file(file, split).emit().unwrap(), dispatch);
+        assert_eq!(
+            module_from_file(file, over_budget).emit().unwrap(),
+            dispatch
+        );
+    }
+}
+
+mod linking {
+    use cranelift_codegen::{
+        ir::{self, AbiParam},
+        isa::CallConv,
+    };
+    use cranelift_module::{Linkage, Module};
+    use walrus::ModuleConfig;
+
+    use crate::{
+        tests::utils::{flags, wasm32},
+        MemoryConfig, WasmModule, DEFAULT_IMPORT_MODULE,
+    };
+
+    #[test]
+    fn test_import_function() {
+        let mut module = WasmModule::new(
+            wasm32(),
+            flags(),
+            ModuleConfig::new(),
+            MemoryConfig::default(),
+        )
+        .unwrap();
+        module.set_import_module("host_log", "host");
+
+        let sig = ir::Signature {
+            params: vec![AbiParam::new(ir::types::I32)],
+            returns: vec![AbiParam::new(ir::types::I64)],
+            call_conv: CallConv::SystemV,
+        };
+
+        module
+            .declare_function("host_log", Linkage::Import, &sig)
+            .unwrap();
+        module
+            .declare_function("abort", Linkage::Import, &sig)
+            .unwrap();
+        // declaring a function a second time should not import it twice
+        module
+            .declare_function("abort", Linkage::Import, &sig)
+            .unwrap();
+
+        let wasm = module.emit().unwrap();
+        let parsed = walrus::Module::from_buffer(&wasm).unwrap();
+
+        let mut imports = parsed
+            .imports
+            .iter()
+            .map(|import| (import.module.as_str(), import.name.as_str()))
+            .collect::<Vec<_>>();
+        imports.sort();
+        assert_eq!(
+            imports,
+            vec![(DEFAULT_IMPORT_MODULE, "abort"), ("host", "host_log")]
+        );
+
+        let func = parsed.funcs.by_name("host_log").unwrap();
+        let ty = parsed.types.get(parsed.funcs.get(func).ty());
+        assert_eq!(ty.params(), &[walrus::ValType::I32]);
+        assert_eq!(ty.results(), &[walrus::ValType::I64]);
+    }
+}
+
+mod calls {
+    use cranelift_codegen::ir::{self, InstBuilder};
+    use cranelift_module::{Linkage, Module};
+    use walrus::ModuleConfig;
+    use wasmtime::Func;
+
+    use crate::{
+        tests::utils::{call_export, define_function, flags, signature, wasm32},
+        MemoryConfig, WasmModule,
+    };
+
+    #[test]
+    /// Calls a function which is declared (and defined) after its caller.
+    fn test_call_declared_after_caller() {
+        let mut module = WasmModule::new(
+            wasm32(),
+            flags(),
+            ModuleConfig::new(),
+            MemoryConfig::default(),
+        )
+        .unwrap();
+
+        let sig = signature(&[ir::types::I32], &[ir::types::I32]);
+        let caller = module
+            .declare_function("func_name", Linkage::Export, &sig)
+            .unwrap();
+        let callee = module
+            .declare_function("double", Linkage::Local, &sig)
+            .unwrap();
+
+        define_function(&mut module, caller, sig.clone(), |builder, module| {
+            let double = module.declare_func_in_func(callee, builder.func);
+            let entry = builder.create_block();
+            builder.append_block_params_for_function_params(entry);
+            builder.switch_to_block(entry);
+            let x = builder.block_params(entry)[0];
+            let call = builder.ins().call(double, &[x]);
+            let doubled = builder.inst_results(call)[0];
+            let res = builder.ins().iadd_imm(doubled, 1);
+            builder.ins().return_(&[res]);
+            builder.seal_block(entry);
+        });
+
+        define_function(&mut module, callee, sig, |builder, _| {
+            let entry = builder.create_block();
+            builder.append_block_params_for_function_params(entry);
+            builder.switch_to_block(entry);
+            let x = builder.block_params(entry)[0];
+            let res = builder.ins().iadd(x, x);
+            builder.ins().return_(&[res]);
+            builder.seal_block(entry);
+        });
+
+        let res: i32 = call_export(&mut module, "func_name", 20, |_| vec![]);
+        assert_eq!(res, 41);
+    }
+
+    #[test]
+    /// Binds the results of a function with multiple return values to locals.
+    fn test_call_multiple_results() {
+        let mut module = WasmModule::new(
+            wasm32(),
+            flags(),
+            ModuleConfig::new(),
+            MemoryConfig::default(),
+        )
+        .unwrap();
+
+        let caller_sig = signature(&[ir::types::I32, ir::types::I32], &[ir::types::I32]);
+        let callee_sig = signature(
+            &[ir::types::I32, ir::types::I32],
+            &[ir::types::I32, ir::types::I32],
+        );
+        let caller = module
+            .declare_function("func_name", Linkage::Export, &caller_sig)
+            .unwrap();
+        let callee = module
+            .declare_function("sum_and_difference", Linkage::Local, &callee_sig)
+            .unwrap();
+
+        define_function(&mut module, callee, callee_sig, |builder, _| {
+            let entry = builder.create_block();
+            builder.append_block_params_for_function_params(entry);
+            builder.switch_to_block(entry);
+            let (x, y) = (
+                builder.block_params(entry)[0],
+                builder.block_params(entry)[1],
+            );
+            let sum = builder.ins().iadd(x, y);
+            let difference = builder.ins().isub(x, y);
+            builder.ins().return_(&[sum, difference]);
+            builder.seal_block(entry);
+        });
+
+        define_function(&mut module, caller, caller_sig, |builder, module| {
+            let callee = module.declare_func_in_func(callee, builder.func);
+            let entry = builder.create_block();
+            builder.append_block_params_for_function_params(entry);
+            builder.switch_to_block(entry);
+            let (x, y) = (
+                builder.block_params(entry)[0],
+                builder.block_params(entry)[1],
+            );
+            let call = builder.ins().call(callee, &[x, y]);
+            let (sum, difference) = (builder.inst_results(call)[0], builder.inst_results(call)[1]);
+            // (x + y) - (x - y) = 2y
+            let res = builder.ins().isub(sum, difference);
+            builder.ins().return_(&[res]);
+            builder.seal_block(entry);
+        });
+
+        let res: i32 = call_export(&mut module, "func_name", (100, 21), |_| vec![]);
+        assert_eq!(res, 42);
+    }
+
+    #[test]
+    fn test_call_import() {
+        let mut module = WasmModule::new(
+            wasm32(),
+            flags(),
+            ModuleConfig::new(),
+            MemoryConfig::default(),
+        )
+        .unwrap();
+
+        let sig = signature(&[ir::types::I32], &[ir::types::I32]);
+        let caller = module
+            .declare_function("func_name", Linkage::Export, &sig)
+            .unwrap();
+        let host = module
+            .declare_function("host_square", Linkage::Import, &sig)
+            .unwrap();
+
+        define_function(&mut module, caller, sig, |builder, module| {
+            let host = module.declare_func_in_func(host, builder.func);
+            let entry = builder.create_block();
+            builder.append_block_params_for_function_params(entry);
+            builder.switch_to_block(entry);
+            let x = builder.block_params(entry)[0];
+            let call = builder.ins().call(host, &[x]);
+            let squared = builder.inst_results(call)[0];
+            let res = builder.ins().iadd(squared, x);
+            builder.ins().return_(&[res]);
+            builder.seal_block(entry);
+        });
+
+        let res: i32 = call_export(&mut module, "func_name", 6, |store| {
+            vec![Func::wrap(store, |x: i32| x * x).into()]
+        });
+        assert_eq!(res, 42);
+    }
+
+    #[test]
+    /// Narrow arguments are extended as the callee's signature requires.
+    fn test_call_extended_arguments() {
+        let mut module = WasmModule::new(
+            wasm32(),
+            flags(),
+            ModuleConfig::new(),
+            MemoryConfig::default(),
+        )
+        .unwrap();
+
+        let caller_sig = signature(&[ir::types::I32], &[ir::types::I32]);
+        let mut host_sig = signature(&[], &[ir::types::I32]);
+        host_sig.params = vec![
+            ir::AbiParam::new(ir::types::I8).uext(),
+            ir::AbiParam::new(ir::types::I8).sext(),
+        ];
+        let caller = module
+            .declare_function("func_name", Linkage::Export, &caller_sig)
+            .unwrap();
+        let host = module
+            .declare_function("host", Linkage::Import, &host_sig)
+            .unwrap();
+
+        define_function(&mut module, caller, caller_sig, |builder, module| {
+            let host = module.declare_func_in_func(host, builder.func);
+            let entry = builder.create_block();
+            builder.append_block_params_for_function_params(entry);
+            builder.switch_to_block(entry);
+            let x = builder.block_params(entry)[0];
+            let x = builder.ins().ireduce(ir::types::I8, x);
+            let call = builder.ins().call(host, &[x, x]);
+            let res = builder.inst_results(call)[0];
+            builder.ins().return_(&[res]);
+            builder.seal_block(entry);
+        });
+
+        let res: i32 = call_export(&mut module, "func_name", 0x3ff, |store| {
+            vec![Func::wrap(store, |x: i32, y: i32| {
+                assert_eq!((x, y), (0xff, -1));
+                x + y
+            })
+            .into()]
+        });
+        assert_eq!(res, 0xfe);
+    }
+}
+
+mod indirect_calls {
+    use cranelift_codegen::ir::{self, InstBuilder};
+    use cranelift_module::{Linkage, Module};
+    use walrus::ModuleConfig;
+    use wasmtime::{Engine, Instance, Store};
+
+    use crate::{
+        tests::utils::{call_export, define_function, flags, signature, wasm32},
+        MemoryConfig, WasmModule,
+    };
+
+    /// Declares and defines `triple(x) = x + x + x`.
+    fn define_triple(module: &mut WasmModule) -> cranelift_module::FuncId {
+        let sig = signature(&[ir::types::I32], &[ir::types::I32]);
+        let triple = module
+            .declare_function("triple", Linkage::Local, &sig)
+            .unwrap();
+        define_function(module, triple, sig, |builder, _| {
+            let entry = builder.create_block();
+            builder.append_block_params_for_function_params(entry);
+            builder.switch_to_block(entry);
+            let x = builder.block_params(entry)[0];
+            let double = builder.ins().iadd(x, x);
+            let res = builder.ins().iadd(double, x);
+            builder.ins().return_(&[res]);
+            builder.seal_block(entry);
+        });
+        triple
+    }
+
+    #[test]
+    fn test_call_indirect() {
+        let mut module = WasmModule::new(
+            wasm32(),
+            flags(),
+            ModuleConfig::new(),
+            MemoryConfig::default(),
+        )
+        .unwrap();
+
+        let sig = signature(&[ir::types::I32], &[ir::types::I32]);
+        let caller = module
+            .declare_function("func_name", Linkage::Export, &sig)
+            .unwrap();
+        let triple = define_triple(&mut module);
+
+        define_function(&mut module, caller, sig.clone(), |builder, module| {
+            let triple = module.declare_func_in_func(triple, builder.func);
+            let sig_ref = builder.import_signature(sig);
+            let entry = builder.create_block();
+            builder.append_block_params_for_function_params(entry);
+            builder.switch_to_block(entry);
+            let x = builder.block_params(entry)[0];
+            let pointer = builder.ins().func_addr(ir::types::I32, triple);
+            let call = builder.ins().call_indirect(sig_ref, pointer, &[x]);
+            let res = builder.inst_results(call)[0];
+            builder.ins().return_(&[res]);
+            builder.seal_block(entry);
+        });
+
+        let res: i32 = call_export(&mut module, "func_name", 14, |_| vec![]);
+        assert_eq!(res, 42);
+    }
+
+    #[test]
+    /// Calls a function through the exported function table from the host.
+    fn test_export_function_table() {
+        let mut module = WasmModule::new(
+            wasm32(),
+            flags(),
+            ModuleConfig::new(),
+            MemoryConfig::default(),
+        )
+        .unwrap();
+        let triple = define_triple(&mut module);
+        module.export_function_table("table");
+        let index = module.function_table_index(triple);
+        assert_ne!(index, 0, "index zero should be reserved for null pointers");
+
+        let wasm = module.emit().unwrap();
+        let engine = Engine::default();
+        let module = wasmtime::Module::new(&engine, wasm).unwrap();
+        let mut store = Store::new(&engine, ());
+        let instance = Instance::new(&mut store, &module, &[]).unwrap();
+        let table = instance.get_table(&mut store, "table").unwrap();
+
+        assert!(table.get(&mut store, 0).unwrap().unwrap_funcref().is_none());
+        let func = table
+            .get(&mut store, index)
+            .unwrap()
+            .unwrap_funcref()
+            .cloned()
+            .unwrap();
+        let func = func.typed::<i32, i32, _>(&store).unwrap();
+        assert_eq!(func.call(&mut store, 5).unwrap(), 15);
+    }
+}
+
+mod data {
+    use cranelift_codegen::ir::{self, InstBuilder};
+    use cranelift_module::{DataContext, Linkage, Module};
+    use walrus::{ActiveDataLocation, DataKind, ModuleConfig};
+
+    use crate::{
+        tests::utils::{call_export, define_function, flags, signature, wasm32},
+        MemoryConfig, WasmModule,
+    };
+
+    /// Returns the contents of the active data segment placed at `address`.
+    fn segment_at(wasm: &[u8], address: u64) -> Option<Vec<u8>> {
+        let module = walrus::Module::from_buffer(wasm).unwrap();
+        let segment = module.data.iter().find(|data| match &data.kind {
+            DataKind::Active(active) => {
+                active.location == ActiveDataLocation::Absolute(address as u32)
+            }
+            DataKind::Passive => false,
+        });
+        segment.map(|data| data.value.clone())
+    }
+
+    #[test]
+    fn test_data_layout() {
+        let mut module = WasmModule::new(
+            wasm32(),
+            flags(),
+            ModuleConfig::new(),
+            MemoryConfig::default(),
+        )
+        .unwrap();
+
+        let greeting = module
+            .declare_data("greeting", Linkage::Local, false, false)
+            .unwrap();
+        let zeroed = module.declare_anonymous_data(true, false).unwrap();
+        let aligned = module
+            .declare_data("aligned", Linkage::Export, true, false)
+            .unwrap();
+
+        let mut ctx = DataContext::new();
+        ctx.define(b"hey".to_vec().into_boxed_slice());
+        module.define_data(greeting, &ctx).unwrap();
+
+        ctx.clear();
+        ctx.define_zeroinit(5);
+        module.define_data(zeroed, &ctx).unwrap();
+
+        ctx.clear();
+        ctx.define(vec![1, 2, 3, 4].into_boxed_slice());
+        ctx.set_align(64);
+        module.define_data(aligned, &ctx).unwrap();
+
+        let greeting_address = module.data_address(greeting).unwrap();
+        let zeroed_address = module.data_address(zeroed).unwrap();
+        let aligned_address = module.data_address(aligned).unwrap();
+
+        assert_ne!(greeting_address, 0);
+        assert!(zeroed_address >= greeting_address + 3);
+        assert!(aligned_address >= zeroed_address + 5);
+        assert_eq!(aligned_address % 64, 0);
+
+        // defining a data object twice is an error
+        assert!(module.define_data(aligned, &ctx).is_err());
+
+        // as is defining one without setting its contents
+        let uninitialized = module.declare_anonymous_data(false, false).unwrap();
+        assert!(module
+            .define_data(uninitialized, &DataContext::new())
+            .is_err());
+
+        let wasm = module.emit().unwrap();
+        assert_eq!(segment_at(&wasm, greeting_address).unwrap(), b"hey");
+        assert_eq!(segment_at(&wasm, aligned_address).unwrap(), &[1, 2, 3, 4]);
+        // memory starts out zeroed, so no segment is needed for this
+        assert!(segment_at(&wasm, zeroed_address).is_none());
+    }
+
+    #[test]
+    fn test_data_relocations() {
+        let mut module = WasmModule::new(
+            wasm32(),
+            flags(),
+            ModuleConfig::new(),
+            MemoryConfig::default(),
+        )
+        .unwrap();
+
+        let sig = signature(&[], &[ir::types::I32]);
+        let method = module
+            .declare_function("method", Linkage::Import, &sig)
+            .unwrap();
+        let vtable = module
+            .declare_data("vtable", Linkage::Local, false, false)
+            .unwrap();
+        let name = module
+            .declare_data("name", Linkage::Local, false, false)
+            .unwrap();
+
+        // vtable = { method, &name[1], &vtable }
+        let mut ctx = DataContext::new();
+        ctx.define_zeroinit(12);
+        let method_ref = module.declare_func_in_data(method, &mut ctx);
+        let name_ref = module.declare_data_in_data(name, &mut ctx);
+        let vtable_ref = module.declare_data_in_data(vtable, &mut ctx);
+        ctx.write_function_addr(0, method_ref);
+        // `name` has not yet been defined, so this has to be resolved later
+        ctx.write_data_addr(4, name_ref, 1);
+        ctx.write_data_addr(8, vtable_ref, 0);
+        module.define_data(vtable, &ctx).unwrap();
+
+        ctx.clear();
+        ctx.define(b"vtable".to_vec().into_boxed_slice());
+        module.define_data(name, &ctx).unwrap();
+
+        let vtable_address = module.data_address(vtable).unwrap() as u32;
+        let name_address = module.data_address(name).unwrap() as u32;
+        let method_index = module.function_table_index(method);
+
+        let expected = [method_index, name_address + 1, vtable_address]
+            .iter()
+            .flat_map(|word| word.to_le_bytes())
+            .collect::<Vec<_>>();
+        let wasm = module.emit().unwrap();
+        assert_eq!(segment_at(&wasm, vtable_address as u64).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_unresolvable_data_relocation() {
+        let mut module = WasmModule::new(
+            wasm32(),
+            flags(),
+            ModuleConfig::new(),
+            MemoryConfig::default(),
+        )
+        .unwrap();
+
+        let imported = module
+            .declare_data("imported", Linkage::Import, false, false)
+            .unwrap();
+        let pointer = module
+            .declare_data("pointer", Linkage::Local, false, false)
+            .unwrap();
+
+        let mut ctx = DataContext::new();
+        ctx.define_zeroinit(4);
+        let imported_ref = module.declare_data_in_data(imported, &mut ctx);
+        ctx.write_data_addr(0, imported_ref, 0);
+        assert!(module.define_data(pointer, &ctx).is_err());
+
+        // the module should be left unchanged by the failed definition
+        assert!(module.data_address(pointer).is_none());
+        ctx.clear();
+        ctx.define_zeroinit(4);
+        module.define_data(pointer, &ctx).unwrap();
+    }
+
+    #[test]
+    fn test_undefined_data_relocation() {
+        let mut module = WasmModule::new(
+            wasm32(),
+            flags(),
+            ModuleConfig::new(),
+            MemoryConfig::default(),
+        )
+        .unwrap();
+
+        let undefined = module
+            .declare_data("undefined", Linkage::Local, false, false)
+            .unwrap();
+        let pointer = module
+            .declare_data("pointer", Linkage::Local, false, false)
+            .unwrap();
+
+        let mut ctx = DataContext::new();
+        ctx.define_zeroinit(4);
+        let undefined_ref = module.declare_data_in_data(undefined, &mut ctx);
+        ctx.write_data_addr(0, undefined_ref, 0);
+        module.define_data(pointer, &ctx).unwrap();
+
+        // the pointer can never be resolved
+        assert!(module.emit().is_err());
     }
-}
 
-mod control_flow {
-    use rusty_fork::rusty_fork_test;
+    #[test]
+    /// Refers to a data object (which is defined after the function using it)
+    /// with `symbol_value` and `global_value`.
+    fn test_global_values() {
+        let mut module = WasmModule::new(
+            wasm32(),
+            flags(),
+            ModuleConfig::new(),
+            MemoryConfig::default(),
+        )
+        .unwrap();
+
+        let sig = signature(&[], &[ir::types::I32]);
+        let func = module
+            .declare_function("func_name", Linkage::Export, &sig)
+            .unwrap();
+        let numbers = module
+            .declare_data("numbers", Linkage::Local, false, false)
+            .unwrap();
+
+        define_function(&mut module, func, sig, |builder, module| {
+            let base = module.declare_data_in_func(numbers, builder.func);
+            let second = builder.create_global_value(ir::GlobalValueData::IAddImm {
+                base,
+                offset: 4.into(),
+                global_type: ir::types::I32,
+            });
+            let third = builder.create_global_value(ir::GlobalValueData::Load {
+                base: second,
+                offset: 4.into(),
+                global_type: ir::types::I32,
+                readonly: true,
+            });
+
+            let entry = builder.create_block();
+            builder.switch_to_block(entry);
+            let address = builder.ins().symbol_value(ir::types::I32, base);
+            let value = builder.ins().global_value(ir::types::I32, third);
+            let res = builder.ins().iadd(address, value);
+            builder.ins().return_(&[res]);
+            builder.seal_block(entry);
+        });
+
+        let mut ctx = DataContext::new();
+        ctx.define(vec![0, 0, 0, 0, 0, 0, 0, 0, 42, 0, 0, 0].into_boxed_slice());
+        module.define_data(numbers, &ctx).unwrap();
+        let address = module.data_address(numbers).unwrap() as i32;
 
-    use super::{enable_log, test_from_file};
+        let res: i32 = call_export(&mut module, "func_name", (), |_| vec![]);
+        assert_eq!(res, address + 42);
+    }
 
     #[test]
-    fn test_brnz() {
-        test_from_file(1, "src/filetests/wasmtime/brnz.clif", |res: i32| -> bool {
-            res == 1
+    /// Loads a `global_value` from memory which is written to before the value
+    /// is used: the value has to be the one loaded before the store.
+    fn test_mutable_global_value() {
+        let mut module = WasmModule::new(
+            wasm32(),
+            flags(),
+            ModuleConfig::new(),
+            MemoryConfig::default(),
+        )
+        .unwrap();
+
+        let sig = signature(&[], &[ir::types::I32]);
+        let func = module
+            .declare_function("func_name", Linkage::Export, &sig)
+            .unwrap();
+        let number = module
+            .declare_data("number", Linkage::Local, true, false)
+            .unwrap();
+
+        define_function(&mut module, func, sig, |builder, module| {
+            let base = module.declare_data_in_func(number, builder.func);
+            let loaded = builder.create_global_value(ir::GlobalValueData::Load {
+                base,
+                offset: 0.into(),
+                global_type: ir::types::I32,
+                readonly: false,
+            });
+
+            let entry = builder.create_block();
+            builder.switch_to_block(entry);
+            let value = builder.ins().global_value(ir::types::I32, loaded);
+            let address = builder.ins().symbol_value(ir::types::I32, base);
+            let zero = builder.ins().iconst(ir::types::I32, 0);
+            builder
+                .ins()
+                .store(ir::MemFlags::trusted(), zero, address, 0);
+            builder.ins().return_(&[value]);
+            builder.seal_block(entry);
         });
-        test_from_file(0, "src/filetests/wasmtime/brnz.clif", |res: i32| -> bool {
-            res == 2
+
+        let mut ctx = DataContext::new();
+        ctx.define(vec![42, 0, 0, 0].into_boxed_slice());
+        module.define_data(number, &ctx).unwrap();
+
+        let res: i32 = call_export(&mut module, "func_name", (), |_| vec![]);
+        assert_eq!(res, 42);
+    }
+}
+
+mod memory {
+    use cranelift_module::{DataContext, Linkage, Module};
+    use target_lexicon::triple;
+    use walrus::ModuleConfig;
+    use wasmtime::{Config, Engine, Instance, Memory, MemoryType, Store};
+
+    use crate::{
+        tests::utils::{flags, wasm32},
+        MemoryConfig, MemoryImport, WasmModule, PAGE_SIZE,
+    };
+
+    /// Defines a data object containing `contents`, returning its address.
+    fn define_bytes(module: &mut WasmModule, contents: &[u8]) -> usize {
+        let data = module.declare_anonymous_data(false, false).unwrap();
+        let mut ctx = DataContext::new();
+        ctx.define(contents.to_vec().into_boxed_slice());
+        module.define_data(data, &ctx).unwrap();
+        module.data_address(data).unwrap() as usize
+    }
+
+    #[test]
+    fn test_exported_memory() {
+        let mut module = WasmModule::new(
+            wasm32(),
+            flags(),
+            ModuleConfig::new(),
+            MemoryConfig {
+                initial_pages: 2,
+                maximum_pages: Some(3),
+                export: Some("linear_memory".to_string()),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let address = define_bytes(&mut module, b"hello");
+
+        let wasm = module.emit().unwrap();
+        let engine = Engine::default();
+        let module = wasmtime::Module::new(&engine, wasm).unwrap();
+        let mut store = Store::new(&engine, ());
+        let instance = Instance::new(&mut store, &module, &[]).unwrap();
+        let memory = instance.get_memory(&mut store, "linear_memory").unwrap();
+
+        assert_eq!(memory.data_size(&store), 2 * PAGE_SIZE as usize);
+        assert_eq!(&memory.data(&store)[address..address + 5], b"hello");
+    }
+
+    #[test]
+    fn test_imported_memory() {
+        let mut module = WasmModule::new(
+            wasm32(),
+            flags(),
+            ModuleConfig::new(),
+            MemoryConfig {
+                initial_pages: 2,
+                import: Some(MemoryImport {
+                    module: "host".to_string(),
+                    name: "memory".to_string(),
+                }),
+                export: None,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let address = define_bytes(&mut module, b"hello");
+
+        let wasm = module.emit().unwrap();
+        let engine = Engine::default();
+        let module = wasmtime::Module::new(&engine, wasm).unwrap();
+        let mut store = Store::new(&engine, ());
+        let memory = Memory::new(&mut store, MemoryType::new(2, None)).unwrap();
+        Instance::new(&mut store, &module, &[memory.into()]).unwrap();
+
+        assert_eq!(&memory.data(&store)[address..address + 5], b"hello");
+    }
+
+    #[test]
+    fn test_memory64() {
+        for import in [false, true] {
+            let mut module = WasmModule::new(
+                triple!("wasm64-unknown-unknown"),
+                flags(),
+                ModuleConfig::new(),
+                MemoryConfig {
+                    initial_pages: 2,
+                    memory64: true,
+                    import: import.then(|| MemoryImport {
+                        module: "host".to_string(),
+                        name: "memory".to_string(),
+                    }),
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+            let address = define_bytes(&mut module, b"hello");
+
+            let wasm = module.emit().unwrap();
+            let engine = Engine::new(Config::new().wasm_memory64(true)).unwrap();
+            let module = wasmtime::Module::new(&engine, wasm).unwrap();
+            let mut store = Store::new(&engine, ());
+            let memory = if import {
+                let memory = Memory::new(&mut store, MemoryType::new64(2, None)).unwrap();
+                Instance::new(&mut store, &module, &[memory.into()]).unwrap();
+                memory
+            } else {
+                let instance = Instance::new(&mut store, &module, &[]).unwrap();
+                instance.get_memory(&mut store, "memory").unwrap()
+            };
+
+            assert!(memory.ty(&store).is_64());
+            assert_eq!(&memory.data(&store)[address..address + 5], b"hello");
+        }
+    }
+
+    #[test]
+    fn test_invalid_memory_config() {
+        let invalid = [
+            MemoryConfig {
+                shared: true,
+                maximum_pages: None,
+                ..Default::default()
+            },
+            MemoryConfig {
+                initial_pages: 10,
+                maximum_pages: Some(5),
+                ..Default::default()
+            },
+            // the pointer type is `i32`
+            MemoryConfig {
+                memory64: true,
+                ..Default::default()
+            },
+        ];
+
+        for config in invalid {
+            assert!(WasmModule::new(wasm32(), flags(), ModuleConfig::new(), config).is_err());
+        }
+    }
+
+    #[test]
+    fn test_data_larger_than_memory() {
+        let mut module = WasmModule::new(
+            wasm32(),
+            flags(),
+            ModuleConfig::new(),
+            MemoryConfig {
+                initial_pages: 2,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        // the stack occupies the first page
+        let data = module
+            .declare_data("huge", Linkage::Local, false, false)
+            .unwrap();
+        let mut ctx = DataContext::new();
+        ctx.define_zeroinit(PAGE_SIZE as usize + 1);
+        assert!(module.define_data(data, &ctx).is_err());
+    }
+}
+
+mod stack {
+    use cranelift_codegen::ir::{self, InstBuilder, StackSlotData, StackSlotKind};
+    use cranelift_module::{Linkage, Module};
+    use walrus::ModuleConfig;
+
+    use crate::{
+        tests::utils::{call_export, define_function, flags, signature, wasm32},
+        MemoryConfig, StackConfig, StackPlacement, WasmModule,
+    };
+
+    #[test]
+    fn test_stack_slots() {
+        let mut module = WasmModule::new(
+            wasm32(),
+            flags(),
+            ModuleConfig::new(),
+            MemoryConfig::default(),
+        )
+        .unwrap();
+
+        let sig = signature(&[ir::types::I32], &[ir::types::I32]);
+        let func = module
+            .declare_function("func_name", Linkage::Export, &sig)
+            .unwrap();
+
+        define_function(&mut module, func, sig, |builder, _| {
+            let first =
+                builder.create_stack_slot(StackSlotData::new(StackSlotKind::ExplicitSlot, 4));
+            let second =
+                builder.create_stack_slot(StackSlotData::new(StackSlotKind::ExplicitSlot, 8));
+
+            let entry = builder.create_block();
+            builder.append_block_params_for_function_params(entry);
+            builder.switch_to_block(entry);
+            let x = builder.block_params(entry)[0];
+            builder.ins().stack_store(x, first, 0);
+            let old = builder.ins().stack_load(ir::types::I32, first, 0);
+            // overwriting the slot must not change the value loaded before
+            let y = builder.ins().iadd_imm(x, 5);
+            builder.ins().stack_store(y, first, 0);
+            let new = builder.ins().stack_load(ir::types::I32, first, 0);
+            builder.ins().stack_store(new, second, 4);
+            let copied = builder.ins().stack_load(ir::types::I32, second, 4);
+            let res = builder.ins().iadd(old, copied);
+            builder.ins().return_(&[res]);
+            builder.seal_block(entry);
         });
+
+        let res: i32 = call_export(&mut module, "func_name", 10, |_| vec![]);
+        assert_eq!(res, 25);
     }
 
     #[test]
-    fn test_brz() {
-        test_from_file(0, "src/filetests/wasmtime/brz.clif", |res: i32| -> bool {
-            res == 1
+    /// Checks that the stack frame is placed at the top of the (configured)
+    /// stack, and is released when the function returns.
+    fn test_stack_frame() {
+        let mut module = WasmModule::new(
+            wasm32(),
+            flags(),
+            ModuleConfig::new(),
+            MemoryConfig {
+                stack: StackConfig {
+                    size: 4096,
+                    placement: StackPlacement::Top(8192),
+                },
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        let address_sig = signature(&[], &[ir::types::I32]);
+        let address = module
+            .declare_function("address", Linkage::Local, &address_sig)
+            .unwrap();
+        let func = module
+            .declare_function("func_name", Linkage::Export, &address_sig)
+            .unwrap();
+
+        define_function(&mut module, address, address_sig.clone(), |builder, _| {
+            let slot =
+                builder.create_stack_slot(StackSlotData::new(StackSlotKind::ExplicitSlot, 24));
+            let entry = builder.create_block();
+            builder.switch_to_block(entry);
+            let res = builder.ins().stack_addr(ir::types::I32, slot, 0);
+            builder.ins().return_(&[res]);
+            builder.seal_block(entry);
         });
-        test_from_file(1, "src/filetests/wasmtime/brz.clif", |res: i32| -> bool {
-            res == 0
+
+        // returns the frame size plus the difference between the two
+        // addresses, which is 32 if the frame is released after each call
+        define_function(&mut module, func, address_sig, |builder, module| {
+            let address = module.declare_func_in_func(address, builder.func);
+            let entry = builder.create_block();
+            builder.switch_to_block(entry);
+            let call = builder.ins().call(address, &[]);
+            let first = builder.inst_results(call)[0];
+            let call = builder.ins().call(address, &[]);
+            let second = builder.inst_results(call)[0];
+            let top = builder.ins().iconst(ir::types::I32, 8192);
+            let size = builder.ins().isub(top, first);
+            let diff = builder.ins().isub(second, first);
+            let res = builder.ins().iadd(size, diff);
+            builder.ins().return_(&[res]);
+            builder.seal_block(entry);
         });
+
+        let res: i32 = call_export(&mut module, "func_name", (), |_| vec![]);
+        assert_eq!(res, 32);
+    }
+}
+
+mod atomics {
+    use cranelift_codegen::ir::{self, AtomicRmwOp, InstBuilder, MemFlags};
+    use cranelift_module::{Linkage, Module, ModuleError};
+    use walrus::ModuleConfig;
+    use wasmtime::{Config, Engine};
+
+    use crate::{
+        tests::utils::{define_function, flags, signature, try_define_function, wasm32},
+        ErrorKind, MemoryConfig, TranslationError, WasmModule,
+    };
+
+    /// Defines a function which applies every atomic instruction to the
+    /// address it is passed.
+    fn define_atomics(module: &mut WasmModule) -> Result<(), ModuleError> {
+        let sig = signature(&[ir::types::I32, ir::types::I64], &[ir::types::I64]);
+        let func = module
+            .declare_function("atomics", Linkage::Export, &sig)
+            .unwrap();
+        try_define_function(module, func, sig, |builder, _| {
+            let entry = builder.create_block();
+            builder.append_block_params_for_function_params(entry);
+            builder.switch_to_block(entry);
+            let (p, x) = (
+                builder.block_params(entry)[0],
+                builder.block_params(entry)[1],
+            );
+            let flags = MemFlags::trusted();
+            builder.ins().atomic_store(flags, x, p);
+            builder.ins().fence();
+            let mut res = x;
+            for op in [
+                AtomicRmwOp::Add,
+                AtomicRmwOp::Xchg,
+                AtomicRmwOp::Nand,
+                AtomicRmwOp::Umin,
+                AtomicRmwOp::Smax,
+            ] {
+                res = builder.ins().atomic_rmw(ir::types::I64, flags, op, p, res);
+            }
+            let narrow = builder.ins().ireduce(ir::types::I16, res);
+            let narrow =
+                builder
+                    .ins()
+                    .atomic_rmw(ir::types::I16, flags, AtomicRmwOp::Smin, p, narrow);
+            let narrow = builder.ins().atomic_cas(flags, p, narrow, narrow);
+            builder.ins().atomic_store(flags, narrow, p);
+            let res = builder.ins().atomic_load(ir::types::I64, flags, p);
+            builder.ins().return_(&[res]);
+            builder.seal_block(entry);
+        })
     }
 
     #[test]
-    fn test_fallthrough() {
-        test_from_file(
-            42,
-            "src/filetests/wasmtime/control.clif",
-            |res: i32| -> bool { res == 42 },
+    fn test_atomics_require_shared_memory() {
+        let mut module = WasmModule::new(
+            wasm32(),
+            flags(),
+            ModuleConfig::new(),
+            MemoryConfig::default(),
+        )
+        .unwrap();
+
+        let err = match define_atomics(&mut module).unwrap_err() {
+            ModuleError::Backend(err) => err,
+            err => panic!("unexpected error: {}", err),
+        };
+        let err = err.downcast_ref::<TranslationError>().unwrap();
+        assert!(matches!(err.kind, ErrorKind::Unsupported(_)));
+        assert_eq!(
+            err.clif.as_deref(),
+            Some("atomic_store.i64 notrap aligned v1, v0")
         );
     }
 
-    rusty_fork_test! {
-        #[test]
-        fn test_loop_2() {
-            enable_log();
-            test_from_file(1, "src/filetests/loop2.clif", |res: i32| -> bool {
-                res == 100
-            });
-            test_from_file(15, "src/filetests/loop2.clif", |res: i32| -> bool {
-                res == 1
-            });
-        }
+    #[test]
+    fn test_atomics_on_shared_memory() {
+        let mut module = WasmModule::new(
+            wasm32(),
+            flags(),
+            ModuleConfig::new(),
+            MemoryConfig {
+                initial_pages: 2,
+                maximum_pages: Some(2),
+                shared: true,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        define_atomics(&mut module).unwrap();
+
+        // big-endian atomics cannot be translated
+        let sig = signature(&[ir::types::I32], &[ir::types::I32]);
+        let func = module
+            .declare_function("big_endian", Linkage::Export, &sig)
+            .unwrap();
+        let err = try_define_function(&mut module, func, sig.clone(), |builder, _| {
+            let entry = builder.create_block();
+            builder.append_block_params_for_function_params(entry);
+            builder.switch_to_block(entry);
+            let p = builder.block_params(entry)[0];
+            let mut flags = MemFlags::trusted();
+            flags.set_endianness(ir::Endianness::Big);
+            let res = builder.ins().atomic_load(ir::types::I32, flags, p);
+            builder.ins().return_(&[res]);
+            builder.seal_block(entry);
+        })
+        .unwrap_err();
+        assert!(matches!(err, ModuleError::Backend(_)));
+        define_function(&mut module, func, sig, |builder, _| {
+            let entry = builder.create_block();
+            builder.append_block_params_for_function_params(entry);
+            builder.switch_to_block(entry);
+            let p = builder.block_params(entry)[0];
+            let res = builder
+                .ins()
+                .atomic_load(ir::types::I32, MemFlags::trusted(), p);
+            builder.ins().return_(&[res]);
+            builder.seal_block(entry);
+        });
+
+        let wasm = module.emit().unwrap();
+        let engine = Engine::new(Config::new().wasm_threads(true)).unwrap();
+        wasmtime::Module::validate(&engine, &wasm).unwrap();
     }
+}
+
+mod isa {
+    use cranelift_codegen::{
+        ir::{self, InstBuilder, MemFlags, StackSlotData, StackSlotKind},
+        settings::{self, Configurable},
+    };
+    use cranelift_module::{DataContext, Linkage, Module};
+    use target_lexicon::{triple, BinaryFormat};
+    use walrus::ModuleConfig;
+    use wasmtime::{Config, Engine, Instance, Store};
+
+    use crate::{
+        tests::utils::{define_function, flags, signature, wasm32},
+        MemoryConfig, WasmIsa, WasmModule,
+    };
 
     #[test]
-    fn test_cond_br() {
-        enable_log();
-        test_from_file(
-            (1, 1),
-            "src/filetests/wasmtime/condbr-i32.clif",
-            |res: i32| -> bool { res == 1 },
-        );
-        test_from_file(
-            (1, 2),
-            "src/filetests/wasmtime/condbr-i32.clif",
-            |res: i32| -> bool { res == 2 },
+    fn test_pointer_type() {
+        let module = WasmModule::new(
+            wasm32(),
+            flags(),
+            ModuleConfig::new(),
+            MemoryConfig::default(),
+        )
+        .unwrap();
+        assert_eq!(module.isa().name(), "wasm32");
+        assert_eq!(module.isa().triple(), &wasm32());
+        assert_eq!(module.isa().pointer_type(), ir::types::I32);
+        assert_eq!(module.target_config().pointer_type(), ir::types::I32);
+        assert_eq!(
+            module.make_signature().call_conv,
+            module.target_config().default_call_conv
         );
+
+        let wasm64 = WasmIsa::new(triple!("wasm64-unknown-unknown"), flags()).unwrap();
+        assert_eq!(wasm64.pointer_type(), ir::types::I64);
     }
 
-    rusty_fork_test! {
-        #[test]
-        fn test_control_flow() {
-            enable_log();
-            test_from_file(
-                14,
-                "src/filetests/control-flow.clif",
-                |res: i32| {
-                    res == 0
-                }
-            );
+    #[test]
+    /// Stores an argument in a stack slot and adds a value loaded from a data
+    /// object to it, using 64-bit pointers.
+    fn test_wasm64() {
+        let mut builder = settings::builder();
+        builder.set("opt_level", "speed").unwrap();
+        let mut module = WasmModule::new(
+            triple!("wasm64-unknown-unknown"),
+            settings::Flags::new(builder),
+            ModuleConfig::new(),
+            MemoryConfig {
+                memory64: true,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(module.isa().name(), "wasm64");
+        assert_eq!(module.isa().flags().opt_level(), settings::OptLevel::Speed);
+        assert_eq!(module.target_config().pointer_type(), ir::types::I64);
+
+        let number = module
+            .declare_data("number", Linkage::Local, false, false)
+            .unwrap();
+        let mut ctx = DataContext::new();
+        ctx.define(7i64.to_le_bytes().to_vec().into_boxed_slice());
+        module.define_data(number, &ctx).unwrap();
+
+        let sig = signature(&[ir::types::I64], &[ir::types::I64]);
+        let func = module
+            .declare_function("func_name", Linkage::Export, &sig)
+            .unwrap();
+        define_function(&mut module, func, sig, |builder, module| {
+            let number = module.declare_data_in_func(number, builder.func);
+            let slot =
+                builder.create_stack_slot(StackSlotData::new(StackSlotKind::ExplicitSlot, 8));
+
+            let entry = builder.create_block();
+            builder.append_block_params_for_function_params(entry);
+            builder.switch_to_block(entry);
+            let x = builder.block_params(entry)[0];
+            builder.ins().stack_store(x, slot, 0);
+            let address = builder.ins().symbol_value(ir::types::I64, number);
+            let y = builder
+                .ins()
+                .load(ir::types::I64, MemFlags::trusted(), address, 0);
+            let x = builder.ins().stack_load(ir::types::I64, slot, 0);
+            let res = builder.ins().iadd(x, y);
+            builder.ins().return_(&[res]);
+            builder.seal_block(entry);
+        });
+
+        let wasm = module.emit().unwrap();
+        let engine = Engine::new(Config::new().wasm_memory64(true)).unwrap();
+        let module = wasmtime::Module::new(&engine, wasm).unwrap();
+        let mut store = Store::new(&engine, ());
+        let instance = Instance::new(&mut store, &module, &[]).unwrap();
+        let func = instance
+            .get_typed_func::<i64, i64, _>(&mut store, "func_name")
+            .unwrap();
+        assert_eq!(func.call(&mut store, 35).unwrap(), 42);
+    }
+
+    #[test]
+    fn test_unsupported_targets() {
+        let flags = flags();
+        assert!(WasmIsa::new(triple!("x86_64-unknown-linux-gnu"), flags.clone()).is_err());
+
+        let mut elf = wasm32();
+        elf.binary_format = BinaryFormat::Elf;
+        assert!(WasmIsa::new(elf.clone(), flags.clone()).is_err());
+        assert!(WasmModule::new(elf, flags, ModuleConfig::new(), MemoryConfig::default()).is_err());
+    }
+}
+
+mod precompiled {
+    use cranelift_codegen::{
+        binemit::Reloc,
+        ir::{self, InstBuilder},
+    };
+    use cranelift_module::{DataContext, Linkage, Module, RelocRecord};
+    use walrus::ModuleConfig;
+
+    use crate::{
+        tests::utils::{call_export, define_function, flags, signature, wasm32},
+        MemoryConfig, WasmModule,
+    };
+
+    /// A relocated immediate (a padded LEB128).
+    const PLACEHOLDER: [u8; 5] = [0x80, 0x80, 0x80, 0x80, 0x00];
+
+    fn reloc(offset: u32, name: ir::ExternalName, addend: i64) -> RelocRecord {
+        RelocRecord {
+            offset,
+            reloc: Reloc::Abs4,
+            name,
+            addend,
         }
     }
+
+    #[test]
+    fn test_define_function_bytes() {
+        let mut module = WasmModule::new(
+            wasm32(),
+            flags(),
+            ModuleConfig::new(),
+            MemoryConfig::default(),
+        )
+        .unwrap();
+
+        let sig = signature(&[ir::types::I32], &[ir::types::I32]);
+        let double = module
+            .declare_function("double", Linkage::Local, &sig)
+            .unwrap();
+        let call_double = module
+            .declare_function("call_double", Linkage::Export, &sig)
+            .unwrap();
+        let add_value = module
+            .declare_function("add_value", Linkage::Export, &sig)
+            .unwrap();
+        let call_pointer = module
+            .declare_function("call_pointer", Linkage::Export, &sig)
+            .unwrap();
+        let value = module
+            .declare_data("value", Linkage::Local, false, false)
+            .unwrap();
+
+        define_function(&mut module, double, sig.clone(), |builder, _| {
+            let entry = builder.create_block();
+            builder.append_block_params_for_function_params(entry);
+            builder.switch_to_block(entry);
+            let x = builder.block_params(entry)[0];
+            let res = builder.ins().iadd(x, x);
+            builder.ins().return_(&[res]);
+            builder.seal_block(entry);
+        });
+
+        // (local.get 0) (call $double)
+        let mut body = vec![0x00, 0x20, 0x00, 0x10];
+        body.extend(PLACEHOLDER);
+        body.push(0x0b);
+        module
+            .define_function_bytes(
+                call_double,
+                &body,
+                &[reloc(4, ir::ExternalName::user(0, double.as_u32()), 0)],
+            )
+            .unwrap();
+
+        // (local.get 0) (i32.load (i32.const $value+4)) (i32.add), through a
+        // local and a block; `value` is only defined afterwards
+        let mut body = vec![0x01, 0x01, 0x7f, 0x20, 0x00, 0x41];
+        body.extend(PLACEHOLDER);
+        body.extend([
+            0x28, 0x02, 0x00, 0x6a, 0x21, 0x01, 0x02, 0x40, 0x0c, 0x00, 0x0b, 0x20, 0x01, 0x0b,
+        ]);
+        module
+            .define_function_bytes(
+                add_value,
+                &body,
+                &[reloc(6, ir::ExternalName::user(1, value.as_u32()), 4)],
+            )
+            .unwrap();
+
+        // (call_indirect (local.get 0) (i32.const $double))
+        let mut body = vec![0x00, 0x20, 0x00, 0x41];
+        body.extend(PLACEHOLDER);
+        body.extend([0x11, 0x00, 0x00, 0x0b]);
+        module
+            .define_function_bytes(
+                call_pointer,
+                &body,
+                &[reloc(4, ir::ExternalName::user(0, double.as_u32()), 0)],
+            )
+            .unwrap();
+
+        let mut ctx = DataContext::new();
+        ctx.define(vec![0, 0, 0, 0, 42, 0, 0, 0].into_boxed_slice());
+        module.define_data(value, &ctx).unwrap();
+
+        let res: i32 = call_export(&mut module, "call_double", 21, |_| vec![]);
+        assert_eq!(res, 42);
+        let res: i32 = call_export(&mut module, "add_value", 1, |_| vec![]);
+        assert_eq!(res, 43);
+        let res: i32 = call_export(&mut module, "call_pointer", 4, |_| vec![]);
+        assert_eq!(res, 8);
+    }
+
+    #[test]
+    fn test_invalid_function_bytes() {
+        let mut module = WasmModule::new(
+            wasm32(),
+            flags(),
+            ModuleConfig::new(),
+            MemoryConfig::default(),
+        )
+        .unwrap();
+
+        let sig = signature(&[], &[ir::types::I32]);
+        let func = module
+            .declare_function("func_name", Linkage::Export, &sig)
+            .unwrap();
+        let name = ir::ExternalName::user(0, func.as_u32());
+
+        // the body does not return a value
+        assert!(module
+            .define_function_bytes(func, &[0x00, 0x0b], &[])
+            .is_err());
+
+        // the relocation does not refer to (the immediate of) a constant
+        let mut body = vec![0x00, 0x41];
+        body.extend(PLACEHOLDER);
+        body.push(0x0b);
+        assert!(module
+            .define_function_bytes(func, &body, &[reloc(3, name, 0)])
+            .is_err());
+
+        // (i32.const 7)
+        module
+            .define_function_bytes(func, &[0x00, 0x41, 0x07, 0x0b], &[])
+            .unwrap();
+        let res: i32 = call_export(&mut module, "func_name", (), |_| vec![]);
+        assert_eq!(res, 7);
+    }
+}
+
+mod errors {
+    use cranelift_codegen::ir::{self, InstBuilder};
+    use cranelift_module::{Linkage, Module, ModuleError};
+    use walrus::ModuleConfig;
+
+    use crate::{
+        tests::utils::{
+            call_export, define_function, flags, signature, try_define_function, wasm32,
+        },
+        ErrorKind, MemoryConfig, TranslationError, WasmModule,
+    };
+
+    #[test]
+    fn test_unsupported_opcode() {
+        let mut module = WasmModule::new(
+            wasm32(),
+            flags(),
+            ModuleConfig::new(),
+            MemoryConfig::default(),
+        )
+        .unwrap();
+
+        let sig = signature(&[ir::types::I32], &[ir::types::I32]);
+        let func = module
+            .declare_function("func_name", Linkage::Export, &sig)
+            .unwrap();
+
+        let err = try_define_function(&mut module, func, sig.clone(), |builder, _| {
+            let entry = builder.create_block();
+            builder.append_block_params_for_function_params(entry);
+            builder.switch_to_block(entry);
+            let x = builder.block_params(entry)[0];
+            let res = builder.ins().uadd_sat(x, x);
+            builder.ins().return_(&[res]);
+            builder.seal_block(entry);
+        })
+        .unwrap_err();
+
+        let err = match err {
+            ModuleError::Backend(err) => err,
+            err => panic!("unexpected error: {}", err),
+        };
+        let err = err.downcast_ref::<TranslationError>().unwrap();
+        assert_eq!(err.kind, ErrorKind::UnsupportedOpcode(ir::Opcode::UaddSat));
+        assert!(err.inst.is_some());
+        assert_eq!(err.clif.as_deref(), Some("v1 = uadd_sat.i32 v0, v0"));
+
+        // the module can still be used (and the function defined again)
+        define_function(&mut module, func, sig, |builder, _| {
+            let entry = builder.create_block();
+            builder.append_block_params_for_function_params(entry);
+            builder.switch_to_block(entry);
+            let x = builder.block_params(entry)[0];
+            let res = builder.ins().iadd(x, x);
+            builder.ins().return_(&[res]);
+            builder.seal_block(entry);
+        });
+        let res: i32 = call_export(&mut module, "func_name", 21, |_| vec![]);
+        assert_eq!(res, 42);
+    }
+
+    #[test]
+    fn test_duplicate_definition() {
+        let mut module = WasmModule::new(
+            wasm32(),
+            flags(),
+            ModuleConfig::new(),
+            MemoryConfig::default(),
+        )
+        .unwrap();
+
+        let sig = signature(&[], &[ir::types::I32]);
+        let func = module
+            .declare_function("func_name", Linkage::Export, &sig)
+            .unwrap();
+
+        define_function(&mut module, func, sig.clone(), |builder, _| {
+            let entry = builder.create_block();
+            builder.switch_to_block(entry);
+            let res = builder.ins().iconst(ir::types::I32, 42);
+            builder.ins().return_(&[res]);
+            builder.seal_block(entry);
+        });
+        let err = try_define_function(&mut module, func, sig, |builder, _| {
+            let entry = builder.create_block();
+            builder.switch_to_block(entry);
+            let res = builder.ins().iconst(ir::types::I32, 7);
+            builder.ins().return_(&[res]);
+            builder.seal_block(entry);
+        })
+        .unwrap_err();
+        assert!(matches!(err, ModuleError::DuplicateDefinition(name) if name == "func_name"));
+
+        // the first definition is left as it was
+        let res: i32 = call_export(&mut module, "func_name", (), |_| vec![]);
+        assert_eq!(res, 42);
+    }
+
+    #[test]
+    fn test_duplicate_definition_from_bytes() {
+        let mut module = WasmModule::new(
+            wasm32(),
+            flags(),
+            ModuleConfig::new(),
+            MemoryConfig::default(),
+        )
+        .unwrap();
+
+        let sig = signature(&[ir::types::I32], &[ir::types::I32]);
+        let func = module
+            .declare_function("func_name", Linkage::Export, &sig)
+            .unwrap();
+
+        // (local.get 0)
+        module
+            .define_function_bytes(func, &[0x00, 0x20, 0x00, 0x0b], &[])
+            .unwrap();
+        // (i32.const 7)
+        let res = module.define_function_bytes(func, &[0x00, 0x41, 0x07, 0x0b], &[]);
+        assert!(matches!(res, Err(ModuleError::DuplicateDefinition(name)) if name == "func_name"));
+        let err = try_define_function(&mut module, func, sig, |builder, _| {
+            let entry = builder.create_block();
+            builder.append_block_params_for_function_params(entry);
+            builder.switch_to_block(entry);
+            let x = builder.block_params(entry)[0];
+            builder.ins().return_(&[x]);
+            builder.seal_block(entry);
+        })
+        .unwrap_err();
+        assert!(matches!(err, ModuleError::DuplicateDefinition(name) if name == "func_name"));
+
+        // the first definition is left as it was
+        let res: i32 = call_export(&mut module, "func_name", 42, |_| vec![]);
+        assert_eq!(res, 42);
+    }
+
+    #[test]
+    fn test_unsupported_signature() {
+        let mut module = WasmModule::new(
+            wasm32(),
+            flags(),
+            ModuleConfig::new(),
+            MemoryConfig::default(),
+        )
+        .unwrap();
+
+        let sig = signature(&[ir::types::I32X4], &[]);
+        assert!(module
+            .declare_function("func_name", Linkage::Export, &sig)
+            .is_err());
+    }
+}
+
+mod check {
+    use cranelift_codegen::ir::{self, condcodes::IntCC, InstBuilder};
+    use cranelift_frontend::{FunctionBuilder, FunctionBuilderContext};
+    use walrus::ModuleConfig;
+
+    use crate::{
+        tests::utils::{flags, signature, wasm32},
+        ErrorKind, MemoryConfig, WasmModule,
+    };
+
+    /// Builds a function with the signature `sig`.
+    fn build_function(
+        sig: ir::Signature,
+        build: impl FnOnce(&mut FunctionBuilder, ir::Block),
+    ) -> ir::Function {
+        let mut func = ir::Function::with_name_signature(ir::ExternalName::user(0, 0), sig);
+        let mut func_ctx = FunctionBuilderContext::new();
+        let mut builder = FunctionBuilder::new(&mut func, &mut func_ctx);
+        let entry = builder.create_block();
+        builder.append_block_params_for_function_params(entry);
+        builder.switch_to_block(entry);
+        (build)(&mut builder, entry);
+        builder.seal_all_blocks();
+        builder.finalize();
+        func
+    }
+
+    #[test]
+    fn test_check_supported_function() {
+        let module = WasmModule::new(
+            wasm32(),
+            flags(),
+            ModuleConfig::new(),
+            MemoryConfig::default(),
+        )
+        .unwrap();
+
+        let func = build_function(
+            signature(&[ir::types::I32], &[ir::types::I32]),
+            |builder, entry| {
+                let x = builder.block_params(entry)[0];
+                let res = builder.ins().iadd_imm(x, 1);
+                builder.ins().return_(&[res]);
+            },
+        );
+        assert!(module.check_function(&func).is_empty());
+    }
+
+    #[test]
+    fn test_check_reports_every_error() {
+        let module = WasmModule::new(
+            wasm32(),
+            flags(),
+            ModuleConfig::new(),
+            MemoryConfig::default(),
+        )
+        .unwrap();
+
+        let mut sig = signature(&[ir::types::I32, ir::types::I32X4], &[ir::types::I32]);
+        sig.params.push(ir::AbiParam::special(
+            ir::types::I32,
+            ir::ArgumentPurpose::StackLimit,
+        ));
+        let func = build_function(sig, |builder, entry| {
+            let x = builder.block_params(entry)[0];
+            let sum = builder.ins().uadd_sat(x, x);
+            let cmp = builder.ins().icmp(IntCC::SignedLessThan, sum, x);
+            let res = builder.ins().bint(ir::types::I32, cmp);
+            builder.ins().return_(&[res]);
+        });
+
+        let kinds = module
+            .check_function(&func)
+            .into_iter()
+            .map(|error| error.kind)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            kinds,
+            vec![
+                ErrorKind::UnsupportedType(ir::types::I32X4),
+                ErrorKind::UnsupportedArgumentPurpose(ir::ArgumentPurpose::StackLimit),
+                ErrorKind::UnsupportedOpcode(ir::Opcode::UaddSat),
+                ErrorKind::UnsupportedOpcode(ir::Opcode::Bint),
+            ]
+        );
+    }
 }
@@ -1,15 +1,27 @@
 use std::{path::Path, thread};
 
 use cranelift_codegen::binemit::{NullStackMapSink, NullTrapSink};
-use cranelift_codegen::{ir, Context};
+use cranelift_codegen::isa::CallConv;
+use cranelift_codegen::{ir, settings, Context};
 use cranelift_frontend::{FunctionBuilder, FunctionBuilderContext};
-use cranelift_module::Module;
+use cranelift_module::{FuncId, Module, ModuleResult};
 use cranelift_reader::parse_functions;
 use log::LevelFilter;
+use target_lexicon::{triple, Triple};
 use walrus::ModuleConfig;
-use wasmtime::{Config, Engine, Instance, Store, WasmParams, WasmResults};
+use wasmtime::{Config, Engine, Extern, Instance, Store, Trap, WasmParams, WasmResults};
 
-use crate::WasmModule;
+use crate::{MemoryConfig, WasmModule};
+
+/// The triple which modules are compiled for in tests.
+pub(crate) fn wasm32() -> Triple {
+    triple!("wasm32-unknown-unknown")
+}
+
+/// The (default) Cranelift settings which modules are compiled with in tests.
+pub(crate) fn flags() -> settings::Flags {
+    settings::Flags::new(settings::builder())
+}
 
 pub(crate) fn enable_log() {
     if std::env::var("ENABLE_DETAILED_CRANELIFT_LOGGING").is_err() {
@@ -28,8 +40,13 @@ pub(crate) fn run_test<Params: WasmParams, Return: WasmResults + std::fmt::Debug
     build: impl FnOnce(&mut FunctionBuilder),
     check: impl FnOnce(Return) -> bool,
 ) {
-    // todo: correct target isa
-    let mut module = WasmModule::new(ModuleConfig::new());
+    let mut module = WasmModule::new(
+        wasm32(),
+        flags(),
+        ModuleConfig::new(),
+        MemoryConfig::default(),
+    )
+    .unwrap();
 
     let func_id = module
         .declare_function("func_name", cranelift_module::Linkage::Export, &sig)
@@ -59,10 +76,10 @@ pub(crate) fn run_test<Params: WasmParams, Return: WasmResults + std::fmt::Debug
         .unwrap();
 
     if std::env::var("PRINT_WAT").is_ok() {
-        println!("{}", module.emit_wat());
+        println!("{}", module.emit_wat().unwrap());
     }
 
-    let wasm = module.emit();
+    let wasm = module.emit().unwrap();
     let engine = Engine::default();
     let module = wasmtime::Module::new(&engine, wasm).unwrap();
     let mut store = Store::new(&engine, ());
@@ -87,13 +104,46 @@ pub(crate) fn test_from_file<Params: WasmParams, Return: WasmResults + std::fmt:
     file: impl AsRef<Path>,
     check: impl FnOnce(Return) -> bool,
 ) {
+    let ret = call_from_file::<Params, Return>(params, file).unwrap();
+    assert!(
+        (check)(ret.clone()),
+        "assertion failed\nnote: the return value was {:#?}",
+        &ret
+    );
+}
+
+/// Calls the (first) function in a file, returning its result or the trap
+/// which it caused.
+///
+/// As with [`test_from_file`], the function is interrupted if it takes longer
+/// than three seconds to run.
+pub(crate) fn call_from_file<Params: WasmParams, Return: WasmResults>(
+    params: Params,
+    file: impl AsRef<Path>,
+) -> Result<Return, Trap> {
+    call_func_name(module_from_file(file, |_| ()), params)
+}
+
+/// Compiles the (first) function in a file into a new module (which is
+/// configured by `configure` first), which exports it as `func_name`.
+pub(crate) fn module_from_file(
+    file: impl AsRef<Path>,
+    configure: impl FnOnce(&mut WasmModule),
+) -> WasmModule {
     let file = ezio::file::read(file);
 
     let funcs = parse_functions(&file).unwrap();
 
     let func = funcs[0].clone();
 
-    let mut module = WasmModule::new(ModuleConfig::new());
+    let mut module = WasmModule::new(
+        wasm32(),
+        flags(),
+        ModuleConfig::new(),
+        MemoryConfig::default(),
+    )
+    .unwrap();
+    configure(&mut module);
 
     let id = module
         .declare_function(
@@ -109,11 +159,21 @@ pub(crate) fn test_from_file<Params: WasmParams, Return: WasmResults + std::fmt:
         .define_function(id, &mut ctx, &mut NullTrapSink {}, &mut NullStackMapSink {})
         .expect("failed to define function");
 
+    module
+}
+
+/// Calls the function exported as `func_name` by `module`, returning its
+/// result or the trap which it caused (it is interrupted if it takes longer
+/// than three seconds to run).
+pub(crate) fn call_func_name<Params: WasmParams, Return: WasmResults>(
+    mut module: WasmModule,
+    params: Params,
+) -> Result<Return, Trap> {
     if std::env::var("PRINT_WAT").is_ok() {
-        println!("{}", module.emit_wat());
+        println!("{}", module.emit_wat().unwrap());
     }
 
-    let wasm = module.emit();
+    let wasm = module.emit().unwrap();
     let engine = Engine::new(Config::new().interruptable(true)).unwrap();
     let module = wasmtime::Module::new(&engine, wasm).unwrap();
     let mut store = Store::new(&engine, ());
@@ -131,10 +191,106 @@ pub(crate) fn test_from_file<Params: WasmParams, Return: WasmResults + std::fmt:
         interrupt_handle.interrupt();
     });
 
-    let ret = func.call(&mut store, params).unwrap();
-    assert!(
-        (check)(ret.clone()),
-        "assertion failed\nnote: the return value was {:#?}",
-        &ret
-    );
+    func.call(&mut store, params)
+}
+
+/// Calls the function exported as `func_name` by `module` (which returns an
+/// `i32`), returning the fuel which it consumed (which is roughly the number of
+/// WebAssembly instructions which it executed).
+pub(crate) fn fuel_of_func_name<Params: WasmParams>(mut module: WasmModule, params: Params) -> u64 {
+    let wasm = module.emit().unwrap();
+    let engine = Engine::new(Config::new().consume_fuel(true)).unwrap();
+    let module = wasmtime::Module::new(&engine, wasm).unwrap();
+    let mut store = Store::new(&engine, ());
+    store.add_fuel(1 << 40).unwrap();
+
+    let instance = Instance::new(&mut store, &module, &[]).unwrap();
+    let func = instance
+        .get_func(&mut store, "func_name")
+        .expect("function not defined!");
+    let func = func.typed::<Params, i32, _>(&store).unwrap();
+
+    let before = store.fuel_consumed().unwrap();
+    func.call(&mut store, params).unwrap();
+    store.fuel_consumed().unwrap() - before
+}
+
+/// Constructs a signature with the provided parameter and return types.
+pub(crate) fn signature(params: &[ir::Type], returns: &[ir::Type]) -> ir::Signature {
+    ir::Signature {
+        params: params.iter().map(|ty| ir::AbiParam::new(*ty)).collect(),
+        returns: returns.iter().map(|ty| ir::AbiParam::new(*ty)).collect(),
+        call_conv: CallConv::SystemV,
+    }
+}
+
+/// Builds the body of the (previously declared) function `func_id` and defines
+/// it in the module.
+///
+/// The module is also passed to `build`, so that it can be used to declare
+/// other functions and data objects in the function being built.
+pub(crate) fn define_function(
+    module: &mut WasmModule,
+    func_id: FuncId,
+    sig: ir::Signature,
+    build: impl FnOnce(&mut FunctionBuilder, &WasmModule),
+) {
+    try_define_function(module, func_id, sig, build).unwrap();
+}
+
+/// Like [`define_function`], but returns the error if the function could not
+/// be defined.
+pub(crate) fn try_define_function(
+    module: &mut WasmModule,
+    func_id: FuncId,
+    sig: ir::Signature,
+    build: impl FnOnce(&mut FunctionBuilder, &WasmModule),
+) -> ModuleResult<()> {
+    let mut ctx = Context::new();
+    ctx.func.signature = sig;
+
+    let mut func_ctx = FunctionBuilderContext::new();
+    let mut builder: FunctionBuilder = FunctionBuilder::new(&mut ctx.func, &mut func_ctx);
+
+    (build)(&mut builder, module);
+
+    builder.finalize();
+
+    if std::env::var("PRINT_CLIF").is_ok() {
+        println!("{}", ctx.func);
+    }
+
+    module
+        .define_function(
+            func_id,
+            &mut ctx,
+            &mut NullTrapSink {},
+            &mut NullStackMapSink {},
+        )
+        .map(|_| ())
+}
+
+/// Instantiates the module (using the imports returned by `imports`) and then
+/// calls the exported function `name`, returning its result.
+pub(crate) fn call_export<Params: WasmParams, Return: WasmResults>(
+    module: &mut WasmModule,
+    name: &str,
+    params: Params,
+    imports: impl FnOnce(&mut Store<()>) -> Vec<Extern>,
+) -> Return {
+    if std::env::var("PRINT_WAT").is_ok() {
+        println!("{}", module.emit_wat().unwrap());
+    }
+
+    let wasm = module.emit().unwrap();
+    let engine = Engine::default();
+    let module = wasmtime::Module::new(&engine, wasm).unwrap();
+    let mut store = Store::new(&engine, ());
+    let imports = (imports)(&mut store);
+    let instance = Instance::new(&mut store, &module, &imports).unwrap();
+    let func = instance
+        .get_func(&mut store, name)
+        .expect("function not defined!");
+    let func = func.typed::<Params, Return, _>(&store).unwrap();
+    func.call(&mut store, params).unwrap()
 }